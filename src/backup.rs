@@ -0,0 +1,265 @@
+//! Point-in-time backups (`rmdb backup` and `rmdb restore`).
+//!
+//! A backup is a directory holding a copy of every log segment of every
+//! keyspace, and of the keyspace catalog, plus a `MANIFEST` listing each
+//! file with its length and CRC32C. Sealed segments
+//! never change, so they are hard-linked when the destination is on the same
+//! filesystem and copied otherwise. The active segment is still being
+//! appended to, so only the prefix that existed when the backup started is
+//! copied; that cut-off is what makes the backup consistent. Every file is
+//! opened before the copy starts, so a compaction that deletes segments
+//! while the backup runs does not take them away from it. The manifest
+//! looks like
+//!
+//! ```text
+//! rmdb backup 1
+//! seq 1234
+//! 0000000007.compact 52133 9a3f0c11
+//! 0000000008.log 4096 5be2d7e0
+//! KEYSPACES 38 0c4f1b2a
+//! keyspaces/1/0000000001.log 812 e1d0a3f4
+//! ```
+//!
+//! where `seq` is the sequence number of the last commit included, and each
+//! file is named by its path within the database directory.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+const HEADER: &str = "rmdb backup 1";
+
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    /// Sequence number of the last commit in the backup.
+    pub seq: u64,
+    pub files: Vec<FileEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    pub name: String,
+    pub len: u64,
+    pub crc: u32,
+}
+
+/// A file to include in a backup.
+pub(crate) struct Source {
+    /// Path within the database directory, with `/` between components.
+    pub name: String,
+    pub path: PathBuf,
+    /// `path`, opened when the backup started, in case it is deleted before
+    /// it is copied.
+    pub file: File,
+    /// Bytes to include, from the start of the file.
+    pub len: u64,
+    /// Whether the file can no longer change, so it can be hard-linked.
+    pub sealed: bool,
+}
+
+impl Manifest {
+    /// Total size of the files in the backup.
+    pub fn bytes(&self) -> u64 {
+        self.files.iter().map(|file| file.len).sum()
+    }
+
+    fn read(dir: &Path) -> Result<Manifest> {
+        let path = dir.join(MANIFEST_FILE);
+        let mut lines = BufReader::new(File::open(&path)?).lines();
+        let mut next_line = || lines.next().transpose().map(|line| line.unwrap_or_default());
+
+        if next_line()? != HEADER {
+            return Err(invalid(&path, "not an rmdb backup manifest"));
+        }
+        let seq = next_line()?
+            .strip_prefix("seq ")
+            .and_then(|seq| seq.parse().ok())
+            .ok_or_else(|| invalid(&path, "missing sequence number"))?;
+
+        let mut files = Vec::new();
+        loop {
+            let line = next_line()?;
+            if line.is_empty() {
+                break;
+            }
+            let fields: Vec<&str> = line.split(' ').collect();
+            let entry = match fields[..] {
+                [name, len, crc] => len.parse().ok().zip(u32::from_str_radix(crc, 16).ok()).map(|(len, crc)| FileEntry {
+                    name: name.to_owned(),
+                    len,
+                    crc,
+                }),
+                _ => None,
+            };
+            // Names come from the manifest, so keep them inside the backup.
+            let inside = |name: &str| {
+                name.split('/')
+                    .all(|part| !part.is_empty() && !part.starts_with('.') && !part.contains('\\'))
+            };
+            match entry {
+                Some(entry) if inside(&entry.name) => files.push(entry),
+                _ => return Err(invalid(&path, "malformed file entry")),
+            }
+        }
+        Ok(Manifest { seq, files })
+    }
+
+    fn write(&self, dir: &Path) -> Result<()> {
+        let mut text = format!("{}\nseq {}\n", HEADER, self.seq);
+        for file in &self.files {
+            text.push_str(&format!("{} {} {:08x}\n", file.name, file.len, file.crc));
+        }
+        // Written last and renamed into place, so a backup with a manifest
+        // is always complete.
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut out = File::create(&tmp)?;
+        out.write_all(text.as_bytes())?;
+        out.sync_all()?;
+        fs::rename(&tmp, dir.join(MANIFEST_FILE))?;
+        sync_dir(dir)
+    }
+}
+
+/// Writes a backup of `sources` taken at `seq` into `dest`, which must not
+/// exist yet or be empty.
+pub(crate) fn create(dest: &Path, seq: u64, sources: &[Source]) -> Result<Manifest> {
+    prepare_empty_dir(dest)?;
+    let mut files = Vec::new();
+    for source in sources {
+        let target = dest.join(&source.name);
+        create_parent(&target)?;
+        // Segment names are never reused, so a link that succeeds is to the
+        // same file.
+        let crc = if source.sealed && fs::hard_link(&source.path, &target).is_ok() {
+            checksum(&target, source.len)?
+        } else {
+            copy_prefix(&source.file, &source.path, &target, source.len)?
+        };
+        sync_parents(dest, &target)?;
+        files.push(FileEntry {
+            name: source.name.clone(),
+            len: source.len,
+            crc,
+        });
+    }
+    let manifest = Manifest { seq, files };
+    manifest.write(dest)?;
+    Ok(manifest)
+}
+
+/// Checks every file listed in the manifest of the backup in `dir` against
+/// its recorded length and checksum.
+pub fn verify(dir: &Path) -> Result<Manifest> {
+    let manifest = Manifest::read(dir)?;
+    for file in &manifest.files {
+        let path = dir.join(&file.name);
+        let len = fs::metadata(&path)?.len();
+        if len != file.len {
+            return Err(invalid(&path, "length does not match the manifest"));
+        }
+        if checksum(&path, len)? != file.crc {
+            return Err(invalid(&path, "checksum does not match the manifest"));
+        }
+    }
+    Ok(manifest)
+}
+
+/// Verifies the backup in `src`, then copies it into `dest` as a database
+/// directory. `dest` must not exist yet or be empty.
+pub fn restore(src: &Path, dest: &Path) -> Result<Manifest> {
+    let manifest = verify(src)?;
+    prepare_empty_dir(dest)?;
+    for file in &manifest.files {
+        // Copied rather than linked: the restored database appends to its
+        // last segment, which must not change the backup.
+        let target = dest.join(&file.name);
+        create_parent(&target)?;
+        let from = src.join(&file.name);
+        copy_prefix(&File::open(&from)?, &from, &target, file.len)?;
+        sync_parents(dest, &target)?;
+    }
+    Ok(manifest)
+}
+
+fn prepare_empty_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(invalid(dir, "directory is not empty"));
+    }
+    Ok(())
+}
+
+/// Copies the first `len` bytes of `input`, opened from `from`, to a new
+/// file `to`, returning their checksum.
+fn copy_prefix(input: &File, from: &Path, to: &Path, len: u64) -> Result<u32> {
+    let mut input = input.take(len);
+    let mut output = File::create(to)?;
+    let mut crc = 0;
+    let mut buf = vec![0; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        crc = crc32c::crc32c_append(crc, &buf[..n]);
+        output.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+    if copied < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} is shorter than expected", from.display())).into());
+    }
+    output.sync_all()?;
+    Ok(crc)
+}
+
+/// CRC32C of the first `len` bytes of `path`.
+fn checksum(path: &Path, len: u64) -> Result<u32> {
+    let mut input = File::open(path)?.take(len);
+    let mut crc = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            return Ok(crc);
+        }
+        crc = crc32c::crc32c_append(crc, &buf[..n]);
+    }
+}
+
+/// Creates the directories leading to `path`, which keyspaces other than
+/// the default one need.
+fn create_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+/// Makes the entry of `path`, a file somewhere inside `root`, durable,
+/// along with those of the directories leading to it.
+fn sync_parents(root: &Path, path: &Path) -> Result<()> {
+    for dir in path.ancestors().skip(1) {
+        sync_dir(dir)?;
+        if dir == root {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn invalid(path: &Path, reason: &'static str) -> Error {
+    Error::InvalidBackup {
+        path: path.to_owned(),
+        reason,
+    }
+}
@@ -0,0 +1,338 @@
+//! Built-in benchmarks (`rmdb bench`).
+//!
+//! Workloads run one after another against the same database, in the order
+//! given, so a fill can populate the keys a later read workload looks up.
+//! Keys are the operation's number, zero-padded to 16 digits, drawn from
+//! `0..num`. Every operation is timed individually, and each workload
+//! reports its throughput along with latency percentiles.
+
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rmdb::{Keyspace, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Workload {
+    /// Writes keys in ascending order.
+    FillSequential,
+    /// Writes keys in random order.
+    FillRandom,
+    /// Reads random keys.
+    ReadRandom,
+    /// Random reads and writes, in the configured proportion.
+    Mixed,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub workloads: Vec<Workload>,
+    /// Operations per workload, split across the threads. Also the number
+    /// of distinct keys.
+    pub num: u64,
+    pub value_size: usize,
+    pub threads: usize,
+    /// Percentage of operations in [`Workload::Mixed`] that are reads.
+    pub read_percent: u32,
+}
+
+/// Results of one workload.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub workload: Workload,
+    pub ops: u64,
+    /// Reads that found their key.
+    pub found: u64,
+    pub reads: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Per-operation latencies in nanoseconds, sorted.
+    latencies: Vec<u64>,
+}
+
+/// Small, fast, and good enough to pick keys: xorshift64*.
+struct Rng(u64);
+
+#[derive(Default)]
+struct Totals {
+    ops: u64,
+    found: u64,
+    reads: u64,
+    bytes: u64,
+    latencies: Vec<u64>,
+}
+
+/// Runs each workload in `options` against `keyspace`, calling `report` as each
+/// one finishes.
+pub fn run<F: FnMut(&Report)>(keyspace: &Keyspace, options: &BenchOptions, mut report: F) -> Result<()> {
+    for &workload in &options.workloads {
+        report(&run_workload(keyspace, options, workload)?);
+    }
+    Ok(())
+}
+
+fn run_workload(keyspace: &Keyspace, options: &BenchOptions, workload: Workload) -> Result<Report> {
+    let threads = options.threads.max(1) as u64;
+    let start = Instant::now();
+    let results: Vec<Result<Totals>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|i| {
+                // Split 0..num into one contiguous share per thread.
+                let range = (options.num * i / threads)..(options.num * (i + 1) / threads);
+                scope.spawn(move || worker(keyspace, options, workload, range, i))
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+    let elapsed = start.elapsed();
+
+    let mut totals = Totals::default();
+    for result in results {
+        let part = result?;
+        totals.ops += part.ops;
+        totals.found += part.found;
+        totals.reads += part.reads;
+        totals.bytes += part.bytes;
+        totals.latencies.extend(part.latencies);
+    }
+    totals.latencies.sort_unstable();
+    Ok(Report {
+        workload,
+        ops: totals.ops,
+        found: totals.found,
+        reads: totals.reads,
+        bytes: totals.bytes,
+        elapsed,
+        latencies: totals.latencies,
+    })
+}
+
+fn worker(keyspace: &Keyspace, options: &BenchOptions, workload: Workload, range: std::ops::Range<u64>, thread: u64) -> Result<Totals> {
+    let mut rng = Rng::seeded(thread);
+    let mut value = vec![0; options.value_size];
+    let mut totals = Totals {
+        latencies: Vec::with_capacity((range.end - range.start) as usize),
+        ..Totals::default()
+    };
+
+    for n in range {
+        let key_number = match workload {
+            Workload::FillSequential => n,
+            _ => rng.below(options.num),
+        };
+        let key = format!("{:016}", key_number);
+        let read = match workload {
+            Workload::ReadRandom => true,
+            Workload::Mixed => rng.below(100) < u64::from(options.read_percent),
+            _ => false,
+        };
+        if !read {
+            rng.fill(&mut value);
+        }
+
+        let start = Instant::now();
+        if read {
+            let found = keyspace.get(key.as_bytes())?;
+            totals.reads += 1;
+            if let Some(found) = found {
+                totals.found += 1;
+                totals.bytes += (key.len() + found.len()) as u64;
+            }
+        } else {
+            keyspace.put(key.as_bytes(), &value)?;
+            totals.bytes += (key.len() + value.len()) as u64;
+        }
+        totals.latencies.push(start.elapsed().as_nanos() as u64);
+        totals.ops += 1;
+    }
+    Ok(totals)
+}
+
+impl Report {
+    /// The latency that `p` percent of operations did not exceed.
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::from_secs(0);
+        }
+        let rank = ((p / 100.0) * self.latencies.len() as f64).ceil() as usize;
+        Duration::from_nanos(self.latencies[rank.clamp(1, self.latencies.len()) - 1])
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE);
+        write!(
+            f,
+            "{:<11} {:>9} ops  {:>10.0} ops/s  {:>8.1} MB/s  p50 {:>9}  p90 {:>9}  p99 {:>9}  p99.9 {:>9}  max {:>9}",
+            self.workload.to_string(),
+            self.ops,
+            self.ops as f64 / secs,
+            self.bytes as f64 / secs / (1 << 20) as f64,
+            format_latency(self.percentile(50.0)),
+            format_latency(self.percentile(90.0)),
+            format_latency(self.percentile(99.0)),
+            format_latency(self.percentile(99.9)),
+            format_latency(self.percentile(100.0)),
+        )?;
+        if self.reads > 0 {
+            write!(f, "  ({} of {} found)", self.found, self.reads)?;
+        }
+        Ok(())
+    }
+}
+
+fn format_latency(d: Duration) -> String {
+    let nanos = d.as_nanos() as f64;
+    if nanos < 1e3 {
+        format!("{}ns", nanos)
+    } else if nanos < 1e6 {
+        format!("{:.1}us", nanos / 1e3)
+    } else if nanos < 1e9 {
+        format!("{:.1}ms", nanos / 1e6)
+    } else {
+        format!("{:.2}s", nanos / 1e9)
+    }
+}
+
+impl FromStr for Workload {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Workload, ()> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "fill-seq" | "fill-sequential" => Ok(Workload::FillSequential),
+            "fill-random" => Ok(Workload::FillRandom),
+            "read-random" => Ok(Workload::ReadRandom),
+            "mixed" => Ok(Workload::Mixed),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Workload::FillSequential => "fill-seq",
+            Workload::FillRandom => "fill-random",
+            Workload::ReadRandom => "read-random",
+            Workload::Mixed => "mixed",
+        })
+    }
+}
+
+impl Rng {
+    /// Seeds from the clock, mixed with `stream` so that threads started in
+    /// the same instant still differ.
+    fn seeded(stream: u64) -> Rng {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        // Never zero, which xorshift cannot leave.
+        Rng((now ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `0..n`, for `n` greater than zero.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmdb::wal::FsyncPolicy;
+    use rmdb::{Db, Options};
+
+    fn report(latencies: Vec<u64>) -> Report {
+        Report {
+            workload: Workload::ReadRandom,
+            ops: latencies.len() as u64,
+            found: 0,
+            reads: 0,
+            bytes: 0,
+            elapsed: Duration::from_secs(1),
+            latencies,
+        }
+    }
+
+    #[test]
+    fn percentiles_pick_the_nearest_rank() {
+        let hundred = report((1..=100).collect());
+        assert_eq!(hundred.percentile(0.0), Duration::from_nanos(1));
+        assert_eq!(hundred.percentile(50.0), Duration::from_nanos(50));
+        assert_eq!(hundred.percentile(99.9), Duration::from_nanos(100));
+        assert_eq!(hundred.percentile(100.0), Duration::from_nanos(100));
+
+        let single = report(vec![7]);
+        assert_eq!(single.percentile(0.0), Duration::from_nanos(7));
+        assert_eq!(single.percentile(100.0), Duration::from_nanos(7));
+        assert_eq!(report(Vec::new()).percentile(50.0), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn workloads_parse_by_name() {
+        assert_eq!("fill-seq".parse(), Ok(Workload::FillSequential));
+        assert_eq!("Fill-Sequential".parse(), Ok(Workload::FillSequential));
+        assert_eq!(" fill-random ".parse(), Ok(Workload::FillRandom));
+        assert_eq!("read-random".parse(), Ok(Workload::ReadRandom));
+        assert_eq!("MIXED".parse(), Ok(Workload::Mixed));
+        assert_eq!("read-seq".parse::<Workload>(), Err(()));
+        assert_eq!("".parse::<Workload>(), Err(()));
+        for workload in &[Workload::FillSequential, Workload::FillRandom, Workload::ReadRandom, Workload::Mixed] {
+            assert_eq!(workload.to_string().parse(), Ok(*workload));
+        }
+    }
+
+    #[test]
+    fn runs_each_workload_across_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            fsync: FsyncPolicy::Never,
+            ..Options::default()
+        };
+        let db = Db::open(dir.path(), options).unwrap();
+        let ks = db.default_keyspace();
+        let bench = BenchOptions {
+            workloads: vec![Workload::FillSequential, Workload::ReadRandom, Workload::Mixed],
+            num: 10,
+            value_size: 20,
+            threads: 2,
+            read_percent: 50,
+        };
+        let mut reports = Vec::new();
+        run(&ks, &bench, |report| reports.push(report.clone())).unwrap();
+
+        assert_eq!(reports.iter().map(|r| r.workload).collect::<Vec<_>>(), bench.workloads);
+        for report in &reports {
+            assert_eq!(report.ops, 10);
+            assert_eq!(report.latencies.len(), 10);
+            assert!(report.latencies.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+        let fill = &reports[0];
+        assert_eq!((fill.reads, fill.found), (0, 0));
+        assert_eq!(fill.bytes, 10 * (16 + 20));
+        // Every key was written by the fill, so every read finds one.
+        let read = &reports[1];
+        assert_eq!((read.reads, read.found), (10, 10));
+        let mixed = &reports[2];
+        assert_eq!(mixed.found, mixed.reads);
+
+        let written: Vec<_> = ks.scan::<[u8], _>(..).collect::<Result<_>>().unwrap();
+        assert_eq!(written.len(), 10);
+        assert_eq!(written[0].0, b"0000000000000000".to_vec());
+        assert!(written.iter().all(|(_, value)| value.len() == 20));
+    }
+}
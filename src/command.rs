@@ -0,0 +1,324 @@
+//! Commands understood by the interactive shell and the server.
+//!
+//! A command is a name followed by binary-safe arguments. Parsing is kept
+//! separate from execution so any front end that can split its input into
+//! arguments can drive the database the same way. Commands run within a
+//! [`Session`], which tracks the keyspace selected by `use` and the
+//! transaction opened by `begin`, if any. On a replica, commands that write
+//! are refused.
+
+use std::fmt::Write;
+use std::ops::Bound;
+
+use crate::error::Result;
+use crate::storage::{Db, Keyspace};
+use crate::txn::Txn;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Get(Vec<u8>),
+    Put(Vec<u8>, Vec<u8>),
+    Del(Vec<u8>),
+    /// Keys from `start` (inclusive) to `end` (exclusive); either may be
+    /// omitted.
+    Scan(Option<Vec<u8>>, Option<Vec<u8>>),
+    Prefix(Vec<u8>),
+    /// Switches the session to another keyspace. A transaction stays open
+    /// across the switch and commits its writes to every keyspace at once.
+    Use(String),
+    Info,
+    /// Reports the role of the server in replication, and how far behind
+    /// each replica or the server itself is.
+    Replication,
+    Ping,
+    Begin,
+    Commit,
+    Rollback,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// A short status message such as `OK`.
+    Status(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+    /// The command was rejected; the session is unchanged.
+    Error(String),
+}
+
+/// Per-client state: the database, the keyspace commands apply to, and the
+/// open transaction, if any.
+pub struct Session<'db> {
+    db: &'db Db,
+    keyspace: Keyspace<'db>,
+    txn: Option<Txn<'db>>,
+}
+
+impl Command {
+    /// Parses `args`, whose first element is the command name. Names are
+    /// case-insensitive.
+    pub fn parse(args: &[Vec<u8>]) -> std::result::Result<Command, String> {
+        let (name, args) = match args.split_first() {
+            Some((name, args)) => (String::from_utf8_lossy(name).to_ascii_lowercase(), args),
+            None => return Err("empty command".to_owned()),
+        };
+
+        let arity_between = |min: usize, max: usize| {
+            if args.len() >= min && args.len() <= max {
+                Ok(())
+            } else {
+                Err(format!("wrong number of arguments for '{}'", name))
+            }
+        };
+        let arity = |n: usize| arity_between(n, n);
+        let command = match name.as_str() {
+            "get" => {
+                arity(1)?;
+                Command::Get(args[0].clone())
+            }
+            "put" | "set" => {
+                arity(2)?;
+                Command::Put(args[0].clone(), args[1].clone())
+            }
+            "del" | "delete" => {
+                arity(1)?;
+                Command::Del(args[0].clone())
+            }
+            "scan" => {
+                arity_between(0, 2)?;
+                Command::Scan(args.first().cloned(), args.get(1).cloned())
+            }
+            "prefix" => {
+                arity(1)?;
+                Command::Prefix(args[0].clone())
+            }
+            "use" => {
+                arity(1)?;
+                Command::Use(String::from_utf8_lossy(&args[0]).into_owned())
+            }
+            "info" => {
+                arity(0)?;
+                Command::Info
+            }
+            "replication" => {
+                arity(0)?;
+                Command::Replication
+            }
+            "ping" => {
+                arity(0)?;
+                Command::Ping
+            }
+            "begin" => {
+                arity(0)?;
+                Command::Begin
+            }
+            "commit" => {
+                arity(0)?;
+                Command::Commit
+            }
+            "rollback" => {
+                arity(0)?;
+                Command::Rollback
+            }
+            _ => return Err(format!("unknown command '{}'", name)),
+        };
+        Ok(command)
+    }
+
+    /// The command's canonical name, for logging.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get(_) => "get",
+            Command::Put(..) => "put",
+            Command::Del(_) => "del",
+            Command::Scan(..) => "scan",
+            Command::Prefix(_) => "prefix",
+            Command::Use(_) => "use",
+            Command::Info => "info",
+            Command::Replication => "replication",
+            Command::Ping => "ping",
+            Command::Begin => "begin",
+            Command::Commit => "commit",
+            Command::Rollback => "rollback",
+        }
+    }
+}
+
+impl<'db> Session<'db> {
+    /// Starts a session whose commands apply to `keyspace`.
+    pub fn new(keyspace: Keyspace<'db>) -> Session<'db> {
+        Session {
+            db: keyspace.db(),
+            keyspace,
+            txn: None,
+        }
+    }
+
+    pub fn keyspace(&self) -> &Keyspace<'db> {
+        &self.keyspace
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.txn.is_some()
+    }
+
+    /// Runs `command`, reading and writing through the open transaction if
+    /// there is one.
+    pub fn execute(&mut self, command: &Command) -> Result<Reply> {
+        if let Command::Put(..) | Command::Del(_) = command {
+            if let Some(primary) = self.db.replication(|replication, _| replication.primary()) {
+                return Ok(Reply::Error(format!("read-only replica of {}", primary)));
+            }
+        }
+        let reply = match command {
+            Command::Get(key) => {
+                let value = match &self.txn {
+                    Some(txn) => txn.get(&self.keyspace, key)?,
+                    None => self.keyspace.get(key)?,
+                };
+                match value {
+                    Some(value) => Reply::Bulk(value),
+                    None => Reply::Nil,
+                }
+            }
+            Command::Put(key, value) => {
+                match &mut self.txn {
+                    Some(txn) => txn.put(&self.keyspace, key, value),
+                    None => self.keyspace.put(key, value)?,
+                }
+                ok()
+            }
+            Command::Del(key) => {
+                let existed = match &mut self.txn {
+                    Some(txn) => txn.delete(&self.keyspace, key)?,
+                    None => self.keyspace.delete(key)?,
+                };
+                Reply::Integer(existed as i64)
+            }
+            Command::Scan(start, end) => {
+                let range = (
+                    start.as_deref().map_or(Bound::Unbounded, Bound::Included),
+                    end.as_deref().map_or(Bound::Unbounded, Bound::Excluded),
+                );
+                match &self.txn {
+                    Some(txn) => pairs_reply(txn.scan::<[u8], _>(&self.keyspace, range))?,
+                    None => pairs_reply(self.keyspace.scan::<[u8], _>(range))?,
+                }
+            }
+            Command::Prefix(prefix) => match &self.txn {
+                Some(txn) => pairs_reply(txn.scan_prefix(&self.keyspace, prefix))?,
+                None => pairs_reply(self.keyspace.scan_prefix(prefix))?,
+            },
+            Command::Use(name) => match self.db.keyspace(name) {
+                Ok(keyspace) => {
+                    self.keyspace = keyspace;
+                    ok()
+                }
+                Err(e) => Reply::Error(e.to_string()),
+            },
+            Command::Info => {
+                let stats = self.keyspace.stats()?;
+                let mut info = String::new();
+                let _ = writeln!(info, "keyspace:{}", self.keyspace.name());
+                let _ = writeln!(info, "keys:{}", stats.keys);
+                let _ = writeln!(info, "last_seq:{}", stats.last_seq);
+                let _ = writeln!(info, "log_bytes:{}", stats.log_bytes);
+                let _ = writeln!(info, "segments:{}", stats.segments);
+                let _ = writeln!(info, "garbage_bytes:{}", stats.garbage_bytes);
+                let _ = writeln!(info, "open_snapshots:{}", stats.open_snapshots);
+                let _ = writeln!(info, "block_cache_bytes:{}", stats.block_cache.bytes);
+                let _ = writeln!(info, "block_cache_hits:{}", stats.block_cache.hits);
+                let _ = writeln!(info, "block_cache_misses:{}", stats.block_cache.misses);
+                let _ = write!(info, "block_cache_evictions:{}", stats.block_cache.evictions);
+                Reply::Bulk(info.into_bytes())
+            }
+            Command::Replication => {
+                let info = self.db.replication(|replication, seq| replication.info(seq));
+                Reply::Bulk(info.into_bytes())
+            }
+            Command::Ping => Reply::Status("PONG".to_owned()),
+            Command::Begin => {
+                if self.txn.is_some() {
+                    return Ok(Reply::Error("a transaction is already open".to_owned()));
+                }
+                self.txn = Some(self.db.begin());
+                ok()
+            }
+            Command::Commit => match self.txn.take() {
+                Some(txn) => {
+                    txn.commit()?;
+                    ok()
+                }
+                None => Reply::Error("no transaction is open".to_owned()),
+            },
+            Command::Rollback => match self.txn.take() {
+                Some(txn) => {
+                    txn.rollback();
+                    ok()
+                }
+                None => Reply::Error("no transaction is open".to_owned()),
+            },
+        };
+        Ok(reply)
+    }
+}
+
+fn ok() -> Reply {
+    Reply::Status("OK".to_owned())
+}
+
+/// Collects a scan into an array of `[key, value]` pairs.
+fn pairs_reply<I: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>(pairs: I) -> Result<Reply> {
+    let mut items = Vec::new();
+    for pair in pairs {
+        let (key, value) = pair?;
+        items.push(Reply::Array(vec![Reply::Bulk(key), Reply::Bulk(value)]));
+    }
+    Ok(Reply::Array(items))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(parts: &[&str]) -> std::result::Result<Command, String> {
+        let args: Vec<Vec<u8>> = parts.iter().map(|part| part.as_bytes().to_vec()).collect();
+        Command::parse(&args)
+    }
+
+    #[test]
+    fn parses_names_and_aliases() {
+        assert_eq!(parse(&["get", "k"]), Ok(Command::Get(b"k".to_vec())));
+        assert_eq!(parse(&["GET", "k"]), Ok(Command::Get(b"k".to_vec())));
+        assert_eq!(parse(&["put", "k", "v"]), Ok(Command::Put(b"k".to_vec(), b"v".to_vec())));
+        assert_eq!(parse(&["Set", "k", "v"]), Ok(Command::Put(b"k".to_vec(), b"v".to_vec())));
+        assert_eq!(parse(&["del", "k"]), Ok(Command::Del(b"k".to_vec())));
+        assert_eq!(parse(&["delete", "k"]), Ok(Command::Del(b"k".to_vec())));
+        assert_eq!(parse(&["scan"]), Ok(Command::Scan(None, None)));
+        assert_eq!(parse(&["scan", "a"]), Ok(Command::Scan(Some(b"a".to_vec()), None)));
+        assert_eq!(parse(&["scan", "a", "b"]), Ok(Command::Scan(Some(b"a".to_vec()), Some(b"b".to_vec()))));
+        assert_eq!(parse(&["prefix", "user:"]), Ok(Command::Prefix(b"user:".to_vec())));
+        assert_eq!(parse(&["info"]), Ok(Command::Info));
+    }
+
+    #[test]
+    fn names_are_canonical() {
+        for (line, name) in &[(&["SET", "k", "v"][..], "put"), (&["delete", "k"][..], "del"), (&["Scan"][..], "scan")] {
+            assert_eq!(parse(line).unwrap().name(), *name);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(parse(&[]), Err("empty command".to_owned()));
+        assert_eq!(parse(&["get"]), Err("wrong number of arguments for 'get'".to_owned()));
+        assert_eq!(parse(&["GET", "a", "b"]), Err("wrong number of arguments for 'get'".to_owned()));
+        assert_eq!(parse(&["set", "k"]), Err("wrong number of arguments for 'set'".to_owned()));
+        assert_eq!(parse(&["info", "x"]), Err("wrong number of arguments for 'info'".to_owned()));
+        assert_eq!(parse(&["scan", "a", "b", "c"]), Err("wrong number of arguments for 'scan'".to_owned()));
+        assert_eq!(parse(&["prefix"]), Err("wrong number of arguments for 'prefix'".to_owned()));
+        assert_eq!(parse(&["frobnicate", "k"]), Err("unknown command 'frobnicate'".to_owned()));
+    }
+}
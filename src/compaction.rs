@@ -0,0 +1,211 @@
+//! Space reclamation for the segmented log.
+//!
+//! Overwritten and deleted values stay in the log until compaction rewrites
+//! the versions still reachable from the index into a single new segment,
+//! or a sorted table in mmap mode (see [`crate::table`]), and deletes the
+//! segments they came from. The rewrite itself lives in
+//! [`crate::storage`]; this module holds its tunables, the background thread
+//! that decides when to run it, and the throttle that keeps it from starving
+//! foreground I/O.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionOptions {
+    /// How often the background thread checks whether to compact. Zero
+    /// disables background compaction; `rmdb compact` still works.
+    pub interval: Duration,
+    /// Fraction of the log that must be garbage before compacting.
+    pub trigger_ratio: f64,
+    /// Bytes of garbage required before compacting, so small databases are
+    /// not rewritten over and over.
+    pub trigger_bytes: u64,
+    /// Upper bound on compaction writes in bytes per second; 0 means
+    /// unlimited.
+    pub rate_limit: u64,
+}
+
+/// What one compaction did, for `rmdb compact`.
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionStats {
+    /// Number of segments rewritten.
+    pub segments: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Background thread running a compaction check every interval.
+pub(crate) struct Compactor {
+    /// Asks a compaction in progress to give up early.
+    cancel: Arc<AtomicBool>,
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Limits the rate of a sequence of writes by sleeping between them.
+pub(crate) struct Throttle {
+    rate: u64,
+    start: Instant,
+    written: u64,
+}
+
+impl Default for CompactionOptions {
+    fn default() -> CompactionOptions {
+        CompactionOptions {
+            interval: Duration::from_secs(30),
+            trigger_ratio: 0.5,
+            trigger_bytes: 16 << 20,
+            rate_limit: 0,
+        }
+    }
+}
+
+impl CompactionOptions {
+    /// Whether `garbage` bytes out of a `total`-byte log warrant compacting.
+    pub fn should_compact(&self, garbage: u64, total: u64) -> bool {
+        total > 0 && garbage >= self.trigger_bytes && garbage as f64 >= total as f64 * self.trigger_ratio
+    }
+}
+
+impl Compactor {
+    /// Calls `check` every `interval` until dropped. `check` is handed a flag
+    /// that is raised when the compactor is being dropped, and should return
+    /// early once it is.
+    pub fn spawn<F>(interval: Duration, mut check: F) -> Compactor
+    where
+        F: FnMut(&AtomicBool) + Send + 'static,
+    {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let flag = cancel.clone();
+        // The loop ends once the sender is dropped.
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                check(&flag);
+            }
+        });
+
+        Compactor {
+            cancel,
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Release);
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Throttle {
+    /// A throttle allowing `rate` bytes per second, or any rate if it is 0.
+    pub fn new(rate: u64) -> Throttle {
+        Throttle {
+            rate,
+            start: Instant::now(),
+            written: 0,
+        }
+    }
+
+    /// Accounts for `bytes` just written, sleeping until the average rate
+    /// since the throttle was created is back under the limit.
+    pub fn consume(&mut self, bytes: u64) {
+        if self.rate == 0 {
+            return;
+        }
+        self.written += bytes;
+        let due = Duration::from_secs_f64(self.written as f64 / self.rate as f64);
+        if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn thresholds_need_both_bytes_and_ratio() {
+        let options = CompactionOptions {
+            trigger_ratio: 0.5,
+            trigger_bytes: 100,
+            ..CompactionOptions::default()
+        };
+        assert!(!options.should_compact(0, 0));
+        assert!(!options.should_compact(99, 100));
+        assert!(!options.should_compact(100, 201));
+        assert!(options.should_compact(100, 200));
+        assert!(options.should_compact(1000, 1000));
+
+        let eager = CompactionOptions {
+            trigger_ratio: 1.0,
+            trigger_bytes: 0,
+            ..options
+        };
+        assert!(!eager.should_compact(0, 0));
+        assert!(!eager.should_compact(9, 10));
+        assert!(eager.should_compact(10, 10));
+    }
+
+    #[test]
+    fn throttle_holds_writes_to_the_rate() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(10_000);
+        for _ in 0..5 {
+            throttle.consume(500);
+        }
+        // 2500 bytes at 10000 per second.
+        assert!(start.elapsed() >= Duration::from_millis(250));
+
+        let start = Instant::now();
+        let mut unlimited = Throttle::new(0);
+        unlimited.consume(u64::MAX);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn compactor_checks_until_dropped() {
+        let checks = Arc::new(AtomicUsize::new(0));
+        let counter = checks.clone();
+        let compactor = Compactor::spawn(Duration::from_millis(1), move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        while checks.load(Ordering::Relaxed) < 3 {
+            thread::yield_now();
+        }
+        drop(compactor);
+        let seen = checks.load(Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(checks.load(Ordering::Relaxed), seen);
+    }
+
+    #[test]
+    fn drop_cancels_a_check_in_progress() {
+        let (started, wait) = mpsc::channel();
+        let compactor = Compactor::spawn(Duration::from_millis(1), move |cancel| {
+            let _ = started.send(());
+            // A compaction that only ends when asked to.
+            while !cancel.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        wait.recv().unwrap();
+
+        let start = Instant::now();
+        drop(compactor);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
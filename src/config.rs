@@ -0,0 +1,607 @@
+//! Server configuration.
+//!
+//! Settings are resolved in three layers: built-in defaults, then a TOML
+//! file, then `RMDB_*` environment variables. Every key is addressed by its
+//! dotted name (`data_dir`, `listen_addr`, ...) and the matching environment
+//! variable is that name upper-cased with dots replaced by underscores and an
+//! `RMDB_` prefix, e.g. `RMDB_MAX_MEMORY`.
+
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use rmdb::compaction::CompactionOptions;
+use rmdb::replication::ReplicationOptions;
+use rmdb::table::ReadOptions;
+use rmdb::wal::FsyncPolicy;
+
+/// Config file read when `--config` is not given. It is optional: if it does
+/// not exist the defaults are used.
+pub const DEFAULT_PATH: &str = "rmdb.toml";
+
+const ENV_PREFIX: &str = "RMDB_";
+
+/// Every key understood by [`Config::set`], in the order they are printed.
+const KEYS: &[&str] = &[
+    "data_dir",
+    "listen_addr",
+    "max_memory",
+    "log_level",
+    "log_format",
+    "wal.fsync",
+    "wal.segment_size",
+    "compaction.interval",
+    "compaction.trigger_ratio",
+    "compaction.trigger_bytes",
+    "compaction.rate_limit",
+    "replication.backlog_size",
+    "read.mode",
+    "read.block_size",
+    "read.block_cache",
+];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Directory holding the database files.
+    pub data_dir: PathBuf,
+    /// Address the server listens on.
+    pub listen_addr: SocketAddr,
+    /// Bytes the block cache and the replication backlog may hold together;
+    /// 0 means unlimited.
+    pub max_memory: u64,
+    /// Least severe level logged without `-v`.
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub wal: WalConfig,
+    pub compaction: CompactionOptions,
+    pub replication: ReplicationOptions,
+    pub read: ReadOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalConfig {
+    pub fsync: FsyncPolicy,
+    /// Size in bytes at which a log segment is sealed and a new one started.
+    pub segment_size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// How log lines are written to standard error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One human-readable line per event.
+    Text,
+    /// One JSON object per event, with the fields of its spans.
+    Json,
+}
+
+/// Where a rejected value came from, so errors can point at it.
+#[derive(Debug, Clone)]
+pub enum Origin {
+    File(PathBuf),
+    Env(String),
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid {
+        key: String,
+        origin: Origin,
+        message: String,
+    },
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            data_dir: PathBuf::from("data"),
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 6380)),
+            max_memory: 0,
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+            wal: WalConfig {
+                fsync: FsyncPolicy::Always,
+                segment_size: 64 << 20,
+            },
+            compaction: CompactionOptions::default(),
+            replication: ReplicationOptions::default(),
+            read: ReadOptions::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from `path`, or from [`DEFAULT_PATH`] if it
+    /// exists when `path` is `None`, then applies environment overrides.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+
+        let path = match path {
+            Some(path) => Some(path),
+            None if Path::new(DEFAULT_PATH).exists() => Some(Path::new(DEFAULT_PATH)),
+            None => None,
+        };
+        if let Some(path) = path {
+            let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+            config.merge_toml(&text, path)?;
+        }
+
+        config.merge_env(env::vars())?;
+        Ok(config)
+    }
+
+    /// Applies every key found in the TOML document `text`.
+    fn merge_toml(&mut self, text: &str, path: &Path) -> Result<(), ConfigError> {
+        let table: toml::value::Table =
+            toml::from_str(text).map_err(|e| ConfigError::Parse(path.to_owned(), e))?;
+        let origin = Origin::File(path.to_owned());
+
+        let mut entries = Vec::new();
+        flatten("", table, &mut entries);
+        for (key, value) in entries {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                _ => return Err(ConfigError::invalid(&key, &origin, "expected a scalar value")),
+            };
+            self.set(&key, &value, &origin)?;
+        }
+        Ok(())
+    }
+
+    /// Applies `RMDB_*` variables that name a known key. Unrelated variables
+    /// sharing the prefix are ignored.
+    fn merge_env<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) -> Result<(), ConfigError> {
+        for (name, value) in vars {
+            if !name.starts_with(ENV_PREFIX) {
+                continue;
+            }
+            if let Some(key) = KEYS.iter().find(|key| env_name(key) == name) {
+                self.set(key, &value, &Origin::Env(name.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets a single key from its textual form.
+    pub fn set(&mut self, key: &str, value: &str, origin: &Origin) -> Result<(), ConfigError> {
+        let invalid = |message: &str| ConfigError::invalid(key, origin, message);
+        match key {
+            "data_dir" => {
+                if value.is_empty() {
+                    return Err(invalid("must not be empty"));
+                }
+                self.data_dir = PathBuf::from(value);
+            }
+            "listen_addr" => {
+                self.listen_addr = value
+                    .parse()
+                    .map_err(|_| invalid("expected an address such as 127.0.0.1:6380"))?;
+            }
+            "max_memory" => {
+                self.max_memory = parse_size(value).ok_or_else(|| invalid("expected a size such as 512mb or 1gb"))?;
+            }
+            "log_level" => {
+                self.log_level = value
+                    .parse()
+                    .map_err(|_| invalid("expected one of error, warn, info, debug, trace"))?;
+            }
+            "log_format" => {
+                self.log_format = value.parse().map_err(|_| invalid("expected text or json"))?;
+            }
+            "wal.fsync" => {
+                self.wal.fsync = value
+                    .parse()
+                    .map_err(|_| invalid("expected always, never, or an interval such as 100ms"))?;
+            }
+            "wal.segment_size" => {
+                self.wal.segment_size = parse_size(value)
+                    .filter(|&size| size > 0)
+                    .ok_or_else(|| invalid("expected a size such as 64mb"))?;
+            }
+            "compaction.interval" => {
+                self.compaction.interval =
+                    parse_duration(value).ok_or_else(|| invalid("expected a duration such as 30s, or 0 to disable"))?;
+            }
+            "compaction.trigger_ratio" => {
+                self.compaction.trigger_ratio = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|ratio| *ratio > 0.0 && *ratio <= 1.0)
+                    .ok_or_else(|| invalid("expected a fraction greater than 0 and at most 1"))?;
+            }
+            "compaction.trigger_bytes" => {
+                self.compaction.trigger_bytes =
+                    parse_size(value).ok_or_else(|| invalid("expected a size such as 16mb"))?;
+            }
+            "compaction.rate_limit" => {
+                self.compaction.rate_limit =
+                    parse_size(value).ok_or_else(|| invalid("expected a size per second such as 8mb, or 0"))?;
+            }
+            "replication.backlog_size" => {
+                self.replication.backlog_size = parse_size(value)
+                    .filter(|&size| size > 0)
+                    .ok_or_else(|| invalid("expected a size such as 16mb"))?;
+            }
+            "read.mode" => {
+                self.read.mode = value.parse().map_err(|_| invalid("expected buffered or mmap"))?;
+            }
+            "read.block_size" => {
+                self.read.block_size = parse_size(value)
+                    .filter(|&size| size > 0)
+                    .ok_or_else(|| invalid("expected a size such as 4kb"))?;
+            }
+            "read.block_cache" => {
+                self.read.block_cache =
+                    parse_size(value).ok_or_else(|| invalid("expected a size such as 64mb, or 0 to disable"))?;
+            }
+            _ => return Err(invalid("unknown key")),
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "data_dir = {:?}", self.data_dir.display().to_string())?;
+        writeln!(f, "listen_addr = \"{}\"", self.listen_addr)?;
+        writeln!(f, "max_memory = {}", self.max_memory)?;
+        writeln!(f, "log_level = \"{}\"", self.log_level)?;
+        writeln!(f, "log_format = \"{}\"", self.log_format)?;
+        writeln!(f)?;
+        writeln!(f, "[wal]")?;
+        writeln!(f, "fsync = \"{}\"", self.wal.fsync)?;
+        writeln!(f, "segment_size = {}", self.wal.segment_size)?;
+        writeln!(f)?;
+        writeln!(f, "[compaction]")?;
+        writeln!(f, "interval = \"{}\"", format_duration(self.compaction.interval))?;
+        writeln!(f, "trigger_ratio = {:?}", self.compaction.trigger_ratio)?;
+        writeln!(f, "trigger_bytes = {}", self.compaction.trigger_bytes)?;
+        writeln!(f, "rate_limit = {}", self.compaction.rate_limit)?;
+        writeln!(f)?;
+        writeln!(f, "[replication]")?;
+        writeln!(f, "backlog_size = {}", self.replication.backlog_size)?;
+        writeln!(f)?;
+        writeln!(f, "[read]")?;
+        writeln!(f, "mode = \"{}\"", self.read.mode)?;
+        writeln!(f, "block_size = {}", self.read.block_size)?;
+        write!(f, "block_cache = {}", self.read.block_cache)
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<LogLevel, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        })
+    }
+}
+
+impl LogLevel {
+    /// The level `steps` more verbose than this one, stopping at trace.
+    pub fn raised(self, steps: u64) -> LogLevel {
+        const LEVELS: [LogLevel; 5] = [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace];
+        let index = (self as usize).saturating_add(steps as usize).min(LEVELS.len() - 1);
+        LEVELS[index]
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<LogFormat, ()> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        })
+    }
+}
+
+impl ConfigError {
+    fn invalid(key: &str, origin: &Origin, message: &str) -> ConfigError {
+        ConfigError::Invalid {
+            key: key.to_owned(),
+            origin: origin.clone(),
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Invalid { key, origin: Origin::File(path), message } => {
+                write!(f, "{}: invalid `{}`: {}", path.display(), key, message)
+            }
+            ConfigError::Invalid { key, origin: Origin::Env(name), message } => {
+                write!(f, "environment variable {}: invalid `{}`: {}", name, key, message)
+            }
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ConfigError::Io(_, e) => Some(e),
+            ConfigError::Parse(_, e) => Some(e),
+            ConfigError::Invalid { .. } => None,
+        }
+    }
+}
+
+/// Flattens nested tables into dotted keys, so `[wal] fsync = ...` becomes
+/// `wal.fsync`.
+fn flatten(prefix: &str, table: toml::value::Table, out: &mut Vec<(String, toml::Value)>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+        match value {
+            toml::Value::Table(table) => flatten(&key, table, out),
+            value => out.push((key, value)),
+        }
+    }
+}
+
+fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_ascii_uppercase())
+}
+
+/// Parses a byte count with an optional `kb`/`mb`/`gb` suffix (powers of
+/// 1024, case-insensitive).
+fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim().to_ascii_lowercase();
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s.as_str(), ""),
+    };
+    let shift = match unit.trim() {
+        "" | "b" => 0,
+        "k" | "kb" => 10,
+        "m" | "mb" => 20,
+        "g" | "gb" => 30,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Parses a duration with an `ms`, `s` or `m` suffix. A bare `0` is accepted
+/// as zero.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim().to_ascii_lowercase();
+    if s == "0" {
+        return Some(Duration::from_secs(0));
+    }
+    let (digits, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit())?);
+    let n = digits.parse::<u64>().ok()?;
+    match unit.trim() {
+        "ms" => Some(Duration::from_millis(n)),
+        "s" => Some(Duration::from_secs(n)),
+        "m" => Some(Duration::from_secs(n.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+/// Formats a duration the way [`parse_duration`] reads it.
+fn format_duration(d: Duration) -> String {
+    if d.subsec_millis() == 0 {
+        format!("{}s", d.as_secs())
+    } else {
+        format!("{}ms", d.as_millis())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse_size("0"), Some(0));
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("12b"), Some(12));
+        assert_eq!(parse_size("1k"), Some(1 << 10));
+        assert_eq!(parse_size("64KB"), Some(64 << 10));
+        assert_eq!(parse_size(" 512 mb "), Some(512 << 20));
+        assert_eq!(parse_size("2Gb"), Some(2 << 30));
+
+        for bad in &["", "mb", "1tb", "1.5gb", "-1", "1 k b", "ten"] {
+            assert_eq!(parse_size(bad), None, "{:?}", bad);
+        }
+        // Too large for a u64, before and after scaling.
+        assert_eq!(parse_size("18446744073709551616"), None);
+        assert_eq!(parse_size("17179869184gb"), None);
+        assert_eq!(parse_size("17179869183gb"), Some(17179869183 << 30));
+    }
+
+    #[test]
+    fn durations_take_unit_suffixes() {
+        assert_eq!(parse_duration("0"), Some(Duration::from_secs(0)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration(" 5 M "), Some(Duration::from_secs(300)));
+
+        for bad in &["", "30", "s", "1h", "1.5s", "-1s", "ten s"] {
+            assert_eq!(parse_duration(bad), None, "{:?}", bad);
+        }
+        assert_eq!(parse_duration("18446744073709551615m"), None);
+
+        for d in &[Duration::from_secs(0), Duration::from_millis(1500), Duration::from_secs(90)] {
+            assert_eq!(parse_duration(&format_duration(*d)), Some(*d));
+        }
+    }
+
+    #[test]
+    fn file_values_are_typed_and_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rmdb.toml");
+        fs::write(&path, "data_dir = \"/var/lib/rmdb\"\nlisten_addr = \"0.0.0.0:7000\"\nmax_memory = \"1gb\"\nlog_level = \"DEBUG\"\n").unwrap();
+
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("/var/lib/rmdb"));
+        assert_eq!(config.listen_addr, SocketAddr::from(([0, 0, 0, 0], 7000)));
+        assert_eq!(config.max_memory, 1 << 30);
+        assert_eq!(config.log_level, LogLevel::Debug);
+
+        // Integers are accepted where a size is expected.
+        let mut config = Config::default();
+        config.merge_toml("max_memory = 1024", &path).unwrap();
+        assert_eq!(config.max_memory, 1024);
+
+        match Config::load(Some(&dir.path().join("missing.toml"))) {
+            Err(ConfigError::Io(missing, _)) => assert!(missing.ends_with("missing.toml")),
+            other => panic!("expected an i/o error, got {:?}", other),
+        }
+        match config.merge_toml("max_memory = ", &path) {
+            Err(ConfigError::Parse(..)) => {}
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let path = Path::new("rmdb.toml");
+        let mut config = Config::default();
+        config.merge_toml("listen_addr = \"127.0.0.1:7000\"\nlog_level = \"warn\"", path).unwrap();
+        config
+            .merge_env(vars(&[
+                ("RMDB_LOG_LEVEL", "trace"),
+                ("RMDB_MAX_MEMORY", "16mb"),
+                // Shares the prefix but names no key.
+                ("RMDB_HISTFILE", "whatever"),
+                ("LOG_LEVEL", "error"),
+            ]))
+            .unwrap();
+        assert_eq!(config.listen_addr, SocketAddr::from(([127, 0, 0, 1], 7000)));
+        assert_eq!(config.log_level, LogLevel::Trace);
+        assert_eq!(config.max_memory, 16 << 20);
+    }
+
+    #[test]
+    fn verbosity_raises_the_level() {
+        assert_eq!(LogLevel::Info.raised(0), LogLevel::Info);
+        assert_eq!(LogLevel::Info.raised(1), LogLevel::Debug);
+        assert_eq!(LogLevel::Error.raised(2), LogLevel::Info);
+        assert_eq!(LogLevel::Warn.raised(10), LogLevel::Trace);
+        assert_eq!(LogLevel::Trace.raised(u64::MAX), LogLevel::Trace);
+
+        let mut config = Config::default();
+        assert_eq!(config.log_format, LogFormat::Text);
+        config.merge_env(vars(&[("RMDB_LOG_FORMAT", "JSON")])).unwrap();
+        assert_eq!(config.log_format, LogFormat::Json);
+        let e = config.merge_env(vars(&[("RMDB_LOG_FORMAT", "xml")])).unwrap_err();
+        assert_eq!(e.to_string(), "environment variable RMDB_LOG_FORMAT: invalid `log_format`: expected text or json");
+    }
+
+    #[test]
+    fn compaction_settings_are_checked() {
+        let path = Path::new("rmdb.toml");
+        let mut config = Config::default();
+        config
+            .merge_toml("[wal]\nsegment_size = \"1mb\"\n[compaction]\ninterval = \"500ms\"\ntrigger_ratio = 0.25", path)
+            .unwrap();
+        assert_eq!(config.wal.segment_size, 1 << 20);
+        assert_eq!(config.compaction.interval, Duration::from_millis(500));
+        assert_eq!(config.compaction.trigger_ratio, 0.25);
+
+        for bad in &["[wal]\nsegment_size = 0", "[compaction]\ntrigger_ratio = 1.5", "[compaction]\ninterval = 10"] {
+            match config.merge_toml(bad, path) {
+                Err(ConfigError::Invalid { .. }) => {}
+                other => panic!("expected an invalid value for {:?}, got {:?}", bad, other),
+            }
+        }
+    }
+
+    #[test]
+    fn errors_name_the_key_and_its_origin() {
+        let path = Path::new("/etc/rmdb.toml");
+        let mut config = Config::default();
+
+        let e = config.merge_toml("data_dir = \"data\"\ncolour = \"blue\"", path).unwrap_err();
+        assert_eq!(e.to_string(), "/etc/rmdb.toml: invalid `colour`: unknown key");
+
+        let e = config.merge_toml("[server]\nport = 1", path).unwrap_err();
+        assert_eq!(e.to_string(), "/etc/rmdb.toml: invalid `server.port`: unknown key");
+
+        let e = config.merge_toml("log_level = [\"info\"]", path).unwrap_err();
+        assert_eq!(e.to_string(), "/etc/rmdb.toml: invalid `log_level`: expected a scalar value");
+
+        match config.merge_toml("max_memory = \"lots\"", path).unwrap_err() {
+            ConfigError::Invalid { key, origin: Origin::File(file), message } => {
+                assert_eq!(key, "max_memory");
+                assert_eq!(file, path);
+                assert_eq!(message, "expected a size such as 512mb or 1gb");
+            }
+            other => panic!("expected an invalid value, got {:?}", other),
+        }
+
+        let e = config.merge_env(vars(&[("RMDB_LISTEN_ADDR", "localhost")])).unwrap_err();
+        match &e {
+            ConfigError::Invalid { key, origin: Origin::Env(name), .. } => {
+                assert_eq!(key, "listen_addr");
+                assert_eq!(name, "RMDB_LISTEN_ADDR");
+            }
+            other => panic!("expected an invalid value, got {:?}", other),
+        }
+        assert_eq!(
+            e.to_string(),
+            "environment variable RMDB_LISTEN_ADDR: invalid `listen_addr`: expected an address such as 127.0.0.1:6380"
+        );
+
+        let e = config.merge_env(vars(&[("RMDB_DATA_DIR", "")])).unwrap_err();
+        assert_eq!(e.to_string(), "environment variable RMDB_DATA_DIR: invalid `data_dir`: must not be empty");
+    }
+}
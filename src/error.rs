@@ -0,0 +1,64 @@
+//! The error type returned by every fallible operation of the crate.
+//!
+//! [`Error`](enum@Error) is `#[non_exhaustive]`: new kinds of failure may be
+//! added without a breaking release, so code matching on it needs a
+//! wildcard arm.
+
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Why an operation failed.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading or writing a file or socket failed.
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    /// A record in a data file could not be decoded.
+    #[error("corrupted record at offset {offset}: {reason}")]
+    Corrupted { offset: u64, reason: &'static str },
+    /// A value or table block read back from a data file failed its
+    /// checksum.
+    #[error("{}: corrupted block of {len} bytes at offset {offset}: checksum mismatch", path.display())]
+    CorruptedBlock { path: PathBuf, offset: u64, len: u64 },
+    /// A key or value exceeds the on-disk length limit.
+    #[error("{0} bytes exceeds the maximum key or value size")]
+    TooLarge(usize),
+    /// A transaction lost a write-write race on this key.
+    #[error("transaction conflict: {:?} was written by a concurrent transaction", String::from_utf8_lossy(.0))]
+    Conflict(Vec<u8>),
+    /// The validator of a keyspace refused a value written to it.
+    #[error("keyspace '{keyspace}' rejected {:?}: {reason}", String::from_utf8_lossy(.key))]
+    Rejected { keyspace: String, key: Vec<u8>, reason: String },
+    /// A line of an import file could not be parsed.
+    #[error("line {line}: {reason}")]
+    InvalidInput { line: u64, reason: &'static str },
+    /// A backup or restore could not be completed as asked.
+    #[error("{}: {reason}", path.display())]
+    InvalidBackup { path: PathBuf, reason: &'static str },
+    /// The keyspace catalog could not be decoded.
+    #[error("{}: {reason}", path.display())]
+    InvalidCatalog { path: PathBuf, reason: &'static str },
+    /// No keyspace has this name.
+    #[error("no keyspace named '{0}'")]
+    NoSuchKeyspace(String),
+    /// A keyspace with this name already exists.
+    #[error("keyspace '{0}' already exists")]
+    KeyspaceExists(String),
+    /// The name cannot be used for a keyspace.
+    #[error("invalid keyspace name '{0}': use 1 to 64 letters, digits, '-' and '_'")]
+    InvalidKeyspaceName(String),
+    /// The default keyspace cannot be dropped.
+    #[error("the default keyspace cannot be dropped")]
+    DropDefaultKeyspace,
+    /// The keyspace was dropped while a handle to it was still in use.
+    #[error("the keyspace was dropped")]
+    KeyspaceDropped,
+    /// A replication link broke down or was refused.
+    #[error("replication: {0}")]
+    Replication(String),
+}
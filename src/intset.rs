@@ -1,12 +1,46 @@
-use std::collections::TreeMap;
-use std::collections::tree_map::Entries;  // This took forever
-use std::num::One;
-use std::num::Saturating;
+//! Sets of integers, stored as ranges of consecutive members.
+
+use std::collections::btree_map;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::error::{Error, Result};
+
+// Integer types that can be stored in an IntSet.
+pub trait Int: Copy + Ord + fmt::Debug {
+  fn one() -> Self;
+  fn saturating_add(self, other: Self) -> Self;
+  fn saturating_sub(self, other: Self) -> Self;
+  // Number of members of [low, high].  Wide enough for the full range of
+  // any supported type.
+  fn width(low: Self, high: Self) -> u128;
+  // Order-preserving mapping onto u64, used by the binary encoding.
+  fn to_ordinal(self) -> u64;
+  fn from_ordinal(n: u64) -> Option<Self>;
+}
+
+macro_rules! impl_int {
+  ($($t:ty)*) => {$(
+    impl Int for $t {
+      fn one() -> $t { 1 }
+      fn saturating_add(self, other: $t) -> $t { <$t>::saturating_add(self, other) }
+      fn saturating_sub(self, other: $t) -> $t { <$t>::saturating_sub(self, other) }
+      fn width(low: $t, high: $t) -> u128 { (high as i128 - low as i128) as u128 + 1 }
+      // Offset from the type's minimum, so signed types encode without a sign.
+      fn to_ordinal(self) -> u64 { (self as i128 - <$t>::MIN as i128) as u64 }
+      fn from_ordinal(n: u64) -> Option<$t> { <$t>::try_from(n as i128 + <$t>::MIN as i128).ok() }
+    }
+  )*}
+}
+
+impl_int!(u8 u16 u32 u64 usize i8 i16 i32 i64 isize);
 
 // Equivalent to Set<T>, but only works for Ints, and may be more efficient
 // if the set contains large contiguous ranges.  Each range is stored as a
 // single entry instead of storing each member individually.
-struct IntSet<T> {
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IntSet<T> {
   // These correspond to ranges of high->low.  The ranges are closed (inclusive)
   // on both ends: [low, high].  Half-open ranges would make the algorithms
   // simpler but then we wouldn't be able to specify a range that includes
@@ -14,24 +48,32 @@ struct IntSet<T> {
   //
   // This representation allows for efficient enumeration and set membership
   // test.
-  ints: TreeMap<T, T>
+  ints: BTreeMap<T, T>
 }
 
-impl<T: Int + std::fmt::Show> IntSet<T> {
+// Iterator over the individual members of an IntSet, in ascending order.
+pub struct Iter<'a, T> {
+  ranges: btree_map::Iter<'a, T, T>,
+  // The rest of the range being enumerated: (next member, high).
+  current: Option<(T, T)>,
+}
+
+impl<T: Int> IntSet<T> {
   pub fn new() -> IntSet<T> {
-    IntSet { ints: TreeMap::new() }
+    IntSet { ints: BTreeMap::new() }
   }
 
   // Returns an iterator to the first range (low, high) such that high >= n.
   // If any range in the set contains n, it will be this one.
-  fn find<'a>(&'a self, n: T) -> Entries<'a, T, T> { self.ints.lower_bound(&n) }
+  fn find(&self, n: T) -> btree_map::Range<'_, T, T> { self.ints.range(n..) }
 
   // For testing only: validates that the set of ranges is the same as "ranges."
+  #[cfg(test)]
   fn assert_ranges(&self, ranges: &[(T, T)]) {
     let mut i = 0;
     for (&high, &low) in self.ints.iter() {
-      assert_eq!(low, ranges[i].val0());
-      assert_eq!(high, ranges[i].val1());
+      assert_eq!(low, ranges[i].0);
+      assert_eq!(high, ranges[i].1);
       i += 1;
     }
     assert_eq!(i, ranges.len());
@@ -52,37 +94,27 @@ impl<T: Int + std::fmt::Show> IntSet<T> {
 
   // Add members from the inclusive range [low, high].
   pub fn add_range(&mut self, low: T, high: T) {
+    assert!(low <= high);
+
     // If we are merging the new range with existing entries; track the list of
     // existing entries we should remove later.  We can't do this in the loop
     // because there's no way to delete entries while iterating over the map.
     let mut to_delete = Vec::new();
 
     // Find the new range to insert by merging (low, high) with any overlapping
-    // ranges.
+    // or adjacent ranges.
     let (insert_high, insert_low) = {
-      let mut iter = self.find(low.saturating_sub(One::one()));
-
-      match iter.next() {
-        None => {
-          // No overlapping ranges, add this range verbatim.
-          (high, low)
-        }
-        Some((&this_high, &this_low)) => {
-          // We overlap with at least one existing range.
-          // Compute the union of all intersecting ranges.
-          let new_low = std::cmp::min(low, this_low);
-          let mut new_high = std::cmp::max(high, this_high);
-          to_delete.push(this_high);
-
-          for (&iter_high, &iter_low) in iter {
-            if iter_low > high.saturating_add(One::one()) { break; }
-            to_delete.push(iter_high);
-            new_high = std::cmp::max(new_high, iter_high)
-          }
-
-          (new_high, new_low)
-        }
+      let mut new_low = low;
+      let mut new_high = high;
+
+      for (&iter_high, &iter_low) in self.find(low.saturating_sub(T::one())) {
+        if iter_low > high.saturating_add(T::one()) { break; }
+        to_delete.push(iter_high);
+        new_low = std::cmp::min(new_low, iter_low);
+        new_high = std::cmp::max(new_high, iter_high);
       }
+
+      (new_high, new_low)
     };
 
     for high in to_delete.iter() {
@@ -99,4 +131,497 @@ impl<T: Int + std::fmt::Show> IntSet<T> {
       self.add_range(low, high);
     }
   }
-}
\ No newline at end of file
+
+  // Remove the single member "n", if present.
+  pub fn remove(&mut self, n: T) {
+    self.remove_range(n, n);
+  }
+
+  // Remove members in the inclusive range [low, high].  Ranges that straddle
+  // either end are shrunk, and a range that contains [low, high] is split in
+  // two.
+  pub fn remove_range(&mut self, low: T, high: T) {
+    assert!(low <= high);
+
+    // Every range overlapping [low, high], as (high, low).
+    let overlapping: Vec<(T, T)> = self.find(low)
+        .take_while(|&(_, &iter_low)| iter_low <= high)
+        .map(|(&iter_high, &iter_low)| (iter_high, iter_low))
+        .collect();
+
+    for (iter_high, iter_low) in overlapping {
+      self.ints.remove(&iter_high);
+      // Neither subtraction nor addition can overflow: iter_low < low means
+      // low is not the minimum value, and likewise for high.
+      if iter_low < low {
+        self.ints.insert(low.saturating_sub(T::one()), iter_low);
+      }
+      if iter_high > high {
+        self.ints.insert(iter_high, high.saturating_add(T::one()));
+      }
+    }
+  }
+
+  // Remove every member.
+  pub fn clear(&mut self) {
+    self.ints.clear();
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ints.is_empty()
+  }
+
+  // Number of members.  Computed from the range widths, so it is cheap, and
+  // u128 so that a set holding every u64 or i64 does not overflow.
+  pub fn len(&self) -> u128 {
+    self.ints.iter().map(|(&high, &low)| T::width(low, high)).sum()
+  }
+
+  // Iterate over the members in ascending order.
+  pub fn iter(&self) -> Iter<'_, T> {
+    Iter { ranges: self.ints.iter(), current: None }
+  }
+
+  // Iterate over the ranges as inclusive (low, high) pairs, in ascending
+  // order.  Ranges never overlap or touch.
+  pub fn ranges(&self) -> impl Iterator<Item = (T, T)> + '_ {
+    self.ints.iter().map(|(&high, &low)| (low, high))
+  }
+
+  // Members in either set.
+  pub fn union(&self, other: &IntSet<T>) -> IntSet<T> {
+    let mut result = self.clone();
+    result.add_intset(other);
+    result
+  }
+
+  // Members in both sets.
+  pub fn intersection(&self, other: &IntSet<T>) -> IntSet<T> {
+    let mut result = IntSet::new();
+    let mut a = self.ranges().peekable();
+    let mut b = other.ranges().peekable();
+
+    // Walk both range lists in order, emitting each overlap and advancing
+    // whichever range ends first.
+    while let (Some(&(a_low, a_high)), Some(&(b_low, b_high))) = (a.peek(), b.peek()) {
+      let low = std::cmp::max(a_low, b_low);
+      let high = std::cmp::min(a_high, b_high);
+      if low <= high {
+        result.ints.insert(high, low);
+      }
+      if a_high < b_high { a.next(); } else { b.next(); }
+    }
+    result
+  }
+
+  // Members in this set but not in "other".
+  pub fn difference(&self, other: &IntSet<T>) -> IntSet<T> {
+    let mut result = self.clone();
+    for (low, high) in other.ranges() {
+      result.remove_range(low, high);
+    }
+    result
+  }
+
+  // Append the compact binary encoding of the set to "out".  The format is a
+  // varint range count followed by two varints per range: the distance from
+  // the end of the previous range (or the low value itself, for the first
+  // range), then the range's width minus one.  Values are encoded as their
+  // offset from T's minimum, so a set prefers small gaps over small values.
+  pub fn encode(&self, out: &mut Vec<u8>) {
+    put_varint(out, self.ints.len() as u64);
+    let mut prev_high: Option<u64> = None;
+    for (low, high) in self.ranges() {
+      let (low, high) = (low.to_ordinal(), high.to_ordinal());
+      // Ranges never touch, so there are at least two values between the
+      // previous high and this low.
+      put_varint(out, match prev_high { Some(prev) => low - prev - 2, None => low });
+      put_varint(out, high - low);
+      prev_high = Some(high);
+    }
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut out = Vec::new();
+    self.encode(&mut out);
+    out
+  }
+
+  // Decode a set written by encode().  The input must hold exactly one
+  // encoded set; offsets in errors are relative to the start of "buf".
+  pub fn decode(buf: &[u8]) -> Result<IntSet<T>> {
+    let mut pos = 0;
+    let count = get_varint(buf, &mut pos)?;
+    let mut set = IntSet::new();
+    let mut prev_high: Option<u64> = None;
+    for _ in 0..count {
+      let start = pos as u64;
+      let delta = get_varint(buf, &mut pos)?;
+      let width = get_varint(buf, &mut pos)?;
+      let low = match prev_high {
+        Some(prev) => prev.checked_add(2).and_then(|n| n.checked_add(delta)),
+        None => Some(delta),
+      };
+      let (low, high) = low
+          .and_then(|low| Some((low, low.checked_add(width)?)))
+          .and_then(|(low, high)| Some((T::from_ordinal(low)?, T::from_ordinal(high)?)))
+          .ok_or(Error::Corrupted { offset: start, reason: "intset range out of bounds" })?;
+      set.ints.insert(high, low);
+      prev_high = Some(high.to_ordinal());
+    }
+    if pos != buf.len() {
+      return Err(Error::Corrupted { offset: pos as u64, reason: "trailing bytes after intset" });
+    }
+    Ok(set)
+  }
+}
+
+impl<'a, T: Int> Iterator for Iter<'a, T> {
+  type Item = T;
+
+  fn next(&mut self) -> Option<T> {
+    if self.current.is_none() {
+      self.current = self.ranges.next().map(|(&high, &low)| (low, high));
+    }
+    let (n, high) = self.current?;
+    // Step without computing high + 1, which may not exist.
+    self.current = if n == high { None } else { Some((n.saturating_add(T::one()), high)) };
+    Some(n)
+  }
+}
+
+impl<'a, T: Int> IntoIterator for &'a IntSet<T> {
+  type Item = T;
+  type IntoIter = Iter<'a, T>;
+
+  fn into_iter(self) -> Iter<'a, T> {
+    self.iter()
+  }
+}
+
+// LEB128: seven bits per byte, least significant first, high bit set on every
+// byte but the last.
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+  while n >= 0x80 {
+    out.push(n as u8 | 0x80);
+    n >>= 7;
+  }
+  out.push(n as u8);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> Result<u64> {
+  let start = *pos as u64;
+  let mut n = 0u64;
+  for shift in (0..64).step_by(7) {
+    let byte = *buf.get(*pos)
+        .ok_or(Error::Corrupted { offset: start, reason: "truncated intset varint" })?;
+    *pos += 1;
+    // The tenth byte may only carry the top bit of a u64.
+    if shift == 63 && byte > 1 {
+      break;
+    }
+    n |= u64::from(byte & 0x7f) << shift;
+    if byte & 0x80 == 0 {
+      return Ok(n);
+    }
+  }
+  Err(Error::Corrupted { offset: start, reason: "intset varint overflows u64" })
+}
+
+// With the "serde" feature, sets serialize as a sequence of inclusive
+// (low, high) pairs in ascending order.
+#[cfg(feature = "serde")]
+impl<T: Int + serde::Serialize> serde::Serialize for IntSet<T> {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(self.ranges())
+  }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: Int + serde::Deserialize<'de>> serde::Deserialize<'de> for IntSet<T> {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    // Accept overlapping or unordered ranges and merge them, but not
+    // reversed ones, which add_range() would panic on.
+    let ranges: Vec<(T, T)> = serde::Deserialize::deserialize(deserializer)?;
+    let mut set = IntSet::new();
+    for (low, high) in ranges {
+      if low > high {
+        return Err(serde::de::Error::custom(format!("invalid intset range {:?}..={:?}", low, high)));
+      }
+      set.add_range(low, high);
+    }
+    Ok(set)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn round_trip<T: Int>(set: &IntSet<T>) -> IntSet<T> {
+    IntSet::decode(&set.to_bytes()).unwrap()
+  }
+
+  #[test]
+  fn encode_empty() {
+    let set: IntSet<u32> = IntSet::new();
+    assert_eq!(set.to_bytes(), vec![0]);
+    round_trip(&set).assert_ranges(&[]);
+  }
+
+  #[test]
+  fn encode_ranges() {
+    let mut set = IntSet::new();
+    set.add(3u32);
+    set.add_range(10, 20);
+    set.add_range(22, 1000);
+    set.add(100_000);
+    // Count, then (delta, width - 1) per range.
+    assert_eq!(set.to_bytes(), vec![4, 3, 0, 5, 10, 0, 0xd2, 0x07, 0xb6, 0x85, 0x06, 0]);
+    round_trip(&set).assert_ranges(&[(3, 3), (10, 20), (22, 1000), (100_000, 100_000)]);
+  }
+
+  #[test]
+  fn encode_max_values() {
+    let mut set = IntSet::new();
+    set.add_range(0u8, u8::MAX);
+    round_trip(&set).assert_ranges(&[(0, u8::MAX)]);
+
+    let mut set = IntSet::new();
+    set.add(0u64);
+    set.add(u64::MAX - 2);
+    set.add(u64::MAX);
+    round_trip(&set).assert_ranges(&[(0, 0), (u64::MAX - 2, u64::MAX - 2), (u64::MAX, u64::MAX)]);
+
+    let mut set = IntSet::new();
+    set.add_range(0u64, u64::MAX);
+    round_trip(&set).assert_ranges(&[(0, u64::MAX)]);
+
+    let mut set = IntSet::new();
+    set.add_range(i64::MIN, -1);
+    set.add(i64::MAX);
+    round_trip(&set).assert_ranges(&[(i64::MIN, -1), (i64::MAX, i64::MAX)]);
+
+    let mut set = IntSet::new();
+    set.add_range(i8::MIN, i8::MAX);
+    assert_eq!(set.to_bytes(), vec![1, 0, 0xff, 0x01]);
+    round_trip(&set).assert_ranges(&[(i8::MIN, i8::MAX)]);
+  }
+
+  #[test]
+  fn decode_rejects_bad_input() {
+    let mut set = IntSet::new();
+    set.add_range(5u16, 300);
+    set.add(1000);
+    let bytes = set.to_bytes();
+    for len in 0..bytes.len() {
+      assert!(IntSet::<u16>::decode(&bytes[..len]).is_err());
+    }
+
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(IntSet::<u16>::decode(&trailing).is_err());
+
+    // Fits a u32 but not a u16.
+    let mut wide = IntSet::new();
+    wide.add(70_000u32);
+    assert!(IntSet::<u16>::decode(&wide.to_bytes()).is_err());
+
+    // A second range past u64::MAX.
+    let mut max = IntSet::new();
+    max.add(u64::MAX - 1);
+    let mut bytes = max.to_bytes();
+    bytes[0] = 2;
+    bytes.extend_from_slice(&[0, 0]);
+    assert!(IntSet::<u64>::decode(&bytes).is_err());
+
+    // An eleven-byte varint.
+    let mut bytes = vec![1];
+    bytes.extend_from_slice(&[0xff; 10]);
+    bytes.push(0);
+    assert!(IntSet::<u64>::decode(&bytes).is_err());
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn serde_round_trip() {
+    let mut set = IntSet::new();
+    set.add_range(i64::MIN, -1);
+    set.add(i64::MAX);
+    let value = toml::Value::try_from(&set).unwrap();
+    assert_eq!(value.to_string(), format!("[[{}, -1], [{}, {}]]", i64::MIN, i64::MAX, i64::MAX));
+    let decoded: IntSet<i64> = value.try_into().unwrap();
+    decoded.assert_ranges(&[(i64::MIN, -1), (i64::MAX, i64::MAX)]);
+
+    let reversed = toml::Value::Array(vec![toml::Value::Array(vec![2.into(), 1.into()])]);
+    assert!(reversed.try_into::<IntSet<i64>>().is_err());
+  }
+
+  #[test]
+  fn add_merges_adjacent_and_overlapping() {
+    let mut set = IntSet::new();
+    set.add_range(10u32, 20);
+    set.add_range(30, 40);
+    set.assert_ranges(&[(10, 20), (30, 40)]);
+
+    // Touching either end.
+    set.add(21);
+    set.add(29);
+    set.assert_ranges(&[(10, 21), (29, 40)]);
+    // Overlapping one range, and inside one.
+    set.add_range(5, 12);
+    set.add_range(31, 35);
+    set.assert_ranges(&[(5, 21), (29, 40)]);
+    // Filling the gap joins both.
+    set.add_range(22, 28);
+    set.assert_ranges(&[(5, 40)]);
+    // Spanning several ranges at once.
+    set.add_range(50, 60);
+    set.add(70);
+    set.add_range(0, 100);
+    set.assert_ranges(&[(0, 100)]);
+  }
+
+  #[test]
+  fn add_at_the_bounds() {
+    let mut set = IntSet::new();
+    set.add(u8::MAX);
+    set.add(u8::MIN);
+    set.assert_ranges(&[(0, 0), (u8::MAX, u8::MAX)]);
+    set.add_range(1, u8::MAX - 1);
+    set.assert_ranges(&[(0, u8::MAX)]);
+    assert_eq!(set.len(), 256);
+
+    let mut set = IntSet::new();
+    set.add_range(i64::MIN, i64::MIN + 1);
+    set.add(i64::MIN + 2);
+    set.add_range(i64::MAX - 1, i64::MAX);
+    set.add(i64::MAX - 2);
+    set.assert_ranges(&[(i64::MIN, i64::MIN + 2), (i64::MAX - 2, i64::MAX)]);
+  }
+
+  #[test]
+  fn remove_shrinks_and_splits() {
+    let mut set = IntSet::new();
+    set.add_range(10u32, 20);
+    set.add_range(30, 40);
+    set.add_range(50, 60);
+
+    // Either end of a range.
+    set.remove(10);
+    set.remove(60);
+    set.assert_ranges(&[(11, 20), (30, 40), (50, 59)]);
+    // The middle of one splits it.
+    set.remove(35);
+    set.assert_ranges(&[(11, 20), (30, 34), (36, 40), (50, 59)]);
+    set.remove_range(15, 16);
+    set.assert_ranges(&[(11, 14), (17, 20), (30, 34), (36, 40), (50, 59)]);
+    // Straddling several ranges, and covering some whole.
+    set.remove_range(19, 52);
+    set.assert_ranges(&[(11, 14), (17, 18), (53, 59)]);
+    // Members that are not there.
+    set.remove(16);
+    set.remove_range(100, 200);
+    set.assert_ranges(&[(11, 14), (17, 18), (53, 59)]);
+    // Exactly a whole range.
+    set.remove_range(17, 18);
+    set.assert_ranges(&[(11, 14), (53, 59)]);
+
+    set.clear();
+    assert!(set.is_empty());
+    set.assert_ranges(&[]);
+  }
+
+  #[test]
+  fn remove_at_the_bounds() {
+    let mut set = IntSet::new();
+    set.add_range(u64::MIN, u64::MAX);
+    set.remove(u64::MIN);
+    set.remove(u64::MAX);
+    set.assert_ranges(&[(1, u64::MAX - 1)]);
+    set.remove_range(u64::MIN, 5);
+    set.remove_range(u64::MAX - 5, u64::MAX);
+    set.assert_ranges(&[(6, u64::MAX - 6)]);
+
+    let mut set = IntSet::new();
+    set.add_range(i8::MIN, i8::MAX);
+    set.remove(0);
+    set.assert_ranges(&[(i8::MIN, -1), (1, i8::MAX)]);
+    set.remove_range(i8::MIN, i8::MAX);
+    set.assert_ranges(&[]);
+  }
+
+  #[test]
+  fn iter_ranges_and_len() {
+    let mut set = IntSet::new();
+    assert_eq!(set.len(), 0);
+    assert_eq!(set.iter().next(), None);
+
+    set.add_range(-2i32, 1);
+    set.add(5);
+    set.add_range(7, 8);
+    assert_eq!(set.iter().collect::<Vec<_>>(), vec![-2, -1, 0, 1, 5, 7, 8]);
+    assert_eq!((&set).into_iter().count(), 7);
+    assert_eq!(set.ranges().collect::<Vec<_>>(), vec![(-2, 1), (5, 5), (7, 8)]);
+    assert_eq!(set.len(), 7);
+    assert!(set.contains(-2) && set.contains(5) && set.contains(8));
+    assert!(!set.contains(-3) && !set.contains(2) && !set.contains(6) && !set.contains(9));
+
+    // Iterating up to the maximum does not overflow.
+    let mut set = IntSet::new();
+    set.add_range(u16::MAX - 2, u16::MAX);
+    assert_eq!(set.iter().collect::<Vec<_>>(), vec![u16::MAX - 2, u16::MAX - 1, u16::MAX]);
+
+    let mut set = IntSet::new();
+    set.add_range(i64::MIN, i64::MAX);
+    assert_eq!(set.len(), 1 << 64);
+    assert_eq!(set.iter().take(2).collect::<Vec<_>>(), vec![i64::MIN, i64::MIN + 1]);
+  }
+
+  #[test]
+  fn set_operations() {
+    let mut a = IntSet::new();
+    a.add_range(0u32, 10);
+    a.add_range(20, 30);
+    a.add(40);
+    let mut b = IntSet::new();
+    b.add_range(5, 25);
+    b.add_range(31, 39);
+    b.add(50);
+
+    a.union(&b).assert_ranges(&[(0, 40), (50, 50)]);
+    a.intersection(&b).assert_ranges(&[(5, 10), (20, 25)]);
+    a.difference(&b).assert_ranges(&[(0, 4), (26, 30), (40, 40)]);
+    b.difference(&a).assert_ranges(&[(11, 19), (31, 39), (50, 50)]);
+
+    // With an empty set, and with itself.
+    let empty = IntSet::new();
+    assert_eq!(a.union(&empty), a);
+    assert!(a.intersection(&empty).is_empty());
+    assert_eq!(a.difference(&empty), a);
+    assert!(empty.difference(&a).is_empty());
+    assert_eq!(a.intersection(&a), a);
+    assert!(a.difference(&a).is_empty());
+  }
+
+  #[test]
+  fn set_operations_at_the_bounds() {
+    let mut all = IntSet::new();
+    all.add_range(i16::MIN, i16::MAX);
+    let mut ends = IntSet::new();
+    ends.add(i16::MIN);
+    ends.add(i16::MAX);
+
+    all.union(&ends).assert_ranges(&[(i16::MIN, i16::MAX)]);
+    all.intersection(&ends).assert_ranges(&[(i16::MIN, i16::MIN), (i16::MAX, i16::MAX)]);
+    all.difference(&ends).assert_ranges(&[(i16::MIN + 1, i16::MAX - 1)]);
+    assert!(ends.difference(&all).is_empty());
+
+    let mut top = IntSet::new();
+    top.add_range(u64::MAX - 1, u64::MAX);
+    let mut bottom = IntSet::new();
+    bottom.add_range(0, 1);
+    top.union(&bottom).assert_ranges(&[(0, 1), (u64::MAX - 1, u64::MAX)]);
+    assert!(top.intersection(&bottom).is_empty());
+  }
+}
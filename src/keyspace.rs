@@ -0,0 +1,270 @@
+//! The catalog of named keyspaces.
+//!
+//! Every keyspace is stored separately: it has its own log segments, index
+//! and compactions, and dropping it deletes its files outright. The
+//! `default` keyspace always exists and lives directly in the database
+//! directory, so databases from before keyspaces open unchanged; the others
+//! live in `keyspaces/<id>/`. Ids are never reused, so files left behind by
+//! a dropped keyspace can never be mistaken for a newer one's.
+//!
+//! The catalog is the `KEYSPACES` file, replaced atomically on every change:
+//!
+//! ```text
+//! rmdb keyspaces 1
+//! next 3
+//! 1 users
+//! 2 sessions
+//! crc 3e1f09a7
+//! ```
+//!
+//! where `next` is the id the next keyspace created will get, and `crc` is
+//! the CRC32C of everything before its line.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+pub const DEFAULT_KEYSPACE: &str = "default";
+pub const CATALOG_FILE: &str = "KEYSPACES";
+/// Directory, inside the database directory, holding the named keyspaces.
+const KEYSPACES_DIR: &str = "keyspaces";
+const HEADER: &str = "rmdb keyspaces 1";
+const MAX_NAME_LEN: usize = 64;
+
+pub(crate) type KeyspaceId = u32;
+
+pub(crate) const DEFAULT_ID: KeyspaceId = 0;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Catalog {
+    next: KeyspaceId,
+    /// Every keyspace but the default one.
+    names: BTreeMap<KeyspaceId, String>,
+}
+
+impl Catalog {
+    /// Reads the catalog of the database in `dir`. A database without one
+    /// has only the default keyspace.
+    pub fn load(dir: &Path) -> Result<Catalog> {
+        let path = dir.join(CATALOG_FILE);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(Catalog {
+                    next: DEFAULT_ID + 1,
+                    names: BTreeMap::new(),
+                })
+            }
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {
+                return Err(invalid(&path, "not an rmdb keyspace catalog"))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let (body, crc) = text
+            .trim_end_matches('\n')
+            .rsplit_once('\n')
+            .and_then(|(body, last)| Some((&text[..body.len() + 1], last.strip_prefix("crc ")?)))
+            .ok_or_else(|| invalid(&path, "missing checksum"))?;
+        if u32::from_str_radix(crc, 16).ok() != Some(crc32c::crc32c(body.as_bytes())) {
+            return Err(invalid(&path, "checksum mismatch"));
+        }
+        let mut lines = body.lines();
+        let mut next_line = || lines.next().unwrap_or_default();
+
+        if next_line() != HEADER {
+            return Err(invalid(&path, "not an rmdb keyspace catalog"));
+        }
+        let next = next_line()
+            .strip_prefix("next ")
+            .and_then(|next| next.parse().ok())
+            .ok_or_else(|| invalid(&path, "missing next keyspace id"))?;
+
+        let mut names = BTreeMap::new();
+        loop {
+            let line = next_line();
+            if line.is_empty() {
+                break;
+            }
+            let entry = line.split_once(' ').and_then(|(id, name)| id.parse::<KeyspaceId>().ok().map(|id| (id, name)));
+            match entry {
+                Some((id, name)) if id != DEFAULT_ID && id < next && validate_name(name).is_ok() => {
+                    names.insert(id, name.to_owned());
+                }
+                _ => return Err(invalid(&path, "malformed keyspace entry")),
+            }
+        }
+        Ok(Catalog { next, names })
+    }
+
+    /// Replaces the catalog of the database in `dir` with this one.
+    pub fn store(&self, dir: &Path) -> Result<()> {
+        let mut text = format!("{}\nnext {}\n", HEADER, self.next);
+        for (id, name) in &self.names {
+            text.push_str(&format!("{} {}\n", id, name));
+        }
+        text.push_str(&format!("crc {:08x}\n", crc32c::crc32c(text.as_bytes())));
+        let tmp = dir.join(format!("{}.tmp", CATALOG_FILE));
+        let mut out = File::create(&tmp)?;
+        out.write_all(text.as_bytes())?;
+        out.sync_all()?;
+        fs::rename(&tmp, dir.join(CATALOG_FILE))?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn id(&self, name: &str) -> Option<KeyspaceId> {
+        if name == DEFAULT_KEYSPACE {
+            return Some(DEFAULT_ID);
+        }
+        self.names.iter().find(|(_, n)| *n == name).map(|(&id, _)| id)
+    }
+
+    pub fn name(&self, id: KeyspaceId) -> Option<&str> {
+        if id == DEFAULT_ID {
+            return Some(DEFAULT_KEYSPACE);
+        }
+        self.names.get(&id).map(String::as_str)
+    }
+
+    /// Ids of every keyspace, the default one first.
+    pub fn ids(&self) -> Vec<KeyspaceId> {
+        std::iter::once(DEFAULT_ID).chain(self.names.keys().copied()).collect()
+    }
+
+    /// Adds a keyspace called `name`, returning its id.
+    pub fn insert(&mut self, name: &str) -> KeyspaceId {
+        let id = self.next;
+        self.next += 1;
+        self.names.insert(id, name.to_owned());
+        id
+    }
+
+    pub fn remove(&mut self, id: KeyspaceId) {
+        self.names.remove(&id);
+    }
+}
+
+/// Directory holding the log segments of keyspace `id` of the database in
+/// `dir`.
+pub(crate) fn keyspace_dir(dir: &Path, id: KeyspaceId) -> PathBuf {
+    if id == DEFAULT_ID {
+        dir.to_owned()
+    } else {
+        dir.join(KEYSPACES_DIR).join(id.to_string())
+    }
+}
+
+/// Directories of keyspaces in the database in `dir` that are not in
+/// `catalog`: leftovers of a drop or create interrupted by a crash.
+pub(crate) fn orphaned_dirs(dir: &Path, catalog: &Catalog) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir.join(KEYSPACES_DIR)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut orphans = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let id = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.parse::<KeyspaceId>().ok());
+        if let Some(id) = id {
+            if catalog.name(id).is_none() {
+                orphans.push(path);
+            }
+        }
+    }
+    Ok(orphans)
+}
+
+/// Checks that `name` can name a keyspace: 1 to 64 ASCII letters, digits,
+/// `-` and `_`.
+pub(crate) fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidKeyspaceName(name.to_owned()))
+    }
+}
+
+fn invalid(path: &Path, reason: &'static str) -> Error {
+    Error::InvalidCatalog {
+        path: path.to_owned(),
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = Catalog::load(dir.path()).unwrap();
+        assert_eq!(empty.ids(), vec![DEFAULT_ID]);
+
+        let mut catalog = empty.clone();
+        assert_eq!(catalog.insert("users"), 1);
+        assert_eq!(catalog.insert("sessions"), 2);
+        catalog.remove(1);
+        catalog.store(dir.path()).unwrap();
+
+        let loaded = Catalog::load(dir.path()).unwrap();
+        assert_eq!(loaded.ids(), vec![DEFAULT_ID, 2]);
+        assert_eq!(loaded.id("sessions"), Some(2));
+        assert_eq!(loaded.id(DEFAULT_KEYSPACE), Some(DEFAULT_ID));
+        assert_eq!(loaded.name(1), None);
+        // Ids are not reused after a drop.
+        assert_eq!(loaded.clone().insert("users"), 3);
+    }
+
+    #[test]
+    fn rejects_a_bad_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut catalog = Catalog::load(dir.path()).unwrap();
+        catalog.insert("users");
+        catalog.store(dir.path()).unwrap();
+        let path = dir.path().join(CATALOG_FILE);
+        let text = fs::read_to_string(&path).unwrap();
+
+        let renamed = text.replace("users", "usurp");
+        let unchecked = &text[..text.find("crc ").unwrap()];
+        for (bad, reason) in &[(renamed.as_str(), "checksum mismatch"), (unchecked, "missing checksum")] {
+            fs::write(&path, bad).unwrap();
+            match Catalog::load(dir.path()) {
+                Err(Error::InvalidCatalog { reason: got, .. }) => assert_eq!(got, *reason),
+                other => panic!("expected an invalid catalog, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CATALOG_FILE);
+        // Well-formed apart from the entries, and correctly checksummed.
+        for entries in &["0 default\n", "3 users\n", "1 bad/name\n", "users\n"] {
+            let body = format!("{}\nnext 3\n{}", HEADER, entries);
+            fs::write(&path, format!("{}crc {:08x}\n", body, crc32c::crc32c(body.as_bytes()))).unwrap();
+            match Catalog::load(dir.path()) {
+                Err(Error::InvalidCatalog { reason, .. }) => assert_eq!(reason, "malformed keyspace entry"),
+                other => panic!("expected an invalid catalog, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn names_are_checked() {
+        for name in &["users", "a", "user-sessions_2", &"x".repeat(MAX_NAME_LEN)] {
+            assert!(validate_name(name).is_ok(), "{:?}", name);
+        }
+        for name in &["", "bad/name", "with space", "..", "naïve", &"x".repeat(MAX_NAME_LEN + 1)] {
+            assert!(matches!(validate_name(name), Err(Error::InvalidKeyspaceName(_))), "{:?}", name);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! RMDB, an embeddable key-value storage engine.
+//!
+//! A [`Db`] is a directory of write-ahead log segments with an in-memory
+//! index over them (see [`storage`]). Keys and values are arbitrary bytes
+//! and live in named [`Keyspace`]s. On top of single-key reads and writes
+//! the engine offers atomic multi-key writes through a [`WriteBatch`],
+//! snapshot-isolated transactions through a [`Txn`], and ordered range and
+//! prefix scans through an [`Iter`]. Every fallible operation returns the
+//! crate's [`Error`].
+//!
+//! ```
+//! use rmdb::{Db, Options, WriteBatch};
+//!
+//! # fn main() -> rmdb::Result<()> {
+//! # let dir = std::env::temp_dir().join(format!("rmdb-doc-{}", std::process::id()));
+//! let db = Db::open(&dir, Options::default())?;
+//! let users = db.create_keyspace("users")?;
+//! users.put(b"alice", b"admin")?;
+//!
+//! let mut batch = WriteBatch::new();
+//! batch.put(&users, b"bob", b"guest");
+//! batch.delete(&users, b"alice");
+//! db.write(batch)?;
+//!
+//! let mut txn = db.begin();
+//! txn.put(&users, b"carol", b"guest");
+//! assert_eq!(txn.get(&users, b"bob")?, Some(b"guest".to_vec()));
+//! txn.commit()?;
+//!
+//! for entry in users.scan_prefix(b"") {
+//!     let (key, value) = entry?;
+//!     println!("{} = {}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&value));
+//! }
+//! # drop(users);
+//! # drop(db);
+//! # std::fs::remove_dir_all(&dir)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The `rmdb` binary is a command-line front end over this API, built with
+//! the default `cli` feature; the RESP server it runs is available as
+//! [`server::serve`] either way. Embedders can turn off default features to
+//! leave out the front end's dependencies.
+
+pub mod backup;
+pub mod batch;
+pub mod command;
+pub mod compaction;
+pub mod error;
+pub mod intset;
+pub mod keyspace;
+pub mod replication;
+pub mod server;
+pub mod storage;
+pub mod table;
+pub mod txn;
+pub mod verify;
+pub mod wal;
+
+pub use batch::WriteBatch;
+pub use error::{Error, Result};
+pub use intset::IntSet;
+pub use storage::{Db, Iter, Keyspace, Options, Stats};
+pub use txn::Txn;
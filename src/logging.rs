@@ -0,0 +1,54 @@
+//! Diagnostic logging.
+//!
+//! The crate logs through [`tracing`]: events for things worth knowing
+//! about, and spans around the work they happen in (commits and WAL
+//! appends, compactions, connections and requests), so that an event can be
+//! traced back to the operation that caused it. Everything is written to
+//! standard error; standard output stays reserved for command results.
+//!
+//! Lifecycle events such as a compaction finishing are logged at `info`,
+//! per-request and per-commit detail at `debug`, and each WAL append at
+//! `trace`.
+
+use std::io::{self, IsTerminal};
+
+use tracing::level_filters::LevelFilter;
+
+use crate::config::{LogFormat, LogLevel};
+
+/// Installs the global subscriber, logging events at `level` and more
+/// severe ones in `format`.
+pub fn init(level: LogLevel, format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level_filter(level))
+        .with_writer(io::stderr)
+        .with_target(false);
+    match format {
+        LogFormat::Text => builder.with_ansi(io::stderr().is_terminal()).init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Error => LevelFilter::ERROR,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Debug => LevelFilter::DEBUG,
+        LogLevel::Trace => LevelFilter::TRACE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_map_in_order() {
+        let levels = [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug, LogLevel::Trace];
+        let filters: Vec<_> = levels.iter().map(|&level| level_filter(level)).collect();
+        assert_eq!(filters, vec![LevelFilter::ERROR, LevelFilter::WARN, LevelFilter::INFO, LevelFilter::DEBUG, LevelFilter::TRACE]);
+        // More verbose levels let more through.
+        assert!(filters.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -1,4 +1,24 @@
-use clap::{Arg, App, SubCommand};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use clap::{Arg, App, ArgMatches, SubCommand};
+
+mod bench;
+mod config;
+mod logging;
+mod shell;
+
+use rmdb::keyspace::DEFAULT_KEYSPACE;
+use rmdb::{backup, error, server, storage, table, verify, wal};
+use rmdb::{Db, Keyspace, Options, WriteBatch};
+
+use config::{Config, LogFormat};
 
 fn main() {
     let matches = App::new("RMDB")
@@ -14,7 +34,7 @@ fn main() {
                     .arg(Arg::with_name("v")
                                .short("v")
                                .multiple(true)
-                               .help("Sets the level of verbosity"))
+                               .help("Logs more detail: each use goes one level beyond log_level (info by default)"))
                     .subcommand(SubCommand::with_name("test")
                                       .about("controls testing features")
                                       .version("1.3")
@@ -22,8 +42,498 @@ fn main() {
                                       .arg(Arg::with_name("debug")
                                           .short("d")
                                           .help("print debug information verbosely")))
+                    .subcommand(SubCommand::with_name("get")
+                                      .about("prints the value stored under a key")
+                                      .arg(keyspace_arg())
+                                      .arg(Arg::with_name("key").required(true)))
+                    .subcommand(SubCommand::with_name("put")
+                                      .about("stores a value under a key")
+                                      .arg(keyspace_arg())
+                                      .arg(Arg::with_name("key").required(true))
+                                      .arg(Arg::with_name("value").required(true)))
+                    .subcommand(SubCommand::with_name("del")
+                                      .about("deletes a key")
+                                      .arg(keyspace_arg())
+                                      .arg(Arg::with_name("key").required(true)))
+                    .subcommand(SubCommand::with_name("scan")
+                                      .about("prints key-value pairs in key order")
+                                      .arg(keyspace_arg())
+                                      .arg(Arg::with_name("from")
+                                          .long("from")
+                                          .value_name("KEY")
+                                          .help("first key to print"))
+                                      .arg(Arg::with_name("to")
+                                          .long("to")
+                                          .value_name("KEY")
+                                          .help("stop before this key"))
+                                      .arg(Arg::with_name("prefix")
+                                          .long("prefix")
+                                          .value_name("PREFIX")
+                                          .conflicts_with_all(&["from", "to"])
+                                          .help("only print keys starting with PREFIX")))
+                    .subcommand(SubCommand::with_name("import")
+                                      .about("loads tab-separated key-value lines, as printed by scan")
+                                      .arg(keyspace_arg())
+                                      .arg(Arg::with_name("file")
+                                          .required(true)
+                                          .help("file to read, or - for standard input"))
+                                      .arg(Arg::with_name("batch-size")
+                                          .long("batch-size")
+                                          .value_name("LINES")
+                                          .default_value("1000")
+                                          .help("number of lines committed atomically together")))
+                    .subcommand(SubCommand::with_name("compact")
+                                      .about("rewrites the log now to reclaim space from old values")
+                                      .arg(keyspace_arg()
+                                          .help("keyspace to compact; all of them if not given")))
+                    .subcommand(SubCommand::with_name("keyspace")
+                                      .about("manages keyspaces")
+                                      .subcommand(SubCommand::with_name("list")
+                                          .about("lists every keyspace with its size"))
+                                      .subcommand(SubCommand::with_name("create")
+                                          .about("creates an empty keyspace")
+                                          .arg(Arg::with_name("name").required(true)))
+                                      .subcommand(SubCommand::with_name("drop")
+                                          .about("drops a keyspace and deletes everything in it")
+                                          .arg(Arg::with_name("name").required(true))))
+                    .subcommand(SubCommand::with_name("backup")
+                                      .about("writes a point-in-time copy of the database to a new directory")
+                                      .arg(Arg::with_name("dest").required(true)))
+                    .subcommand(SubCommand::with_name("restore")
+                                      .about("verifies a backup and restores it into the data directory")
+                                      .arg(Arg::with_name("src").required(true))
+                                      .arg(Arg::with_name("verify-only")
+                                          .long("verify-only")
+                                          .help("only check the backup against its manifest")))
+                    .subcommand(SubCommand::with_name("verify")
+                                      .about("checks every data file for damage; exits with status 1 if any is found")
+                                      .arg(Arg::with_name("salvage")
+                                          .long("salvage")
+                                          .value_name("DIR")
+                                          .help("also copies every intact record into a new database in DIR")))
+                    .subcommand(SubCommand::with_name("bench")
+                                      .about("runs built-in workloads and reports throughput and latency")
+                                      .arg(Arg::with_name("workloads")
+                                          .long("workloads")
+                                          .value_name("LIST")
+                                          .default_value("fill-seq,fill-random,read-random,mixed")
+                                          .help("comma-separated workloads to run in order: fill-seq, fill-random, read-random, mixed"))
+                                      .arg(Arg::with_name("num")
+                                          .long("num")
+                                          .value_name("N")
+                                          .default_value("100000")
+                                          .help("operations per workload, and the number of distinct keys"))
+                                      .arg(Arg::with_name("value-size")
+                                          .long("value-size")
+                                          .value_name("BYTES")
+                                          .default_value("100")
+                                          .help("size of each value written"))
+                                      .arg(Arg::with_name("threads")
+                                          .long("threads")
+                                          .value_name("N")
+                                          .default_value("1")
+                                          .help("number of threads sharing each workload"))
+                                      .arg(Arg::with_name("read-ratio")
+                                          .long("read-ratio")
+                                          .value_name("PERCENT")
+                                          .default_value("90")
+                                          .help("percentage of mixed operations that are reads"))
+                                      .arg(Arg::with_name("dir")
+                                          .long("dir")
+                                          .value_name("DIR")
+                                          .help("database directory to use and keep; by default a scratch directory is created and removed")))
+                    .subcommand(SubCommand::with_name("serve")
+                                      .about("serves the database over the network")
+                                      .arg(Arg::with_name("log-format")
+                                          .long("log-format")
+                                          .value_name("FORMAT")
+                                          .possible_values(&["text", "json"])
+                                          .help("writes logs as text or as JSON objects, overriding log_format"))
+                                      .arg(Arg::with_name("replica-of")
+                                          .long("replica-of")
+                                          .value_name("ADDR")
+                                          .help("serves as a read-only replica of the primary at ADDR")))
+                    .subcommand(SubCommand::with_name("shell")
+                                      .about("opens an interactive prompt against the database")
+                                      .arg(keyspace_arg()))
+                    .subcommand(SubCommand::with_name("wal")
+                                      .about("write-ahead log tools")
+                                      .subcommand(SubCommand::with_name("inspect")
+                                          .about("dumps and verifies every log record")))
                     .get_matches();
 
-    let config = matches.value_of("config").unwrap_or("default.conf");
-    println!("Value for config: {}", config);
+    let config = match Config::load(matches.value_of("config").map(Path::new)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("rmdb: {}", e);
+            process::exit(1);
+        }
+    };
+    let log_format = match matches.subcommand_matches("serve").and_then(|sub| sub.value_of("log-format")) {
+        Some(format) => format.parse::<LogFormat>().unwrap(),
+        None => config.log_format,
+    };
+    logging::init(config.log_level.raised(matches.occurrences_of("v")), log_format);
+
+    let result = match matches.subcommand() {
+        ("test", Some(sub)) => {
+            test(&config, sub);
+            Ok(())
+        }
+        ("get", Some(sub)) => get(&config, sub),
+        ("put", Some(sub)) => put(&config, sub),
+        ("del", Some(sub)) => del(&config, sub),
+        ("scan", Some(sub)) => scan(&config, sub),
+        ("import", Some(sub)) => import(&config, sub),
+        ("compact", Some(sub)) => compact(&config, sub),
+        ("keyspace", Some(sub)) => match sub.subcommand() {
+            ("list", Some(_)) => keyspace_list(&config),
+            ("create", Some(cmd)) => {
+                open_db(&config).and_then(|db| db.create_keyspace(cmd.value_of("name").unwrap()).map(|_| ()))
+            }
+            ("drop", Some(cmd)) => open_db(&config).and_then(|db| db.drop_keyspace(cmd.value_of("name").unwrap())),
+            _ => {
+                eprintln!("{}", sub.usage());
+                process::exit(1);
+            }
+        },
+        ("backup", Some(sub)) => backup(&config, sub),
+        ("restore", Some(sub)) => restore(&config, sub),
+        ("verify", Some(sub)) => verify(&config, sub),
+        ("bench", Some(sub)) => bench(&config, sub),
+        ("serve", Some(sub)) => serve(&config, sub),
+        ("shell", Some(sub)) => open_db(&config).and_then(|db| shell::run(keyspace(&db, sub)?)),
+        ("wal", Some(sub)) => match sub.subcommand() {
+            ("inspect", Some(_)) => wal_inspect(&config),
+            _ => {
+                eprintln!("{}", sub.usage());
+                process::exit(1);
+            }
+        },
+        _ => {
+            println!("{}", config);
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("rmdb: {}", e);
+        process::exit(1);
+    }
+}
+
+fn test(config: &Config, matches: &ArgMatches) {
+    if matches.is_present("debug") {
+        println!("{:#?}", config);
+    } else {
+        println!("{}", config);
+    }
+}
+
+fn open_db(config: &Config) -> error::Result<Db> {
+    let options = Options {
+        fsync: config.wal.fsync,
+        segment_size: config.wal.segment_size,
+        compaction: config.compaction,
+        replication: config.replication,
+        read: config.read,
+        max_memory: config.max_memory,
+    };
+    Db::open(&config.data_dir, options)
+}
+
+fn serve(config: &Config, matches: &ArgMatches) -> error::Result<()> {
+    let replica_of = if matches.is_present("replica-of") {
+        Some(flag_value(matches, "replica-of", "an address such as 127.0.0.1:6380", |_: &SocketAddr| true))
+    } else {
+        None
+    };
+    let db = open_db(config)?;
+    server::serve(Arc::new(db), config.listen_addr, replica_of)
+}
+
+/// The `--keyspace` flag shared by the commands that read or write keys.
+fn keyspace_arg() -> Arg<'static, 'static> {
+    Arg::with_name("keyspace")
+        .short("k")
+        .long("keyspace")
+        .value_name("NAME")
+        .help("keyspace to use instead of the default one")
+}
+
+fn keyspace<'db>(db: &'db Db, matches: &ArgMatches) -> error::Result<Keyspace<'db>> {
+    db.keyspace(matches.value_of("keyspace").unwrap_or(DEFAULT_KEYSPACE))
+}
+
+fn get(config: &Config, matches: &ArgMatches) -> error::Result<()> {
+    let db = open_db(config)?;
+    match keyspace(&db, matches)?.get(matches.value_of("key").unwrap().as_bytes())? {
+        Some(value) => println!("{}", String::from_utf8_lossy(&value)),
+        None => println!("(nil)"),
+    }
+    Ok(())
+}
+
+fn put(config: &Config, matches: &ArgMatches) -> error::Result<()> {
+    let db = open_db(config)?;
+    keyspace(&db, matches)?.put(matches.value_of("key").unwrap().as_bytes(), matches.value_of("value").unwrap().as_bytes())
+}
+
+fn del(config: &Config, matches: &ArgMatches) -> error::Result<()> {
+    let db = open_db(config)?;
+    let existed = keyspace(&db, matches)?.delete(matches.value_of("key").unwrap().as_bytes())?;
+    println!("{}", if existed { 1 } else { 0 });
+    Ok(())
+}
+
+fn scan(config: &Config, matches: &ArgMatches) -> error::Result<()> {
+    let db = open_db(config)?;
+    let keyspace = keyspace(&db, matches)?;
+    let pairs = match matches.value_of("prefix") {
+        Some(prefix) => keyspace.scan_prefix(prefix.as_bytes()),
+        None => keyspace.scan::<str, _>((
+            matches.value_of("from").map_or(Bound::Unbounded, Bound::Included),
+            matches.value_of("to").map_or(Bound::Unbounded, Bound::Excluded),
+        )),
+    };
+    for pair in pairs {
+        let (key, value) = pair?;
+        println!("{}\t{}", String::from_utf8_lossy(&key), String::from_utf8_lossy(&value));
+    }
+    Ok(())
+}
+
+/// Parses the value of the flag `name`, exiting with `expected` in the
+/// message if it is not a `T` accepted by `valid`.
+fn flag_value<T: FromStr>(matches: &ArgMatches, name: &str, expected: &str, valid: impl Fn(&T) -> bool) -> T {
+    match matches.value_of(name).unwrap().parse::<T>() {
+        Ok(value) if valid(&value) => value,
+        _ => {
+            eprintln!("rmdb: --{} must be {}", name, expected);
+            process::exit(1);
+        }
+    }
+}
+
+fn import(config: &Config, matches: &ArgMatches) -> error::Result<()> {
+    let batch_size = flag_value(matches, "batch-size", "a positive integer", |&n: &usize| n > 0);
+    let input: Box<dyn BufRead> = match matches.value_of("file").unwrap() {
+        "-" => Box::new(BufReader::new(io::stdin())),
+        path => Box::new(BufReader::new(File::open(path)?)),
+    };
+
+    let db = open_db(config)?;
+    let keyspace = keyspace(&db, matches)?;
+    let mut batch = WriteBatch::new();
+    let mut count = 0;
+    // Keys and values are raw bytes; only the tab and newline are special.
+    for (i, line) in input.split(b'\n').enumerate() {
+        let mut line = line?;
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let tab = line.iter().position(|&b| b == b'\t').ok_or(error::Error::InvalidInput {
+            line: i as u64 + 1,
+            reason: "expected a tab between key and value",
+        })?;
+        batch.put(&keyspace, &line[..tab], &line[tab + 1..]);
+        if batch.len() == batch_size {
+            count += batch.len();
+            db.write(std::mem::take(&mut batch))?;
+        }
+    }
+    if !batch.is_empty() {
+        count += batch.len();
+        db.write(batch)?;
+    }
+
+    println!("imported {} keys", count);
+    Ok(())
+}
+
+fn compact(config: &Config, matches: &ArgMatches) -> error::Result<()> {
+    let db = open_db(config)?;
+    let keyspaces = match matches.value_of("keyspace") {
+        Some(name) => vec![db.keyspace(name)?],
+        None => db.keyspaces(),
+    };
+    for keyspace in keyspaces {
+        let stats = keyspace.compact()?;
+        println!(
+            "{}: compacted {} segments: {} bytes -> {} bytes",
+            keyspace.name(),
+            stats.segments,
+            stats.bytes_before,
+            stats.bytes_after
+        );
+    }
+    Ok(())
+}
+
+fn keyspace_list(config: &Config) -> error::Result<()> {
+    let db = open_db(config)?;
+    for keyspace in db.keyspaces() {
+        let stats = keyspace.stats()?;
+        println!(
+            "{}\t{} keys\t{} bytes\t{} segments",
+            keyspace.name(),
+            stats.keys,
+            stats.log_bytes,
+            stats.segments
+        );
+    }
+    Ok(())
+}
+
+fn backup(config: &Config, matches: &ArgMatches) -> error::Result<()> {
+    let db = open_db(config)?;
+    let dest = Path::new(matches.value_of("dest").unwrap());
+    let manifest = db.backup(dest)?;
+    println!(
+        "backed up {} files, {} bytes, through #{} to {}",
+        manifest.files.len(),
+        manifest.bytes(),
+        manifest.seq,
+        dest.display()
+    );
+    Ok(())
+}
+
+fn restore(config: &Config, matches: &ArgMatches) -> error::Result<()> {
+    let src = Path::new(matches.value_of("src").unwrap());
+    if matches.is_present("verify-only") {
+        let manifest = backup::verify(src)?;
+        println!("{}: {} files, {} bytes, through #{}: ok", src.display(), manifest.files.len(), manifest.bytes(), manifest.seq);
+        return Ok(());
+    }
+    let manifest = backup::restore(src, &config.data_dir)?;
+    println!("restored {} files through #{} into {}", manifest.files.len(), manifest.seq, config.data_dir.display());
+    Ok(())
+}
+
+fn verify(config: &Config, matches: &ArgMatches) -> error::Result<()> {
+    let salvage = matches.value_of("salvage").map(Path::new);
+    let report = verify::verify(&config.data_dir, salvage)?;
+    for file in &report.files {
+        println!("{}: {} records, {} bytes", file.name, file.records, file.bytes);
+        for damage in &file.damaged {
+            println!("  damaged: {} bytes at offset {}: {}", damage.len, damage.offset, damage.reason);
+        }
+        if let Some(offset) = file.torn {
+            println!("  torn record at offset {}; it will be discarded on the next open", offset);
+        }
+    }
+    print!("{} keyspaces, {} files, {} records: ", report.keyspaces, report.files.len(), report.records());
+    if report.damaged_regions() == 0 {
+        println!("ok");
+    } else {
+        println!("{} damaged regions, {} bytes", report.damaged_regions(), report.damaged_bytes());
+    }
+    if let Some(dest) = salvage {
+        println!("salvaged {} records into {}", report.records(), dest.display());
+    }
+    if report.damaged_regions() > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn bench(config: &Config, matches: &ArgMatches) -> error::Result<()> {
+    let mut workloads = Vec::new();
+    for name in matches.value_of("workloads").unwrap().split(',') {
+        match name.parse() {
+            Ok(workload) => workloads.push(workload),
+            Err(()) => {
+                eprintln!("rmdb: unknown workload '{}'", name);
+                process::exit(1);
+            }
+        }
+    }
+    let options = bench::BenchOptions {
+        workloads,
+        num: flag_value(matches, "num", "a positive integer", |&n: &u64| n > 0),
+        value_size: flag_value(matches, "value-size", "a number of bytes", |_: &usize| true),
+        threads: flag_value(matches, "threads", "a positive integer", |&n: &usize| n > 0),
+        read_percent: flag_value(matches, "read-ratio", "a percentage from 0 to 100", |&n: &u32| n <= 100),
+    };
+
+    // Never benchmark against the configured data directory: the fills
+    // would overwrite real keys.
+    let (dir, scratch) = match matches.value_of("dir") {
+        Some(dir) => (Path::new(dir).to_owned(), false),
+        None => (std::env::temp_dir().join(format!("rmdb-bench-{}", process::id())), true),
+    };
+    let mut bench_config = config.clone();
+    bench_config.data_dir = dir.clone();
+    let db = open_db(&bench_config)?;
+    println!(
+        "{} ops per workload, {} byte values, {} threads, fsync {}, in {}",
+        options.num,
+        options.value_size,
+        options.threads,
+        config.wal.fsync,
+        dir.display()
+    );
+    let result = bench::run(&db.default_keyspace(), &options, |report| println!("{}", report));
+    drop(db);
+    if scratch {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    result
+}
+
+fn wal_inspect(config: &Config) -> error::Result<()> {
+    let mut count = 0;
+    let mut bytes = 0;
+    for path in storage::segment_files(&config.data_dir)? {
+        println!("{}:", path.display());
+        let mut reader = wal::Reader::open(&path)?;
+        count += inspect_segment(&mut reader)?;
+        bytes += reader.valid_len();
+        if reader.torn() {
+            println!("torn record at offset {}; it will be discarded on the next open", reader.valid_len());
+        }
+    }
+    println!("{} records, {} bytes", count, bytes);
+    Ok(())
+}
+
+/// Prints every record of one segment, returning how many there were.
+fn inspect_segment(reader: &mut wal::Reader<File>) -> error::Result<usize> {
+    let mut count = 0;
+    for entry in reader {
+        let (offset, record) = entry?;
+        // Compaction ends a segment with one of these to keep the sequence
+        // number of the last commit it covers.
+        if record.ops.is_empty() {
+            println!("{:>12}  {:<9} (no operations)", offset, format!("#{}", record.seq));
+        }
+        // Operations after the first of a commit leave the offset and
+        // sequence columns blank. Table blocks have no commit of their own.
+        for (i, op) in record.ops.iter().enumerate() {
+            let (offset, seq) = if i == 0 && record.seq == table::BLOCK_SEQ {
+                (offset.to_string(), "block".to_owned())
+            } else if i == 0 {
+                (offset.to_string(), format!("#{}", record.seq))
+            } else {
+                (String::new(), String::new())
+            };
+            match op {
+                wal::Op::Put { key, value } => println!(
+                    "{:>12}  {:<9} put  {} ({} bytes)",
+                    offset,
+                    seq,
+                    String::from_utf8_lossy(key),
+                    value.len()
+                ),
+                wal::Op::Delete { key } => {
+                    println!("{:>12}  {:<9} del  {}", offset, seq, String::from_utf8_lossy(key))
+                }
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
 }
@@ -0,0 +1,1066 @@
+//! Primary/replica replication (`rmdb serve --replica-of`).
+//!
+//! Once it is serving, a primary keeps its most recent changes in memory, in
+//! a backlog bounded by `replication.backlog_size`: every commit, with the
+//! keyspaces it wrote to named, and every keyspace created or dropped. A
+//! database opened for anything else keeps none. A replica connects to
+//! the primary's ordinary port and sends
+//!
+//! ```text
+//! sync <seq>
+//! ```
+//!
+//! with the sequence number of the last commit it has applied. If the
+//! backlog still holds everything after that commit, the primary answers
+//! `continue` with the names of its keyspaces, which the replica matches,
+//! and streams from there. Otherwise, or if the replica sends a
+//! bare `sync`, the primary takes a snapshot and sends all of it first: a
+//! `full` message with the snapshot's sequence number, every keyspace
+//! followed by its keys, and `synced`. Either way it then streams each
+//! change as it happens, and pings once a second with its last sequence
+//! number so the replica can tell how far behind it is. The replica acks
+//! what it has applied, so the primary can tell too. Every message is a
+//! RESP array of bulk strings, like a request.
+//!
+//! Sending a snapshot can take longer than the backlog lasts on a busy
+//! primary, and the changes made meanwhile are streamed after it. So from
+//! the moment the snapshot is taken until the replica acks it, the backlog
+//! keeps every change the replica has yet to be sent, even past its size.
+//!
+//! A replica applies each commit under the primary's own sequence number,
+//! so after a restart it resumes from its last one. Before loading a
+//! snapshot it empties its database and leaves a `SYNCING` file in the data
+//! directory until the load is durable; if the file is still there when it
+//! reconnects, the load was interrupted and it asks for a new one. Replicas
+//! serve reads but refuse writes, and cannot themselves be followed.
+//!
+//! `replication` reports either side's view: its role, and per replica or
+//! for the primary, the sequence numbers applied and the lag in commits.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+#[cfg(test)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tracing::{info, info_span, warn};
+
+use crate::batch::WriteBatch;
+use crate::command::Reply;
+use crate::error::{Error, Result};
+use crate::keyspace::DEFAULT_KEYSPACE;
+use crate::server;
+use crate::storage::{Db, Keyspace};
+use crate::wal::{self, Op, Record};
+
+/// Left in the data directory of a replica while it loads a snapshot.
+pub const SYNCING_FILE: &str = "SYNCING";
+
+/// How often the streaming side checks for shutdown and acks.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Silence from the primary after which a replica gives up on the link.
+const LINK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait before a replica reconnects after losing the primary.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Keys of a snapshot a replica writes per commit while loading it.
+const LOAD_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplicationOptions {
+    /// Bytes of recent changes kept for replicas to catch up from. A
+    /// replica further behind than this needs a full sync.
+    pub backlog_size: u64,
+}
+
+/// A change to the database, as streamed to replicas.
+#[derive(Debug)]
+pub(crate) enum Event {
+    /// A commit, as the parts written to each keyspace.
+    Commit { seq: u64, parts: Vec<(String, Vec<Op>)> },
+    /// Keyspace changes carry the sequence number of the last commit
+    /// before them.
+    CreateKeyspace { seq: u64, name: String },
+    DropKeyspace { seq: u64, name: String },
+}
+
+/// The replication state of a database: the backlog, and which replicas
+/// follow it or which primary it follows.
+pub(crate) struct Replication {
+    backlog_size: u64,
+    /// Events in the order they happened. Each has a position in the stream
+    /// of every event since the database was opened; the first held is at
+    /// `start`.
+    events: VecDeque<Arc<Event>>,
+    start: u64,
+    bytes: u64,
+    /// Every event after the commit with this sequence number is held.
+    base_seq: u64,
+    /// Whether events are kept at all. Not until the database serves, or a
+    /// replica connects; before then a replica has nothing to resume.
+    enabled: bool,
+    /// Signalled whenever an event is added.
+    changed: Arc<Condvar>,
+    replicas: BTreeMap<u64, Peer>,
+    next_peer: u64,
+    upstream: Option<Upstream>,
+}
+
+/// A replica, as its primary sees it.
+struct Peer {
+    addr: SocketAddr,
+    streaming: bool,
+    /// Sequence number of the last commit it has acknowledged.
+    acked: u64,
+    /// Until it acks the snapshot it was sent: the position of the next
+    /// event to send it, and the snapshot's sequence number. The backlog
+    /// holds every event from that position on.
+    pin: Option<(u64, u64)>,
+}
+
+/// The primary, as a replica sees it.
+struct Upstream {
+    addr: SocketAddr,
+    connected: bool,
+    /// Last sequence number the primary reported.
+    seq: u64,
+    last_contact: Option<Instant>,
+}
+
+/// A connection between a primary and a replica: a TCP stream, except in
+/// tests, which run both ends over a Unix socket pair.
+pub(crate) trait Link {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Default for ReplicationOptions {
+    fn default() -> ReplicationOptions {
+        ReplicationOptions { backlog_size: 16 << 20 }
+    }
+}
+
+impl Event {
+    fn seq(&self) -> u64 {
+        match self {
+            Event::Commit { seq, .. } | Event::CreateKeyspace { seq, .. } | Event::DropKeyspace { seq, .. } => *seq,
+        }
+    }
+
+    /// Rough size in memory, for bounding the backlog.
+    fn size(&self) -> u64 {
+        match self {
+            Event::Commit { parts, .. } => parts
+                .iter()
+                .flat_map(|(name, ops)| ops.iter().map(move |op| name.len() + op_size(op)))
+                .sum::<usize>() as u64,
+            Event::CreateKeyspace { name, .. } | Event::DropKeyspace { name, .. } => name.len() as u64,
+        }
+    }
+}
+
+fn op_size(op: &Op) -> usize {
+    match op {
+        Op::Put { key, value } => key.len() + value.len() + 16,
+        Op::Delete { key } => key.len() + 16,
+    }
+}
+
+impl Replication {
+    /// Starts with an empty backlog, for a database whose last commit is
+    /// `seq`.
+    pub fn new(options: ReplicationOptions, seq: u64) -> Replication {
+        Replication {
+            backlog_size: options.backlog_size,
+            events: VecDeque::new(),
+            start: 0,
+            bytes: 0,
+            base_seq: seq,
+            enabled: false,
+            changed: Arc::new(Condvar::new()),
+            replicas: BTreeMap::new(),
+            next_peer: 0,
+            upstream: None,
+        }
+    }
+
+    /// Starts keeping events, after the commit numbered `seq`. Does nothing
+    /// if already started.
+    pub fn enable(&mut self, seq: u64) {
+        if !self.enabled {
+            self.enabled = true;
+            self.base_seq = seq;
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Adds `event` to the backlog, dropping the oldest events beyond its
+    /// size that no replica loading a snapshot still needs. Does nothing
+    /// until the backlog is enabled.
+    pub fn push(&mut self, event: Event) {
+        if !self.enabled {
+            return;
+        }
+        self.bytes += event.size();
+        self.events.push_back(Arc::new(event));
+        let pinned = self.replicas.values().filter_map(|peer| peer.pin).map(|(position, _)| position).min();
+        while self.bytes > self.backlog_size && pinned.is_none_or(|position| self.start < position) {
+            let event = match self.events.pop_front() {
+                Some(event) => event,
+                None => break,
+            };
+            self.bytes -= event.size();
+            self.start += 1;
+            // Without a keyspace change, a replica that has seen the commit
+            // before it has not necessarily seen the change.
+            self.base_seq = match *event {
+                Event::Commit { seq, .. } => seq,
+                _ => event.seq() + 1,
+            }
+            .max(self.base_seq);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Position just past the last event held.
+    pub fn end(&self) -> u64 {
+        self.start + self.events.len() as u64
+    }
+
+    /// Position of the first event a replica that has applied every commit
+    /// through `seq` is missing, or `None` if the backlog no longer holds
+    /// them all. `last_seq` is the database's own last commit.
+    pub fn resume(&self, seq: u64, last_seq: u64) -> Option<u64> {
+        if seq < self.base_seq || seq > last_seq {
+            return None;
+        }
+        // Keyspace changes made after commit `seq` carry `seq` themselves;
+        // sending one the replica already has does no harm.
+        let skip = self.events.iter().take_while(|event| match ***event {
+            Event::Commit { seq: s, .. } => s <= seq,
+            _ => event.seq() < seq,
+        });
+        Some(self.start + skip.count() as u64)
+    }
+
+    /// The events from position `from` on, or `None` if some of them have
+    /// been dropped.
+    pub fn read(&self, from: u64) -> Option<Vec<Arc<Event>>> {
+        if from < self.start {
+            return None;
+        }
+        let skip = (from - self.start) as usize;
+        Some(self.events.iter().skip(skip).cloned().collect())
+    }
+
+    pub fn changed(&self) -> Arc<Condvar> {
+        self.changed.clone()
+    }
+
+    /// The primary this database follows, if it is a replica.
+    pub fn primary(&self) -> Option<SocketAddr> {
+        self.upstream.as_ref().map(|upstream| upstream.addr)
+    }
+
+    /// A report for `replication`, for a database whose last commit is
+    /// `last_seq`.
+    pub fn info(&self, last_seq: u64) -> String {
+        let mut info = String::new();
+        match &self.upstream {
+            Some(upstream) => {
+                let _ = writeln!(info, "role:replica");
+                let _ = writeln!(info, "primary:{}", upstream.addr);
+                let _ = writeln!(info, "link:{}", if upstream.connected { "up" } else { "down" });
+                let _ = writeln!(info, "last_seq:{}", last_seq);
+                let _ = writeln!(info, "primary_seq:{}", upstream.seq);
+                let _ = writeln!(info, "lag:{}", upstream.seq.saturating_sub(last_seq));
+                match upstream.last_contact {
+                    Some(at) => {
+                        let _ = write!(info, "last_contact_ms:{}", at.elapsed().as_millis());
+                    }
+                    None => {
+                        let _ = write!(info, "last_contact_ms:-1");
+                    }
+                }
+            }
+            None => {
+                let _ = writeln!(info, "role:primary");
+                let _ = writeln!(info, "last_seq:{}", last_seq);
+                let _ = writeln!(info, "backlog_bytes:{}", self.bytes);
+                let _ = write!(info, "replicas:{}", self.replicas.len());
+                for (i, peer) in self.replicas.values().enumerate() {
+                    let _ = write!(
+                        info,
+                        "\nreplica{}:addr={},state={},acked_seq={},lag={}",
+                        i,
+                        peer.addr,
+                        if peer.streaming { "streaming" } else { "syncing" },
+                        peer.acked,
+                        last_seq.saturating_sub(peer.acked)
+                    );
+                }
+            }
+        }
+        info
+    }
+
+    fn add_peer(&mut self, addr: SocketAddr, acked: u64) -> u64 {
+        let id = self.next_peer;
+        self.next_peer += 1;
+        self.replicas.insert(
+            id,
+            Peer {
+                addr,
+                streaming: false,
+                acked,
+                pin: None,
+            },
+        );
+        id
+    }
+
+    /// Holds the events from `position` on for replica `id`, which is being
+    /// sent the snapshot numbered `seq`.
+    pub fn pin(&mut self, id: u64, position: u64, seq: u64) {
+        self.peer(id).pin = Some((position, seq));
+    }
+
+    /// Records that replica `id` has been sent every event before
+    /// `position`.
+    fn advance(&mut self, id: u64, position: u64) {
+        if let Some((pinned, _)) = &mut self.peer(id).pin {
+            *pinned = position;
+        }
+    }
+
+    /// Records that replica `id` has applied every commit through `seq`,
+    /// releasing its pin once that includes its snapshot.
+    fn ack(&mut self, id: u64, seq: u64) {
+        let peer = self.peer(id);
+        peer.acked = seq;
+        if peer.pin.is_some_and(|(_, snapshot)| seq >= snapshot) {
+            peer.pin = None;
+        }
+    }
+
+    fn peer(&mut self, id: u64) -> &mut Peer {
+        self.replicas.get_mut(&id).unwrap()
+    }
+
+    fn upstream(&mut self) -> &mut Upstream {
+        self.upstream.as_mut().unwrap()
+    }
+}
+
+impl Link for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(test)]
+impl Link for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Streams changes to the replica on `stream`, which sent `sync` with
+/// `args`, until either side disconnects or the server shuts down. If
+/// streaming fails, the replica is told why before the error is returned.
+pub(crate) fn serve_replica(stream: &TcpStream, db: &Db, args: &[Vec<u8>], shutdown: &AtomicBool) -> Result<()> {
+    serve_link(stream, stream.peer_addr()?, db, args, shutdown)
+}
+
+fn serve_link<L: Link>(stream: &L, addr: SocketAddr, db: &Db, args: &[Vec<u8>], shutdown: &AtomicBool) -> Result<()>
+where
+    for<'a> &'a L: Read + Write,
+{
+    let result = start_replica(stream, addr, db, args, shutdown);
+    if let Err(e) = &result {
+        let reason = match e {
+            Error::Replication(reason) => reason.clone(),
+            e => e.to_string(),
+        };
+        let _ = send(&mut &*stream, &[b"error".to_vec(), reason.into_bytes()]);
+    }
+    result
+}
+
+fn start_replica<L: Link>(stream: &L, addr: SocketAddr, db: &Db, args: &[Vec<u8>], shutdown: &AtomicBool) -> Result<()>
+where
+    for<'a> &'a L: Read + Write,
+{
+    let from = match args {
+        [] => None,
+        [seq] => Some(parse_seq(seq)?),
+        _ => return Err(error("wrong number of arguments for 'sync'")),
+    };
+    if let Some(primary) = db.replication(|replication, _| replication.primary()) {
+        return Err(Error::Replication(format!("this server is itself a replica of {}", primary)));
+    }
+    let id = db.replication(|replication, seq| {
+        replication.enable(seq);
+        replication.add_peer(addr, from.unwrap_or(0))
+    });
+    let result = stream_changes(stream, db, id, from, shutdown);
+    db.replication(|replication, _| replication.replicas.remove(&id));
+    result
+}
+
+fn stream_changes<L: Link>(stream: &L, db: &Db, id: u64, from: Option<u64>, shutdown: &AtomicBool) -> Result<()>
+where
+    for<'a> &'a L: Read + Write,
+{
+    let mut out = BufWriter::new(stream);
+    let mut cursor = match from.and_then(|seq| db.resume_replica(seq)) {
+        Some((cursor, keyspaces)) => {
+            info!(seq = from.unwrap(), "replica continuing from the backlog");
+            // Keyspaces created or dropped while the server was down are not
+            // in the backlog, so the replica gets the list.
+            let mut message = vec![b"continue".to_vec(), db.last_seq().to_string().into_bytes()];
+            message.extend(keyspaces.into_iter().map(String::into_bytes));
+            send(&mut out, &message)?;
+            cursor
+        }
+        None => send_snapshot(&mut out, db, id)?,
+    };
+    out.flush()?;
+    db.replication(|replication, _| replication.peer(id).streaming = true);
+
+    stream.set_read_timeout(Some(Duration::from_millis(1)))?;
+    let mut input = Vec::new();
+    let mut last_ping = Instant::now();
+    while !shutdown.load(Ordering::Relaxed) {
+        let events = db
+            .wait_for_events(cursor, POLL_INTERVAL)
+            .ok_or_else(|| error("the replica fell behind the backlog and must resync"))?;
+        for event in &events {
+            send(&mut out, &encode_event(event)?)?;
+        }
+        cursor += events.len() as u64;
+        db.replication(|replication, _| replication.advance(id, cursor));
+        if last_ping.elapsed() >= PING_INTERVAL {
+            send(&mut out, &[b"ping".to_vec(), db.last_seq().to_string().into_bytes()])?;
+            last_ping = Instant::now();
+        }
+        out.flush()?;
+
+        if !read_available(stream, &mut input)? {
+            return Ok(());
+        }
+        while let Some(message) = next_message(&mut input)? {
+            match &message[..] {
+                [kind, seq] if kind == b"ack" => {
+                    let seq = parse_seq(seq)?;
+                    db.replication(|replication, _| replication.ack(id, seq));
+                }
+                _ => return Err(error("unexpected message from the replica")),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends a snapshot of the whole database to replica `id`, returning the
+/// position in the backlog of the first event after it. The backlog holds
+/// the events from there on until the replica acks the snapshot.
+fn send_snapshot<W: Write>(out: &mut W, db: &Db, id: u64) -> Result<u64> {
+    let (cursor, seq, txn, keyspaces) = db.replication_snapshot(id);
+    info!(seq, keyspaces = keyspaces.len(), "sending a snapshot to a replica");
+    db.replication(|replication, _| replication.peer(id).acked = 0);
+    send(out, &[b"full".to_vec(), seq.to_string().into_bytes()])?;
+    for keyspace in &keyspaces {
+        let name = keyspace.name().as_bytes().to_vec();
+        send(out, &[b"keyspace".to_vec(), name.clone()])?;
+        for pair in txn.scan::<[u8], _>(keyspace, ..) {
+            let (key, value) = pair?;
+            send(out, &[b"put".to_vec(), name.clone(), key, value])?;
+        }
+    }
+    send(out, &[b"synced".to_vec()])?;
+    txn.rollback();
+    Ok(cursor)
+}
+
+/// Encodes `event` as a message. The operations of each part of a commit
+/// travel as a log record, checksum included.
+fn encode_event(event: &Event) -> Result<Vec<Vec<u8>>> {
+    Ok(match event {
+        Event::Commit { seq, parts } => {
+            let mut message = vec![b"commit".to_vec()];
+            for (name, ops) in parts {
+                message.push(name.as_bytes().to_vec());
+                message.push(wal::encode(&Record {
+                    seq: *seq,
+                    ops: ops.clone(),
+                })?);
+            }
+            message
+        }
+        Event::CreateKeyspace { name, .. } => vec![b"create".to_vec(), name.as_bytes().to_vec()],
+        Event::DropKeyspace { name, .. } => vec![b"drop".to_vec(), name.as_bytes().to_vec()],
+    })
+}
+
+/// Makes `db` a read-only replica of the primary at `addr`, and starts a
+/// thread that follows it, reconnecting whenever the link drops, until
+/// `shutdown` is raised.
+pub(crate) fn follow(db: Arc<Db>, addr: SocketAddr, shutdown: Arc<AtomicBool>) -> JoinHandle<()> {
+    db.replication(|replication, _| {
+        replication.upstream = Some(Upstream {
+            addr,
+            connected: false,
+            seq: 0,
+            last_contact: None,
+        })
+    });
+    thread::spawn(move || {
+        let _span = info_span!("replication", primary = %addr).entered();
+        while !shutdown.load(Ordering::Relaxed) {
+            if let Err(e) = Follower::new(&db).run(addr, &shutdown) {
+                warn!(error = %e, "replication link failed");
+            }
+            db.replication(|replication, _| replication.upstream().connected = false);
+            let retry = Instant::now() + RETRY_INTERVAL;
+            while !shutdown.load(Ordering::Relaxed) && Instant::now() < retry {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    })
+}
+
+/// One connection of a replica to its primary.
+struct Follower<'db> {
+    db: &'db Db,
+    /// The snapshot being loaded, if any.
+    loading: Option<Load<'db>>,
+}
+
+struct Load<'db> {
+    seq: u64,
+    keyspace: Option<Keyspace<'db>>,
+    batch: WriteBatch,
+}
+
+impl<'db> Follower<'db> {
+    fn new(db: &'db Db) -> Follower<'db> {
+        Follower { db, loading: None }
+    }
+
+    fn run(&mut self, addr: SocketAddr, shutdown: &AtomicBool) -> Result<()> {
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        self.run_link(&stream, shutdown)
+    }
+
+    fn run_link<L: Link>(&mut self, stream: &L, shutdown: &AtomicBool) -> Result<()>
+    where
+        for<'a> &'a L: Read + Write,
+    {
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let mut out = BufWriter::new(stream);
+
+        let mut request = vec![b"sync".to_vec()];
+        if !self.db.dir().join(SYNCING_FILE).exists() {
+            request.push(self.db.last_seq().to_string().into_bytes());
+        }
+        send(&mut out, &request)?;
+        out.flush()?;
+        self.db.replication(|replication, _| replication.upstream().connected = true);
+        info!("connected to the primary");
+
+        let mut input = Vec::new();
+        let mut acked = None;
+        let mut heard = Instant::now();
+        while !shutdown.load(Ordering::Relaxed) {
+            if !read_available(stream, &mut input)? {
+                return Err(error("the primary closed the connection"));
+            }
+            while let Some(message) = next_message(&mut input)? {
+                self.apply(&message)?;
+                heard = Instant::now();
+            }
+            if heard.elapsed() > LINK_TIMEOUT {
+                return Err(error("no word from the primary"));
+            }
+            let seq = self.db.last_seq();
+            if self.loading.is_none() && acked != Some(seq) {
+                send(&mut out, &[b"ack".to_vec(), seq.to_string().into_bytes()])?;
+                out.flush()?;
+                acked = Some(seq);
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, message: &[Vec<u8>]) -> Result<()> {
+        let text = |arg: &[u8]| String::from_utf8_lossy(arg).into_owned();
+        self.db
+            .replication(|replication, _| replication.upstream().last_contact = Some(Instant::now()));
+        match message {
+            [kind, seq] if kind == b"ping" => {
+                let seq = parse_seq(seq)?;
+                self.db.replication(|replication, _| replication.upstream().seq = seq);
+            }
+            [kind, seq, names @ ..] if kind == b"continue" => {
+                let seq = parse_seq(seq)?;
+                let names: Vec<String> = names.iter().map(|name| text(name)).collect();
+                for keyspace in self.db.keyspaces() {
+                    if keyspace.name() != DEFAULT_KEYSPACE && !names.iter().any(|name| name == keyspace.name()) {
+                        self.db.drop_keyspace(keyspace.name())?;
+                    }
+                }
+                for name in &names {
+                    self.keyspace(name)?;
+                }
+                self.db.replication(|replication, _| replication.upstream().seq = seq);
+            }
+            [kind, seq] if kind == b"full" => {
+                let seq = parse_seq(seq)?;
+                info!(seq, "loading a snapshot from the primary");
+                File::create(self.db.dir().join(SYNCING_FILE))?.sync_all()?;
+                sync_dir(&self.db.dir())?;
+                self.db.reset()?;
+                self.loading = Some(Load {
+                    seq,
+                    keyspace: None,
+                    batch: WriteBatch::new(),
+                });
+                self.db.replication(|replication, _| replication.upstream().seq = seq);
+            }
+            [kind, name] if kind == b"keyspace" => {
+                self.flush_load()?;
+                let keyspace = self.keyspace(&text(name))?;
+                self.loading_mut()?.keyspace = Some(keyspace);
+            }
+            [kind, _, key, value] if kind == b"put" => {
+                let load = self.loading_mut()?;
+                let keyspace = load.keyspace.as_ref().ok_or_else(|| error("a key arrived before its keyspace"))?;
+                load.batch.put(keyspace, key, value);
+                if load.batch.len() >= LOAD_BATCH {
+                    self.flush_load()?;
+                }
+            }
+            [kind] if kind == b"synced" => {
+                self.flush_load()?;
+                let load = self.loading.take().ok_or_else(|| error("'synced' without a snapshot"))?;
+                // The snapshot must be durable before the marker goes.
+                self.db.sync()?;
+                fs::remove_file(self.db.dir().join(SYNCING_FILE))?;
+                sync_dir(&self.db.dir())?;
+                info!(seq = load.seq, "snapshot loaded");
+            }
+            [kind, parts @ ..] if kind == b"commit" && !parts.is_empty() && parts.len() % 2 == 0 => {
+                let mut batch = WriteBatch::new();
+                let mut seq = None;
+                for part in parts.chunks(2) {
+                    let keyspace = self.keyspace(&text(&part[0]))?;
+                    let record = wal::decode_frame(&part[1]).ok_or_else(|| error("damaged commit"))?;
+                    if seq.is_some_and(|seq| seq != record.seq) {
+                        return Err(error("parts of one commit with different sequence numbers"));
+                    }
+                    seq = Some(record.seq);
+                    for op in record.ops {
+                        match op {
+                            Op::Put { key, value } => batch.put(&keyspace, &key, &value),
+                            Op::Delete { key } => batch.delete(&keyspace, &key),
+                        }
+                    }
+                }
+                let seq = seq.unwrap();
+                self.db.replicate(seq, batch)?;
+                self.db.replication(|replication, _| {
+                    let upstream = replication.upstream();
+                    upstream.seq = upstream.seq.max(seq);
+                });
+            }
+            [kind, name] if kind == b"create" => {
+                self.keyspace(&text(name))?;
+            }
+            [kind, name] if kind == b"drop" => match self.db.drop_keyspace(&text(name)) {
+                Ok(()) | Err(Error::NoSuchKeyspace(_)) => {}
+                Err(e) => return Err(e),
+            },
+            [kind, reason] if kind == b"error" => {
+                return Err(Error::Replication(format!("the primary gave up: {}", text(reason))));
+            }
+            _ => return Err(error("unexpected message from the primary")),
+        }
+        Ok(())
+    }
+
+    /// The keyspace called `name`, created if it does not exist yet.
+    fn keyspace(&self, name: &str) -> Result<Keyspace<'db>> {
+        match self.db.keyspace(name) {
+            Err(Error::NoSuchKeyspace(_)) => self.db.create_keyspace(name),
+            result => result,
+        }
+    }
+
+    fn loading_mut(&mut self) -> Result<&mut Load<'db>> {
+        self.loading.as_mut().ok_or_else(|| error("snapshot data without a snapshot"))
+    }
+
+    /// Writes the keys of the snapshot received so far.
+    fn flush_load(&mut self) -> Result<()> {
+        if let Some(load) = &mut self.loading {
+            if !load.batch.is_empty() {
+                let batch = std::mem::replace(&mut load.batch, WriteBatch::new());
+                self.db.replicate(load.seq, batch)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn send<W: Write>(out: &mut W, message: &[Vec<u8>]) -> Result<()> {
+    let mut buf = Vec::new();
+    let reply = Reply::Array(message.iter().map(|arg| Reply::Bulk(arg.clone())).collect());
+    server::encode_reply(&reply, &mut buf);
+    out.write_all(&buf)?;
+    Ok(())
+}
+
+/// Appends whatever `stream` has to offer within its read timeout to
+/// `input`, returning `false` once the peer has closed the connection.
+fn read_available<L>(stream: &L, input: &mut Vec<u8>) -> Result<bool>
+where
+    for<'a> &'a L: Read,
+{
+    let mut chunk = [0; 64 * 1024];
+    match (&mut &*stream).read(&mut chunk) {
+        Ok(0) => Ok(false),
+        Ok(n) => {
+            input.extend_from_slice(&chunk[..n]);
+            Ok(true)
+        }
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::Interrupted => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Takes the next complete message off the front of `input`.
+fn next_message(input: &mut Vec<u8>) -> Result<Option<Vec<Vec<u8>>>> {
+    match server::parse_request(input) {
+        Ok(Some((message, used))) => {
+            input.drain(..used);
+            Ok(Some(message))
+        }
+        Ok(None) => Ok(None),
+        Err(_) => Err(error("malformed message")),
+    }
+}
+
+fn parse_seq(arg: &[u8]) -> Result<u64> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|seq| seq.parse().ok())
+        .ok_or_else(|| error("invalid sequence number"))
+}
+
+fn error(reason: &str) -> Error {
+    Error::Replication(reason.to_owned())
+}
+
+fn sync_dir(dir: &std::path::Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{contents, options, pairs};
+    use crate::storage::Options;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:7000".parse().unwrap()
+    }
+
+    fn message(parts: &[&str]) -> Vec<Vec<u8>> {
+        parts.iter().map(|part| part.as_bytes().to_vec()).collect()
+    }
+
+    fn commit(seq: u64, key: &str) -> Event {
+        Event::Commit {
+            seq,
+            parts: vec![(
+                DEFAULT_KEYSPACE.to_owned(),
+                vec![Op::Put {
+                    key: key.as_bytes().to_vec(),
+                    value: vec![0; 100],
+                }],
+            )],
+        }
+    }
+
+    /// The next message on `link` other than a ping.
+    fn receive(link: &UnixStream, input: &mut Vec<u8>) -> Vec<Vec<u8>> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match next_message(input).unwrap() {
+                Some(message) if message[0] == b"ping" => {}
+                Some(message) => return message,
+                None => {
+                    assert!(Instant::now() < deadline, "nothing from the other end");
+                    assert!(read_available(link, input).unwrap(), "the other end closed the connection");
+                }
+            }
+        }
+    }
+
+    /// The sequence number and the keyspace and key of every operation of
+    /// a `commit` message.
+    fn decode_commit(message: &[Vec<u8>]) -> (u64, Vec<(String, Vec<u8>)>) {
+        assert_eq!(message[0], b"commit");
+        let mut seq = 0;
+        let mut keys = Vec::new();
+        for part in message[1..].chunks(2) {
+            let record = wal::decode_frame(&part[1]).unwrap();
+            seq = record.seq;
+            let name = String::from_utf8(part[0].clone()).unwrap();
+            keys.extend(record.ops.iter().map(|op| (name.clone(), op.key().to_vec())));
+        }
+        (seq, keys)
+    }
+
+    /// Serves the replica on `link` from `db` as the server would: reads its
+    /// `sync` request, then streams to it.
+    fn serve(db: &Db, link: &UnixStream, shutdown: &AtomicBool) -> Result<()> {
+        link.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+        let request = receive(link, &mut Vec::new());
+        assert_eq!(request[0], b"sync");
+        serve_link(link, addr(), db, &request[1..], shutdown)
+    }
+
+    /// Runs `client` against a primary serving `db` over a socket pair.
+    fn with_primary<F: FnOnce(&UnixStream)>(db: &Db, client: F) {
+        let (primary, replica) = UnixStream::pair().unwrap();
+        replica.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+        let shutdown = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let serving = scope.spawn(|| serve(db, &primary, &shutdown));
+            client(&replica);
+            shutdown.store(true, Ordering::Relaxed);
+            serving.join().unwrap().unwrap();
+        });
+    }
+
+    fn wait_until<F: FnMut() -> bool>(mut condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn backlog_holds_pinned_events() {
+        let mut replication = Replication::new(ReplicationOptions { backlog_size: 300 }, 0);
+        replication.enable(0);
+        let id = replication.add_peer(addr(), 0);
+        replication.pin(id, 0, 0);
+        for seq in 1..=10 {
+            replication.push(commit(seq, "k"));
+        }
+        // Everything the replica has yet to be sent is held.
+        assert_eq!(replication.read(0).unwrap().len(), 10);
+
+        replication.advance(id, 6);
+        replication.push(commit(11, "k"));
+        assert!(replication.read(5).is_none());
+        assert_eq!(replication.read(6).unwrap().len(), 5);
+        // Acks before the snapshot's do not release the pin.
+        replication.pin(id, 6, 4);
+        replication.ack(id, 3);
+        replication.push(commit(12, "k"));
+        assert_eq!(replication.read(6).unwrap().len(), 6);
+
+        replication.ack(id, 4);
+        replication.push(commit(13, "k"));
+        assert!(replication.read(6).is_none());
+        assert_eq!(replication.end() - replication.start, 2);
+        assert_eq!(replication.resume(10, 13), None);
+        assert_eq!(replication.resume(12, 13), Some(12));
+    }
+
+    #[test]
+    fn backlog_is_kept_once_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        ks.put(b"a", b"1").unwrap();
+        db.create_keyspace("other").unwrap();
+        assert_eq!(db.replication(|replication, _| (replication.end(), replication.bytes)), (0, 0));
+
+        // Nothing before the backlog was enabled can be resumed from.
+        db.replication(|replication, seq| replication.enable(seq));
+        ks.put(b"b", b"2").unwrap();
+        assert_eq!(db.replication(|replication, _| replication.read(0).unwrap().len()), 1);
+        assert_eq!(db.replication(|replication, seq| replication.resume(0, seq)), None);
+        assert_eq!(db.replication(|replication, seq| replication.resume(1, seq)), Some(0));
+    }
+
+    #[test]
+    fn sync_continues_from_the_backlog() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        db.replication(|replication, seq| replication.enable(seq));
+        let ks = db.default_keyspace();
+        ks.put(b"a", b"1").unwrap();
+        let other = db.create_keyspace("other").unwrap();
+        other.put(b"b", b"2").unwrap();
+        ks.put(b"c", b"3").unwrap();
+
+        with_primary(&db, |replica| {
+            let mut input = Vec::new();
+            send(&mut &*replica, &message(&["sync", "1"])).unwrap();
+            assert_eq!(receive(replica, &mut input), message(&["continue", "3", "default", "other"]));
+            // The replica has commit 1; the keyspace created after it is
+            // sent again, then the commits it is missing.
+            assert_eq!(receive(replica, &mut input), message(&["create", "other"]));
+            assert_eq!(decode_commit(&receive(replica, &mut input)), (2, vec![("other".to_owned(), b"b".to_vec())]));
+            assert_eq!(decode_commit(&receive(replica, &mut input)), (3, vec![("default".to_owned(), b"c".to_vec())]));
+
+            // Later commits are streamed as they happen.
+            ks.put(b"d", b"4").unwrap();
+            assert_eq!(decode_commit(&receive(replica, &mut input)).0, 4);
+
+            send(&mut &*replica, &message(&["ack", "4"])).unwrap();
+            wait_until(|| db.replication(|replication, seq| replication.info(seq)).contains("acked_seq=4,lag=0"));
+        });
+        assert!(db.replication(|replication, _| replication.replicas.is_empty()));
+    }
+
+    #[test]
+    fn sync_sends_a_snapshot_when_the_backlog_falls_short() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Db::open(dir.path(), options()).unwrap();
+            db.default_keyspace().put(b"a", b"1").unwrap();
+            db.create_keyspace("other").unwrap().put(b"b", b"2").unwrap();
+            db.default_keyspace().put(b"c", b"3").unwrap();
+        }
+        // Reopened, the backlog is empty.
+        let db = Db::open(dir.path(), options()).unwrap();
+        for request in &[&["sync", "1"][..], &["sync"]] {
+            with_primary(&db, |replica| {
+                let mut input = Vec::new();
+                send(&mut &*replica, &message(request)).unwrap();
+                let expected = [
+                    &["full", "3"][..],
+                    &["keyspace", "default"],
+                    &["put", "default", "a", "1"],
+                    &["put", "default", "c", "3"],
+                    &["keyspace", "other"],
+                    &["put", "other", "b", "2"],
+                    &["synced"],
+                ];
+                for expected in &expected {
+                    assert_eq!(receive(replica, &mut input), message(expected));
+                }
+                send(&mut &*replica, &message(&["ack", "3"])).unwrap();
+                wait_until(|| {
+                    db.replication(|replication, _| replication.replicas.values().all(|peer| peer.acked == 3 && peer.pin.is_none()))
+                });
+            });
+        }
+        // A replica ahead of the primary cannot continue either.
+        with_primary(&db, |replica| {
+            send(&mut &*replica, &message(&["sync", "9"])).unwrap();
+            assert_eq!(receive(replica, &mut Vec::new()), message(&["full", "3"]));
+        });
+    }
+
+    #[test]
+    fn full_sync_keeps_up_with_a_busy_primary() {
+        let dir = tempfile::tempdir().unwrap();
+        // A backlog with room for about one commit.
+        let options = Options {
+            replication: ReplicationOptions { backlog_size: 64 },
+            ..options()
+        };
+        let db = Db::open(dir.path(), options).unwrap();
+        let ks = db.default_keyspace();
+        ks.put(b"before", b"0").unwrap();
+
+        with_primary(&db, |replica| {
+            let mut input = Vec::new();
+            send(&mut &*replica, &message(&["sync"])).unwrap();
+            assert_eq!(receive(replica, &mut input), message(&["full", "1"]));
+            // Commits made while the snapshot is still being sent.
+            for i in 0..100 {
+                ks.put(format!("key{}", i).as_bytes(), b"value").unwrap();
+            }
+            assert_eq!(receive(replica, &mut input), message(&["keyspace", "default"]));
+            assert_eq!(receive(replica, &mut input), message(&["put", "default", "before", "0"]));
+            assert_eq!(receive(replica, &mut input), message(&["synced"]));
+            for seq in 2..102 {
+                assert_eq!(decode_commit(&receive(replica, &mut input)).0, seq);
+            }
+
+            // Once the snapshot is acked, the backlog is bounded again.
+            send(&mut &*replica, &message(&["ack", "101"])).unwrap();
+            wait_until(|| db.replication(|replication, _| replication.replicas.values().all(|peer| peer.pin.is_none())));
+            ks.put(b"after", b"1").unwrap();
+            assert_eq!(decode_commit(&receive(replica, &mut input)).0, 102);
+            assert!(db.replication(|replication, _| replication.bytes) <= 64);
+        });
+    }
+
+    #[test]
+    fn follower_loads_and_streams() {
+        let primary_dir = tempfile::tempdir().unwrap();
+        let replica_dir = tempfile::tempdir().unwrap();
+        let primary = Db::open(primary_dir.path(), options()).unwrap();
+        let replica = Db::open(replica_dir.path(), options()).unwrap();
+        replica.default_keyspace().put(b"stale", b"0").unwrap();
+        primary.replication(|replication, _| replication.backlog_size = 0);
+        primary.default_keyspace().put(b"a", b"1").unwrap();
+        primary.create_keyspace("other").unwrap().put(b"b", b"2").unwrap();
+        primary.replication(|replication, _| replication.backlog_size = 1 << 20);
+        replica.replication(|replication, _| {
+            replication.upstream = Some(Upstream {
+                addr: addr(),
+                connected: false,
+                seq: 0,
+                last_contact: None,
+            })
+        });
+
+        let (primary_end, replica_end) = UnixStream::pair().unwrap();
+        let shutdown = AtomicBool::new(false);
+        thread::scope(|scope| {
+            let serving = scope.spawn(|| serve(&primary, &primary_end, &shutdown));
+            let following = scope.spawn(|| Follower::new(&replica).run_link(&replica_end, &shutdown));
+
+            // The replica's commit 1 is not the primary's, and the backlog no
+            // longer holds it, so the replica loads a snapshot.
+            wait_until(|| replica.keyspace("other").is_ok_and(|other| contents(&other) == pairs(&[("b", "2")])));
+            assert_eq!(contents(&replica.default_keyspace()), pairs(&[("a", "1")]));
+            assert!(!replica_dir.path().join(SYNCING_FILE).exists());
+
+            primary.default_keyspace().put(b"c", b"3").unwrap();
+            primary.drop_keyspace("other").unwrap();
+            wait_until(|| replica.keyspace("other").is_err());
+            assert_eq!(contents(&replica.default_keyspace()), pairs(&[("a", "1"), ("c", "3")]));
+            assert_eq!(replica.last_seq(), primary.last_seq());
+            wait_until(|| primary.replication(|replication, seq| replication.info(seq)).contains("state=streaming,acked_seq=3,lag=0"));
+
+            shutdown.store(true, Ordering::Relaxed);
+            serving.join().unwrap().unwrap();
+            following.join().unwrap().unwrap();
+        });
+    }
+}
@@ -0,0 +1,452 @@
+//! Network server (`rmdb serve`).
+//!
+//! Clients speak RESP, the Redis serialization protocol, so `redis-cli` and
+//! existing Redis client libraries can be used directly. Requests are either
+//! arrays of bulk strings or inline commands (a single line of
+//! space-separated words, handy with `nc`), and are executed through
+//! [`Command`]. Each connection is served by its own thread.
+//!
+//! Each connection is logged within a `connection` span carrying the peer
+//! address, and each command within a `request` span carrying its name.
+//!
+//! A connection that sends `sync` is a replica, and is handed over to
+//! [`crate::replication`] for good. A server started as a replica follows
+//! its primary from a thread of its own and refuses writes.
+//!
+//! SIGTERM and SIGINT stop the server gracefully: no new connections are
+//! accepted, each connection finishes the command it is running, and the
+//! database is closed once every connection thread has exited.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use tracing::{debug, debug_span, error, info, info_span, warn};
+
+use crate::command::{Command, Reply, Session};
+use crate::error::{Error, Result};
+use crate::replication;
+use crate::storage::Db;
+
+/// How often blocked accepts and reads wake up to check for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// Serves `db` on `addr` until the process receives SIGTERM or SIGINT. With
+/// `replica_of`, it is a read-only replica of the primary there.
+pub fn serve(db: Arc<Db>, addr: SocketAddr, replica_of: Option<SocketAddr>) -> Result<()> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGTERM, shutdown.clone())?;
+    signal_hook::flag::register(SIGINT, shutdown.clone())?;
+
+    let listener = TcpListener::bind(addr)?;
+    info!(addr = %listener.local_addr()?, "listening");
+    let follower = match replica_of {
+        Some(primary) => Some(replication::follow(db.clone(), primary, shutdown.clone())),
+        // Replicas that connect later resume from what the backlog holds.
+        None => {
+            db.replication(|replication, seq| replication.enable(seq));
+            None
+        }
+    };
+    run(listener, db, shutdown)?;
+    if let Some(follower) = follower {
+        let _ = follower.join();
+    }
+    Ok(())
+}
+
+/// Accepts connections on `listener` until `shutdown` is raised, then waits
+/// for the open ones to finish.
+fn run(listener: TcpListener, db: Arc<Db>, shutdown: Arc<AtomicBool>) -> Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut workers: Vec<JoinHandle<()>> = Vec::new();
+    while !shutdown.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                workers.retain(|worker| !worker.is_finished());
+                let db = db.clone();
+                let shutdown = shutdown.clone();
+                workers.push(thread::spawn(move || {
+                    let _span = info_span!("connection", peer = %peer).entered();
+                    debug!("connected");
+                    match Connection::new(stream).and_then(|mut c| c.serve(&db, &shutdown)) {
+                        Ok(()) => debug!("disconnected"),
+                        Err(e) => warn!(error = %e, "connection failed"),
+                    }
+                }));
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    info!(connections = workers.len(), "shutting down");
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// Bytes received but not yet parsed.
+    buf: Vec<u8>,
+}
+
+/// A request's arguments and its length in bytes, if one is complete.
+pub(crate) type Parsed = Option<(Vec<Vec<u8>>, usize)>;
+
+/// Why a request could not be parsed. Either way the connection is closed,
+/// since there is no reliable place to resume reading from.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ProtocolError {
+    Invalid(&'static str),
+    TooLarge,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Connection> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            stream,
+            buf: Vec::new(),
+        })
+    }
+
+    fn serve(&mut self, db: &Db, shutdown: &AtomicBool) -> io::Result<()> {
+        let mut session = Session::new(db.default_keyspace());
+        let mut chunk = [0; 16 * 1024];
+        loop {
+            // Run every complete request already buffered before reading.
+            loop {
+                let args = match parse_request(&self.buf) {
+                    Ok(Some((args, used))) => {
+                        self.buf.drain(..used);
+                        args
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        let message = match e {
+                            ProtocolError::Invalid(reason) => format!("Protocol error: {}", reason),
+                            ProtocolError::TooLarge => "Protocol error: request too large".to_owned(),
+                        };
+                        warn!("{}, closing the connection", message);
+                        self.send_error(&message)?;
+                        return Ok(());
+                    }
+                };
+                if args.is_empty() {
+                    continue;
+                }
+                if args[0].eq_ignore_ascii_case(b"quit") {
+                    return self.send(&Reply::Status("OK".to_owned()));
+                }
+                if args[0].eq_ignore_ascii_case(b"sync") {
+                    let _span = info_span!("replica").entered();
+                    info!("replica connected");
+                    return match replication::serve_replica(&self.stream, db, &args[1..], shutdown) {
+                        Ok(()) => {
+                            info!("replica disconnected");
+                            Ok(())
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "replication stopped");
+                            Ok(())
+                        }
+                    };
+                }
+                self.execute(&mut session, &args)?;
+            }
+
+            if shutdown.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn execute(&mut self, session: &mut Session, args: &[Vec<u8>]) -> io::Result<()> {
+        let command = match Command::parse(args) {
+            Ok(command) => command,
+            Err(e) => {
+                debug!(error = %e, "rejected request");
+                return self.send_error(&e);
+            }
+        };
+        let _span = debug_span!("request", command = command.name()).entered();
+        let start = Instant::now();
+        let result = match session.execute(&command) {
+            Ok(reply) => self.send(&reply),
+            Err(e) => {
+                // Only failures of the server itself are errors; the rest are
+                // the client's to deal with.
+                match e {
+                    Error::Io(_) | Error::Corrupted { .. } | Error::CorruptedBlock { .. } => {
+                        error!(error = %e, "command failed")
+                    }
+                    _ => debug!(error = %e, "command refused"),
+                }
+                self.send_error(&e.to_string())
+            }
+        };
+        debug!(elapsed_us = start.elapsed().as_micros() as u64, "handled");
+        result
+    }
+
+    fn send(&mut self, reply: &Reply) -> io::Result<()> {
+        let mut out = Vec::new();
+        encode_reply(reply, &mut out);
+        self.stream.write_all(&out)
+    }
+
+    fn send_error(&mut self, message: &str) -> io::Result<()> {
+        self.send(&Reply::Error(message.to_owned()))
+    }
+}
+
+/// Parses one request from the front of `buf`, returning its arguments and
+/// the number of bytes it occupied, or `None` if it is not complete yet.
+pub(crate) fn parse_request(buf: &[u8]) -> std::result::Result<Parsed, ProtocolError> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != b'*' {
+        return parse_inline(buf);
+    }
+
+    let (count, mut pos) = match parse_line_int(buf, 1)? {
+        Some(parsed) => parsed,
+        None => return Ok(None),
+    };
+    if count <= 0 {
+        return Ok(Some((Vec::new(), pos)));
+    }
+    if count as usize > MAX_ARRAY_LEN {
+        return Err(ProtocolError::TooLarge);
+    }
+
+    // The count alone is no promise that the arguments will follow, so only
+    // a little is reserved up front.
+    let mut args = Vec::with_capacity((count as usize).min(1024));
+    for _ in 0..count {
+        if pos >= buf.len() {
+            return Ok(None);
+        }
+        if buf[pos] != b'$' {
+            return Err(ProtocolError::Invalid("expected '$'"));
+        }
+        let (len, start) = match parse_line_int(buf, pos + 1)? {
+            Some(parsed) => parsed,
+            None => return Ok(None),
+        };
+        if len < 0 {
+            return Err(ProtocolError::Invalid("invalid bulk length"));
+        }
+        let len = len as usize;
+        if len > MAX_BULK_LEN {
+            return Err(ProtocolError::TooLarge);
+        }
+        if buf.len() < start + len + 2 {
+            return Ok(None);
+        }
+        if &buf[start + len..start + len + 2] != b"\r\n" {
+            return Err(ProtocolError::Invalid("bulk string not terminated by CRLF"));
+        }
+        args.push(buf[start..start + len].to_vec());
+        pos = start + len + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+fn parse_inline(buf: &[u8]) -> std::result::Result<Parsed, ProtocolError> {
+    let end = match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => end,
+        None if buf.len() > MAX_INLINE_LEN => return Err(ProtocolError::TooLarge),
+        None => return Ok(None),
+    };
+    let args = buf[..end]
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_vec())
+        .collect();
+    Ok(Some((args, end + 1)))
+}
+
+/// Parses a CRLF-terminated decimal integer starting at `start`, returning it
+/// and the position just past the CRLF.
+fn parse_line_int(buf: &[u8], start: usize) -> std::result::Result<Option<(i64, usize)>, ProtocolError> {
+    let end = match buf[start..].windows(2).position(|w| w == b"\r\n") {
+        Some(i) => start + i,
+        None if buf.len() - start > 32 => return Err(ProtocolError::Invalid("length line too long")),
+        None => return Ok(None),
+    };
+    let n = std::str::from_utf8(&buf[start..end])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(ProtocolError::Invalid("invalid length"))?;
+    Ok(Some((n, end + 2)))
+}
+
+pub(crate) fn encode_reply(reply: &Reply, out: &mut Vec<u8>) {
+    match reply {
+        Reply::Status(s) => {
+            out.push(b'+');
+            out.extend_from_slice(s.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        Reply::Error(e) => {
+            out.extend_from_slice(b"-ERR ");
+            out.extend_from_slice(e.replace(['\r', '\n'], " ").as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        Reply::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+        Reply::Bulk(bytes) => {
+            out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+            out.extend_from_slice(bytes);
+            out.extend_from_slice(b"\r\n");
+        }
+        Reply::Nil => out.extend_from_slice(b"$-1\r\n"),
+        Reply::Array(items) => {
+            out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+            for item in items {
+                encode_reply(item, out);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Options;
+    use crate::wal::FsyncPolicy;
+
+    fn args(parts: &[&str]) -> Vec<Vec<u8>> {
+        parts.iter().map(|part| part.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn partial_frames_wait_for_more() {
+        let request = b"*3\r\n$3\r\nset\r\n$1\r\nk\r\n$5\r\nvalue\r\n";
+        for len in 0..request.len() {
+            assert_eq!(parse_request(&request[..len]), Ok(None), "prefix of {} bytes", len);
+        }
+        assert_eq!(parse_request(request), Ok(Some((args(&["set", "k", "value"]), request.len()))));
+
+        let inline = b"get  some-key\r\n";
+        for len in 0..inline.len() {
+            assert_eq!(parse_request(&inline[..len]), Ok(None));
+        }
+        assert_eq!(parse_request(inline), Ok(Some((args(&["get", "some-key"]), inline.len()))));
+    }
+
+    #[test]
+    fn pipelined_requests_parse_one_at_a_time() {
+        let mut buf = b"*1\r\n$4\r\nping\r\n*2\r\n$3\r\nget\r\n$0\r\n\r\nping\n".to_vec();
+        let mut requests = Vec::new();
+        while let Some((request, used)) = parse_request(&buf).unwrap() {
+            requests.push(request);
+            buf.drain(..used);
+        }
+        assert_eq!(requests, vec![args(&["ping"]), args(&["get", ""]), args(&["ping"])]);
+        assert!(buf.is_empty());
+
+        // An empty array is skipped over.
+        assert_eq!(parse_request(b"*0\r\n"), Ok(Some((Vec::new(), 4))));
+    }
+
+    #[test]
+    fn oversize_lengths_are_refused_up_front() {
+        // Refused from the length alone, before any of the data arrives.
+        let bulk = format!("*1\r\n${}\r\n", MAX_BULK_LEN + 1);
+        assert_eq!(parse_request(bulk.as_bytes()), Err(ProtocolError::TooLarge));
+        let at_limit = format!("*1\r\n${}\r\n", MAX_BULK_LEN);
+        assert_eq!(parse_request(at_limit.as_bytes()), Ok(None));
+
+        let array = format!("*{}\r\n", MAX_ARRAY_LEN + 1);
+        assert_eq!(parse_request(array.as_bytes()), Err(ProtocolError::TooLarge));
+        let at_limit = format!("*{}\r\n", MAX_ARRAY_LEN);
+        assert_eq!(parse_request(at_limit.as_bytes()), Ok(None));
+
+        let inline = vec![b'a'; MAX_INLINE_LEN + 1];
+        assert_eq!(parse_request(&inline), Err(ProtocolError::TooLarge));
+        assert_eq!(parse_request(&inline[..MAX_INLINE_LEN]), Ok(None));
+
+        let endless = format!("*1\r\n${}", "9".repeat(40));
+        assert_eq!(parse_request(endless.as_bytes()), Err(ProtocolError::Invalid("length line too long")));
+    }
+
+    #[test]
+    fn malformed_frames_are_invalid() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"*1\r\n+ok\r\n", "expected '$'"),
+            (b"*1\r\n$-1\r\n", "invalid bulk length"),
+            (b"*1\r\n$x\r\n", "invalid length"),
+            (b"*two\r\n", "invalid length"),
+            (b"*1\r\n$2\r\nabc\r\n", "bulk string not terminated by CRLF"),
+        ];
+        for (request, reason) in cases {
+            assert_eq!(parse_request(request), Err(ProtocolError::Invalid(reason)));
+        }
+    }
+
+    #[test]
+    fn replies_round_trip_through_the_parser() {
+        let mut out = Vec::new();
+        encode_reply(&Reply::Array(vec![Reply::Bulk(b"a\r\nb".to_vec()), Reply::Bulk(Vec::new())]), &mut out);
+        assert_eq!(out, b"*2\r\n$4\r\na\r\nb\r\n$0\r\n\r\n");
+        assert_eq!(parse_request(&out), Ok(Some((vec![b"a\r\nb".to_vec(), Vec::new()], out.len()))));
+    }
+
+    #[test]
+    fn serves_pipelined_requests_until_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            fsync: FsyncPolicy::Never,
+            ..Options::default()
+        };
+        let db = Arc::new(Db::open(dir.path(), options.clone()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || run(listener, db, shutdown))
+        };
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nvalue\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\nGET missing\r\n")
+            .unwrap();
+        let expected: &[u8] = b"+OK\r\n$5\r\nvalue\r\n$-1\r\n";
+        let mut replies = vec![0; expected.len()];
+        client.read_exact(&mut replies).unwrap();
+        assert_eq!(replies, expected);
+
+        // The open connection is closed, and the server returns once its
+        // thread has exited, releasing the database.
+        shutdown.store(true, Ordering::Relaxed);
+        server.join().unwrap().unwrap();
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+        let db = Db::open(dir.path(), options).unwrap();
+        assert_eq!(db.default_keyspace().get(b"k").unwrap(), Some(b"value".to_vec()));
+    }
+}
@@ -0,0 +1,271 @@
+//! Interactive shell (`rmdb shell`).
+
+use std::env;
+use std::path::PathBuf;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use tracing::warn;
+
+use rmdb::command::{Command, Reply, Session};
+use rmdb::keyspace::DEFAULT_KEYSPACE;
+use rmdb::{Error, Keyspace, Result};
+
+const HISTORY_FILE: &str = ".rmdb_history";
+
+const HELP: &str = "\
+get <key>           print the value stored under key
+put <key> <value>   store value under key (alias: set)
+del <key>           delete key (alias: delete)
+scan [start [end]]  print key-value pairs from start up to, not including, end
+prefix <prefix>     print key-value pairs whose keys start with prefix
+use <keyspace>      run later commands against another keyspace
+info                print database statistics
+replication         print the replication role and lag
+ping                check that the database responds
+begin               start a transaction; later commands see a snapshot
+commit              apply the transaction's writes atomically
+rollback            discard the transaction's writes
+help                show this message
+exit                leave the shell (alias: quit)
+
+Arguments are separated by spaces; wrap them in double quotes to include
+spaces, and use \\\" and \\\\ inside quotes for literal quotes and backslashes.";
+
+/// Runs the shell, starting in `keyspace`, until end of input or `exit`.
+pub fn run(keyspace: Keyspace) -> Result<()> {
+    let mut editor = DefaultEditor::new().map_err(readline_error)?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // A missing history file is expected on first use.
+        let _ = editor.load_history(path);
+    }
+
+    let mut session = Session::new(keyspace);
+    loop {
+        let line = match editor.readline(&prompt(&session)) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+
+        let args = match split(&line) {
+            Ok(args) => args,
+            Err(e) => {
+                println!("(error) {}", e);
+                continue;
+            }
+        };
+        match args[0].to_ascii_lowercase().as_slice() {
+            b"exit" | b"quit" => break,
+            b"help" => {
+                println!("{}", HELP);
+                continue;
+            }
+            _ => {}
+        }
+
+        match Command::parse(&args) {
+            Ok(command) => match session.execute(&command) {
+                Ok(reply) => print!("{}", format_reply(&reply, command == Command::Info, 0)),
+                Err(e) => println!("(error) {}", e),
+            },
+            Err(e) => println!("(error) {}", e),
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            warn!(path = %path.display(), error = %e, "cannot save shell history");
+        }
+    }
+    Ok(())
+}
+
+/// `rmdb> `, with the keyspace unless it is the default one, and marked
+/// while a transaction is open.
+fn prompt(session: &Session) -> String {
+    let mut prompt = "rmdb".to_owned();
+    let name = session.keyspace().name();
+    if name != DEFAULT_KEYSPACE {
+        prompt.push(':');
+        prompt.push_str(name);
+    }
+    if session.in_transaction() {
+        prompt.push_str("(txn)");
+    }
+    prompt.push_str("> ");
+    prompt
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+fn readline_error(e: ReadlineError) -> Error {
+    match e {
+        ReadlineError::Io(e) => Error::Io(e),
+        e => Error::Io(std::io::Error::other(e)),
+    }
+}
+
+/// Splits a line into arguments, honouring double quotes.
+fn split(line: &str) -> std::result::Result<Vec<Vec<u8>>, &'static str> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let mut arg = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c @ '"') | Some(c @ '\\') => arg.push(c),
+                        Some(c) => {
+                            arg.push('\\');
+                            arg.push(c);
+                        }
+                        None => return Err("unbalanced quotes"),
+                    },
+                    Some(c) => arg.push(c),
+                    None => return Err("unbalanced quotes"),
+                }
+            }
+            if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                return Err("closing quote must be followed by a space");
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                arg.push(c);
+                chars.next();
+            }
+        }
+        args.push(arg.into_bytes());
+    }
+
+    if args.is_empty() {
+        return Err("empty command");
+    }
+    Ok(args)
+}
+
+/// Formats a reply the way `redis-cli` does. `raw` prints bulk strings
+/// verbatim instead of quoted, for multi-line text such as `info`.
+fn format_reply(reply: &Reply, raw: bool, indent: usize) -> String {
+    match reply {
+        Reply::Status(s) => format!("{}\n", s),
+        Reply::Error(e) => format!("(error) {}\n", e),
+        Reply::Integer(i) => format!("(integer) {}\n", i),
+        Reply::Bulk(bytes) if raw => format!("{}\n", String::from_utf8_lossy(bytes)),
+        Reply::Bulk(bytes) => format!("{}\n", quote(bytes)),
+        Reply::Nil => "(nil)\n".to_owned(),
+        Reply::Array(items) if items.is_empty() => "(empty array)\n".to_owned(),
+        Reply::Array(items) => {
+            let width = items.len().to_string().len();
+            let mut out = String::new();
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(&" ".repeat(indent));
+                }
+                let label = format!("{:>width$}) ", i + 1, width = width);
+                out.push_str(&label);
+                out.push_str(&format_reply(item, raw, indent + label.len()));
+            }
+            out
+        }
+    }
+}
+
+/// Quotes `bytes`, escaping anything that is not printable ASCII.
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() + 2);
+    out.push('"');
+    for &b in bytes {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(parts: &[&str]) -> Vec<Vec<u8>> {
+        parts.iter().map(|part| part.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn split_honours_quotes_and_escapes() {
+        assert_eq!(split("  put   key value ").unwrap(), args(&["put", "key", "value"]));
+        assert_eq!(split("put \"two words\" \"\"").unwrap(), args(&["put", "two words", ""]));
+        assert_eq!(split(r#"put k "say \"hi\" \\ \n""#).unwrap(), args(&["put", "k", r#"say "hi" \ \n"#]));
+        // Quotes only group at the start of an argument.
+        assert_eq!(split("put a\"b c").unwrap(), args(&["put", "a\"b", "c"]));
+        assert_eq!(split("get\tkey").unwrap(), args(&["get", "key"]));
+    }
+
+    #[test]
+    fn split_rejects_malformed_lines() {
+        assert_eq!(split(""), Err("empty command"));
+        assert_eq!(split("   "), Err("empty command"));
+        assert_eq!(split("get \"key"), Err("unbalanced quotes"));
+        assert_eq!(split("get \"key\\"), Err("unbalanced quotes"));
+        assert_eq!(split("get \"key\"x"), Err("closing quote must be followed by a space"));
+    }
+
+    #[test]
+    fn quote_escapes_unprintable_bytes() {
+        assert_eq!(quote(b"plain text"), "\"plain text\"");
+        assert_eq!(quote(b"a\"b\\c"), r#""a\"b\\c""#);
+        assert_eq!(quote(b"\n\r\t"), r#""\n\r\t""#);
+        assert_eq!(quote(&[0, 0x7f, 0xff]), r#""\x00\x7f\xff""#);
+    }
+
+    #[test]
+    fn replies_format_like_redis_cli() {
+        assert_eq!(format_reply(&Reply::Status("OK".to_owned()), false, 0), "OK\n");
+        assert_eq!(format_reply(&Reply::Integer(-1), false, 0), "(integer) -1\n");
+        assert_eq!(format_reply(&Reply::Nil, false, 0), "(nil)\n");
+        assert_eq!(format_reply(&Reply::Bulk(b"a\nb".to_vec()), false, 0), "\"a\\nb\"\n");
+        assert_eq!(format_reply(&Reply::Bulk(b"a\nb".to_vec()), true, 0), "a\nb\n");
+        assert_eq!(format_reply(&Reply::Array(Vec::new()), false, 0), "(empty array)\n");
+
+        let pair = |key: &str, value: &str| Reply::Array(vec![Reply::Bulk(key.into()), Reply::Bulk(value.into())]);
+        assert_eq!(
+            format_reply(&Reply::Array(vec![pair("k1", "v"), pair("k2", "v")]), false, 0),
+            "1) 1) \"k1\"\n   2) \"v\"\n2) 1) \"k2\"\n   2) \"v\"\n"
+        );
+
+        // Labels are right-aligned to the widest index.
+        let items: Vec<_> = (0..10).map(Reply::Integer).collect();
+        let text = format_reply(&Reply::Array(items), false, 0);
+        assert!(text.starts_with(" 1) (integer) 0\n 2) (integer) 1\n"));
+        assert!(text.ends_with("10) (integer) 9\n"));
+    }
+}
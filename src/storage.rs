@@ -0,0 +1,2460 @@
+//! Key-value storage engine.
+//!
+//! Every mutation is appended to the write-ahead log inside the database
+//! directory (see [`crate::wal`]), and an in-memory index maps each key to
+//! the locations of its values in the log. Opening a database replays the
+//! log to rebuild the index.
+//!
+//! While it is open, the database holds an exclusive lock on the `LOCK`
+//! file in its directory, so no other handle can write to it at the same
+//! time.
+//!
+//! Every log record carries a checksum, checked as the log is replayed, and
+//! the index keeps a CRC32C of each value it points to. Every read of a
+//! value checks it against that, so damage to a data file after it was
+//! opened surfaces as [`Error::CorruptedBlock`] rather than as wrong data.
+//!
+//! Keys live in keyspaces (see [`crate::keyspace`]), and each keyspace has a
+//! log and an index of its own, so it grows, compacts and is dropped
+//! independently of the others. A commit that writes to several keyspaces
+//! is first logged whole to `batch.log` in the database directory, and only
+//! then split into one record per keyspace; if a crash interrupts that, the
+//! next open writes the missing parts from the batch log, so the commit is
+//! applied to every keyspace or to none.
+//!
+//! A keyspace's log is split into numbered segment files. Commits are
+//! appended to the active segment, the one with the highest id, which is
+//! sealed and replaced by a fresh one once it grows past the configured
+//! segment size. Compaction (see [`crate::compaction`]) seals the active
+//! segment and rewrites the versions still in the index into a single
+//! compacted segment numbered just above it. A compacted segment supersedes
+//! every segment below it, so if a crash leaves any of those behind, the
+//! next open deletes them rather than replaying them.
+//!
+//! With `read.mode = "mmap"`, compaction writes a sorted table instead (see
+//! [`crate::table`]): the live values go into blocks indexed sparsely, and
+//! only the keys written since stay in the index. Reads and scans look in
+//! the index first and fall back to the table. Because a key missing from
+//! the index may still be in the table, a keyspace with a table keeps
+//! deletes in the index until the next compaction drops the keys they hide.
+//! A keyspace that has a table keeps being compacted into one even in
+//! buffered mode; only the mapping of sealed segments is turned off.
+//!
+//! The index is ordered by key, so range and prefix scans walk it directly.
+//! Scans return an [`Iter`] that reads a bounded batch of entries at a time
+//! from a snapshot, rather than collecting the whole result up front.
+//!
+//! Each commit is stamped with the next sequence number, shared by every
+//! keyspace, and the index keeps a short chain of versions per key so that
+//! readers holding a snapshot (see [`crate::txn`]) keep seeing the values as
+//! of their sequence number. Versions are dropped as soon as no snapshot can
+//! observe them, so without open transactions every key has exactly one
+//! version.
+
+use std::collections::btree_map::Entry;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::ops::{Bound, RangeBounds};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::{debug, debug_span, error, info, info_span, warn};
+
+use crate::backup::{self, Manifest};
+use crate::batch::WriteBatch;
+use crate::compaction::{CompactionOptions, CompactionStats, Compactor, Throttle};
+use crate::error::{Error, Result};
+use crate::keyspace::{self, Catalog, KeyspaceId, CATALOG_FILE, DEFAULT_ID, DEFAULT_KEYSPACE};
+use crate::replication::{Event, Replication, ReplicationOptions};
+use crate::table::{BlockCache, CacheStats, DataFile, Entries, ReadMode, ReadOptions, Table, TableWriter, BLOCK_SEQ};
+use crate::txn::Txn;
+use crate::wal::{self, FsyncPolicy, Op, Record, Wal};
+
+/// Extension of segments written by commits.
+const LOG_EXT: &str = "log";
+/// Extension of segments written by compaction.
+const COMPACT_EXT: &str = "compact";
+/// The single log file of databases created before the log was segmented.
+/// It is adopted as segment 1.
+const LEGACY_LOG_FILE: &str = "wal.log";
+/// Locked by the process that has the database open, so that two never
+/// write to it at once.
+const LOCK_FILE: &str = "LOCK";
+/// Log of the commits that span keyspaces. Its keys are prefixed with the
+/// big-endian id of their keyspace.
+const BATCH_LOG_FILE: &str = "batch.log";
+
+/// Entries an [`Iter`] reads per acquisition of the index lock.
+const SCAN_BATCH: usize = 128;
+
+/// How a [`Db`] is opened. The defaults match those of `rmdb.toml`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// When commits are forced to stable storage.
+    pub fsync: FsyncPolicy,
+    /// Size in bytes at which the active segment is sealed.
+    pub segment_size: u64,
+    pub compaction: CompactionOptions,
+    pub replication: ReplicationOptions,
+    /// How values are read, and what compaction writes for them.
+    pub read: ReadOptions,
+    /// Bytes the block cache and the replication backlog may hold together;
+    /// 0 means no limit. The block cache is cut down to fit.
+    pub max_memory: u64,
+}
+
+/// A point-in-time summary of a keyspace, for `info`.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    /// Number of live keys.
+    pub keys: usize,
+    /// Sequence number of the last commit to any keyspace.
+    pub last_seq: u64,
+    /// Size of the keyspace's log, over every segment.
+    pub log_bytes: u64,
+    pub segments: usize,
+    /// Bytes of the log holding versions that are no longer reachable.
+    pub garbage_bytes: u64,
+    /// Snapshots open on the whole database.
+    pub open_snapshots: usize,
+    /// The block cache, shared by every keyspace; all zero without one.
+    pub block_cache: CacheStats,
+}
+
+/// A database: a directory of keyspaces, opened with [`Db::open`]. It can
+/// be shared between threads; every operation takes `&self`.
+pub struct Db {
+    // Only held to be dropped, and declared first so the thread is stopped
+    // before the database closes.
+    _compactor: Option<Compactor>,
+    shared: Arc<Shared>,
+}
+
+/// A handle to one keyspace of a [`Db`].
+#[derive(Clone)]
+pub struct Keyspace<'db> {
+    db: &'db Db,
+    id: KeyspaceId,
+    name: String,
+}
+
+/// State reachable from the background compactor as well as the handle.
+struct Shared {
+    inner: Mutex<Inner>,
+    /// Held for the duration of a compaction, so that two never overlap,
+    /// and while the catalog changes, so a backup sees it whole.
+    compacting: Mutex<()>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    options: Options,
+    /// Sequence number of the last commit.
+    seq: u64,
+    /// Sequence numbers of open snapshots, with how many share each.
+    snapshots: BTreeMap<u64, usize>,
+    catalog: Catalog,
+    spaces: BTreeMap<KeyspaceId, Space>,
+    /// Appends commits that span keyspaces, before their parts are written.
+    batch_log: Wal,
+    replication: Replication,
+    /// Decoded table blocks, shared by every keyspace.
+    cache: Option<Arc<BlockCache>>,
+    /// Holds the lock on the directory until the database is closed.
+    _lock: File,
+}
+
+/// The log and index of one keyspace.
+struct Space {
+    /// The keyspace's name, for logging.
+    name: String,
+    dir: PathBuf,
+    fsync: FsyncPolicy,
+    segment_size: u64,
+    /// Whether sealed segments are mapped into memory.
+    mmap: bool,
+    cache: Option<Arc<BlockCache>>,
+    /// Appends to the active segment.
+    wal: Wal,
+    active: u64,
+    /// Every segment, including the active one.
+    segments: BTreeMap<u64, Segment>,
+    /// Sequence number of the last commit that wrote to this keyspace.
+    seq: u64,
+    index: BTreeMap<Vec<u8>, Vec<Version>>,
+    /// Number of keys whose latest version is not a delete.
+    live_keys: usize,
+    /// Keys holding more than a single live version, to revisit when the
+    /// oldest snapshot is released.
+    stale: HashSet<Vec<u8>>,
+    /// The sorted table in the oldest segment, holding the keys that are
+    /// not in the index.
+    table: Option<Table>,
+    /// Set while compaction writes a table, which the deletes in the index
+    /// will have to hide keys of once it is swapped in.
+    writing_table: bool,
+    validator: Option<Validator>,
+}
+
+/// Checks a key and value about to be put in a keyspace, giving the reason
+/// if they may not be.
+type Validator = Box<dyn Fn(&[u8], &[u8]) -> std::result::Result<(), String> + Send + Sync>;
+
+struct Segment {
+    /// Handle for reading values; appends go through [`Space::wal`].
+    file: DataFile,
+    size: u64,
+    /// Bytes of operations whose versions have been dropped. Record
+    /// framing is not counted, so this errs on the low side.
+    garbage: u64,
+}
+
+/// One value of a key, written by the commit numbered `seq`. A `None`
+/// location marks a delete.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Version {
+    seq: u64,
+    ptr: Option<ValuePtr>,
+}
+
+/// Location of a value within the log.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct ValuePtr {
+    segment: u64,
+    offset: u64,
+    len: u32,
+    /// CRC32C of the key and the value, taken when the record holding them
+    /// passed its own checksum, and checked on every read of the value.
+    crc: u32,
+}
+
+/// Iterator over the live key-value pairs in a key range, in key order, as
+/// of the moment it was created. Writes made while iterating are not seen.
+pub struct Iter<'db> {
+    db: &'db Db,
+    keyspace: KeyspaceId,
+    snapshot: u64,
+    /// Whether the iterator holds the snapshot itself, rather than reading
+    /// within a transaction's.
+    owns_snapshot: bool,
+    /// Where the next batch starts; it moves past each batch read.
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    buffer: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+/// The segment files found in a keyspace directory.
+struct Listing {
+    /// Segments to replay, in order, and whether each is compacted.
+    live: Vec<(u64, bool, PathBuf)>,
+    /// Segments superseded by a compacted one, and unfinished compactions.
+    obsolete: Vec<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            fsync: FsyncPolicy::Always,
+            segment_size: 64 << 20,
+            compaction: CompactionOptions::default(),
+            replication: ReplicationOptions::default(),
+            read: ReadOptions::default(),
+            max_memory: 0,
+        }
+    }
+}
+
+impl Db {
+    /// Opens the database in `dir`, creating the directory if needed. Fails
+    /// if another handle, in this process or another, has it open.
+    pub fn open<P: AsRef<Path>>(dir: P, options: Options) -> Result<Db> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let lock = lock_dir(dir)?;
+
+        let catalog = Catalog::load(dir)?;
+        for orphan in keyspace::orphaned_dirs(dir, &catalog)? {
+            info!(path = %orphan.display(), "removing files of a keyspace that is not in the catalog");
+            fs::remove_dir_all(orphan)?;
+        }
+        let budget = cache_budget(&options);
+        if budget < options.read.block_cache {
+            debug!(budget, max_memory = options.max_memory, "block cache cut down to fit the memory limit");
+        }
+        let cache = if budget > 0 {
+            Some(Arc::new(BlockCache::new(budget)))
+        } else {
+            None
+        };
+        let mut spaces = BTreeMap::new();
+        for id in catalog.ids() {
+            let name = catalog.name(id).unwrap();
+            spaces.insert(id, Space::open(&keyspace::keyspace_dir(dir, id), name, &options, cache.clone())?);
+        }
+
+        let mut seq = spaces.values().map(|space| space.seq).max().unwrap_or(0);
+        let batch_path = dir.join(BATCH_LOG_FILE);
+        if batch_path.exists() {
+            for entry in wal::Reader::open(&batch_path)? {
+                let (offset, record) = entry?;
+                seq = seq.max(record.seq);
+                redo(&mut spaces, offset, record)?;
+            }
+            // The batch log is emptied below, so what it held must be safe
+            // in the keyspaces first.
+            for space in spaces.values() {
+                space.wal.sync()?;
+            }
+        }
+
+        let inner = Inner {
+            dir: dir.to_owned(),
+            options: options.clone(),
+            seq,
+            snapshots: BTreeMap::new(),
+            catalog,
+            spaces,
+            batch_log: Wal::open(&batch_path, 0, options.fsync)?,
+            replication: Replication::new(options.replication, seq),
+            cache,
+            _lock: lock,
+        };
+        debug!(dir = %dir.display(), keyspaces = inner.spaces.len(), seq, "opened database");
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(inner),
+            compacting: Mutex::new(()),
+        });
+        let compactor = if options.compaction.interval > Duration::from_secs(0) {
+            let weak = Arc::downgrade(&shared);
+            Some(Compactor::spawn(options.compaction.interval, move |cancel| {
+                if let Some(shared) = weak.upgrade() {
+                    let spaces: Vec<_> = {
+                        let inner = shared.inner.lock().unwrap();
+                        inner.spaces.iter().map(|(&id, space)| (id, space.name.clone())).collect()
+                    };
+                    for (id, name) in spaces {
+                        if cancel.load(Ordering::Acquire) {
+                            break;
+                        }
+                        if shared.needs_compaction(id) {
+                            if let Err(e) = shared.compact(id, cancel) {
+                                error!(keyspace = %name, error = %e, "compaction failed");
+                            }
+                        }
+                    }
+                }
+            }))
+        } else {
+            None
+        };
+
+        Ok(Db {
+            _compactor: compactor,
+            shared,
+        })
+    }
+
+    /// The keyspace called `name`.
+    pub fn keyspace(&self, name: &str) -> Result<Keyspace<'_>> {
+        let inner = self.shared.inner.lock().unwrap();
+        match inner.catalog.id(name) {
+            Some(id) => Ok(Keyspace {
+                db: self,
+                id,
+                name: name.to_owned(),
+            }),
+            None => Err(Error::NoSuchKeyspace(name.to_owned())),
+        }
+    }
+
+    /// The keyspace every database has, which cannot be dropped.
+    pub fn default_keyspace(&self) -> Keyspace<'_> {
+        Keyspace {
+            db: self,
+            id: DEFAULT_ID,
+            name: DEFAULT_KEYSPACE.to_owned(),
+        }
+    }
+
+    /// Every keyspace, the default one first and the rest in the order
+    /// they were created.
+    pub fn keyspaces(&self) -> Vec<Keyspace<'_>> {
+        let inner = self.shared.inner.lock().unwrap();
+        inner
+            .catalog
+            .ids()
+            .into_iter()
+            .map(|id| Keyspace {
+                db: self,
+                id,
+                name: inner.catalog.name(id).unwrap().to_owned(),
+            })
+            .collect()
+    }
+
+    /// Creates an empty keyspace called `name`.
+    pub fn create_keyspace(&self, name: &str) -> Result<Keyspace<'_>> {
+        keyspace::validate_name(name)?;
+        let _compacting = self.shared.compacting.lock().unwrap();
+        let mut inner = self.shared.inner.lock().unwrap();
+        if inner.catalog.id(name).is_some() {
+            return Err(Error::KeyspaceExists(name.to_owned()));
+        }
+
+        let mut catalog = inner.catalog.clone();
+        let id = catalog.insert(name);
+        // The directory comes first: until the catalog names it, the next
+        // open deletes it as a leftover.
+        let dir = keyspace::keyspace_dir(&inner.dir, id);
+        let space = Space::open(&dir, name, &inner.options, inner.cache.clone())?;
+        if let Err(e) = catalog.store(&inner.dir) {
+            drop(space);
+            let _ = fs::remove_dir_all(&dir);
+            return Err(e);
+        }
+        inner.catalog = catalog;
+        inner.spaces.insert(id, space);
+        let seq = inner.seq;
+        inner.replication.push(Event::CreateKeyspace {
+            seq,
+            name: name.to_owned(),
+        });
+        info!(keyspace = name, id, "created keyspace");
+        Ok(Keyspace {
+            db: self,
+            id,
+            name: name.to_owned(),
+        })
+    }
+
+    /// Drops the keyspace called `name` and deletes everything in it. Waits
+    /// for a compaction in progress to finish first.
+    pub fn drop_keyspace(&self, name: &str) -> Result<()> {
+        let _compacting = self.shared.compacting.lock().unwrap();
+        let space = {
+            let mut inner = self.shared.inner.lock().unwrap();
+            let id = inner.catalog.id(name).ok_or_else(|| Error::NoSuchKeyspace(name.to_owned()))?;
+            if id == DEFAULT_ID {
+                return Err(Error::DropDefaultKeyspace);
+            }
+            let mut catalog = inner.catalog.clone();
+            catalog.remove(id);
+            catalog.store(&inner.dir)?;
+            inner.catalog = catalog;
+            let seq = inner.seq;
+            inner.replication.push(Event::DropKeyspace {
+                seq,
+                name: name.to_owned(),
+            });
+            let space = inner.spaces.remove(&id).unwrap();
+            // If only this keyspace recorded the last commit, its number
+            // would be handed out again after a restart, and replicas resume
+            // by number.
+            if space.seq == seq && inner.spaces.values().all(|space| space.seq < seq) {
+                inner.write_at(seq, Vec::new())?;
+            }
+            space
+        };
+        // Out of the catalog, the files would be deleted on the next open
+        // anyway.
+        let dir = space.dir.clone();
+        drop(space);
+        fs::remove_dir_all(dir)?;
+        info!(keyspace = name, "dropped keyspace");
+        Ok(())
+    }
+
+    /// Applies every operation in `batch` atomically, as a single commit,
+    /// even if it writes to several keyspaces.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.write(batch.into_ops())
+    }
+
+    /// Starts a transaction reading from a snapshot of the current state of
+    /// every keyspace.
+    pub fn begin(&self) -> Txn<'_> {
+        Txn::new(self, self.snapshot())
+    }
+
+    /// Writes a consistent copy of the database, as of now, into `dest`,
+    /// which must not exist yet or be empty. Commits and compactions can
+    /// continue while the files are copied; new commits are not part of the
+    /// backup.
+    pub fn backup(&self, dest: &Path) -> Result<Manifest> {
+        // Compaction is the only thing that deletes segments, and the
+        // catalog only changes under the same lock. It is held just long
+        // enough to open the files; the copy then reads from those handles
+        // while compaction and writes go on.
+        let (seq, sources) = {
+            let _compacting = self.shared.compacting.lock().unwrap();
+            let inner = self.shared.inner.lock().unwrap();
+            let mut sources = Vec::new();
+            for space in inner.spaces.values() {
+                for (&id, segment) in &space.segments {
+                    sources.push(backup::Source {
+                        name: relative_name(&inner.dir, segment.file.path()),
+                        path: segment.file.path().to_owned(),
+                        file: File::open(segment.file.path())?,
+                        len: segment.size,
+                        sealed: id != space.active,
+                    });
+                }
+            }
+            // Commits in the batch log are complete whenever the lock is
+            // free, so only the catalog is needed besides the segments.
+            let catalog = inner.dir.join(CATALOG_FILE);
+            if catalog.exists() {
+                sources.push(backup::Source {
+                    name: CATALOG_FILE.to_owned(),
+                    len: fs::metadata(&catalog)?.len(),
+                    file: File::open(&catalog)?,
+                    path: catalog,
+                    sealed: false,
+                });
+            }
+            (inner.seq, sources)
+        };
+        let manifest = backup::create(dest, seq, &sources)?;
+        info!(dest = %dest.display(), files = manifest.files.len(), bytes = manifest.bytes(), seq, "backup written");
+        Ok(manifest)
+    }
+
+    pub(crate) fn get_at(&self, keyspace: KeyspaceId, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        self.shared.inner.lock().unwrap().space(keyspace)?.get_at(key, seq)
+    }
+
+    /// Iterates over `start..end` of `keyspace` as of `seq`, a snapshot held
+    /// by the caller.
+    pub(crate) fn scan_at(&self, keyspace: KeyspaceId, seq: u64, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Iter<'_> {
+        Iter::new(self, keyspace, seq, false, start, end)
+    }
+
+    /// Takes a snapshot of the current state, to be given back with
+    /// [`Db::release`].
+    fn snapshot(&self) -> u64 {
+        let mut inner = self.shared.inner.lock().unwrap();
+        let seq = inner.seq;
+        *inner.snapshots.entry(seq).or_insert(0) += 1;
+        seq
+    }
+
+    /// Commits `batch` on behalf of a transaction reading at `snapshot`,
+    /// failing if a later commit already wrote any of the same keys.
+    pub(crate) fn commit(&self, snapshot: u64, batch: WriteBatch) -> Result<()> {
+        let ops = batch.into_ops();
+        let mut inner = self.shared.inner.lock().unwrap();
+        for (keyspace, op) in &ops {
+            if inner.space(*keyspace)?.latest(op.key()).is_some_and(|v| v.seq > snapshot) {
+                return Err(Error::Conflict(op.key().to_vec()));
+            }
+        }
+        inner.write(ops)
+    }
+
+    /// Releases a snapshot taken by [`Db::begin`] or a scan.
+    pub(crate) fn release(&self, snapshot: u64) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        let count = inner.snapshots.get_mut(&snapshot).expect("released an unknown snapshot");
+        *count -= 1;
+        if *count == 0 {
+            inner.snapshots.remove(&snapshot);
+            let oldest = inner.oldest();
+            for space in inner.spaces.values_mut() {
+                space.collect_stale(oldest);
+            }
+        }
+    }
+
+    /// Runs `f` on the replication state, along with the sequence number of
+    /// the last commit.
+    pub(crate) fn replication<T, F: FnOnce(&mut Replication, u64) -> T>(&self, f: F) -> T {
+        let mut inner = self.shared.inner.lock().unwrap();
+        let seq = inner.seq;
+        f(&mut inner.replication, seq)
+    }
+
+    pub(crate) fn dir(&self) -> PathBuf {
+        self.shared.inner.lock().unwrap().dir.clone()
+    }
+
+    /// Sequence number of the last commit.
+    pub(crate) fn last_seq(&self) -> u64 {
+        self.shared.inner.lock().unwrap().seq
+    }
+
+    /// The replication events from position `from` on, waiting up to
+    /// `timeout` for one if there are none yet. `None` if some of them are
+    /// no longer held.
+    pub(crate) fn wait_for_events(&self, from: u64, timeout: Duration) -> Option<Vec<Arc<Event>>> {
+        let inner = self.shared.inner.lock().unwrap();
+        let changed = inner.replication.changed();
+        let (inner, _) = changed
+            .wait_timeout_while(inner, timeout, |inner| inner.replication.end() == from)
+            .unwrap();
+        inner.replication.read(from)
+    }
+
+    /// Where a replica that has applied every commit through `seq` resumes
+    /// in the replication events, with the name of every keyspace as of
+    /// there, or `None` if the events it is missing are no longer held.
+    pub(crate) fn resume_replica(&self, seq: u64) -> Option<(u64, Vec<String>)> {
+        let inner = self.shared.inner.lock().unwrap();
+        let cursor = inner.replication.resume(seq, inner.seq)?;
+        let names = inner.spaces.values().map(|space| space.name.clone()).collect();
+        Some((cursor, names))
+    }
+
+    /// Takes a snapshot for replica `replica` to load, returning the
+    /// position of the first replication event after it, its sequence
+    /// number, a transaction to read it through, and every keyspace. The
+    /// events from that position on are held until the replica acks the
+    /// snapshot.
+    pub(crate) fn replication_snapshot(&self, replica: u64) -> (u64, u64, Txn<'_>, Vec<Keyspace<'_>>) {
+        // Taken under one lock, so no commit falls between the snapshot and
+        // the position, and none is dropped from the backlog before the pin.
+        let mut inner = self.shared.inner.lock().unwrap();
+        let seq = inner.seq;
+        *inner.snapshots.entry(seq).or_insert(0) += 1;
+        let cursor = inner.replication.end();
+        inner.replication.pin(replica, cursor, seq);
+        let keyspaces = inner
+            .catalog
+            .ids()
+            .into_iter()
+            .map(|id| Keyspace {
+                db: self,
+                id,
+                name: inner.catalog.name(id).unwrap().to_owned(),
+            })
+            .collect();
+        (cursor, seq, Txn::new(self, seq), keyspaces)
+    }
+
+    /// Applies `batch`, received from the primary, under the primary's
+    /// sequence number `seq`. The commits a snapshot is loaded in all share
+    /// its number.
+    pub(crate) fn replicate(&self, seq: u64, batch: WriteBatch) -> Result<()> {
+        let mut inner = self.shared.inner.lock().unwrap();
+        if seq < inner.seq {
+            return Err(Error::Replication(format!("commit {} arrived after commit {}", seq, inner.seq)));
+        }
+        inner.write_at(seq, batch.into_ops())
+    }
+
+    /// Empties the database, for a replica about to load a snapshot: drops
+    /// every keyspace but the default one and deletes the default one's
+    /// keys with its log.
+    pub(crate) fn reset(&self) -> Result<()> {
+        let _compacting = self.shared.compacting.lock().unwrap();
+        let mut inner = self.shared.inner.lock().unwrap();
+        let mut catalog = inner.catalog.clone();
+        for id in catalog.ids() {
+            if id != DEFAULT_ID {
+                catalog.remove(id);
+            }
+        }
+        catalog.store(&inner.dir)?;
+        inner.catalog = catalog;
+        let dropped: Vec<_> = inner.spaces.split_off(&(DEFAULT_ID + 1)).into_values().collect();
+        for space in dropped {
+            let dir = space.dir.clone();
+            drop(space);
+            fs::remove_dir_all(dir)?;
+        }
+
+        let default = inner.spaces.remove(&DEFAULT_ID).unwrap();
+        let dir = default.dir.clone();
+        drop(default);
+        let listing = list_segments(&dir)?;
+        for path in listing.live.into_iter().map(|(_, _, path)| path).chain(listing.obsolete) {
+            fs::remove_file(path)?;
+        }
+        sync_dir(&dir)?;
+        let space = Space::open(&dir, DEFAULT_KEYSPACE, &inner.options, inner.cache.clone())?;
+        inner.spaces.insert(DEFAULT_ID, space);
+        inner.batch_log.truncate(0)?;
+        inner.seq = 0;
+        info!("emptied the database");
+        Ok(())
+    }
+
+    /// Forces everything written so far to stable storage.
+    pub(crate) fn sync(&self) -> Result<()> {
+        let inner = self.shared.inner.lock().unwrap();
+        for space in inner.spaces.values() {
+            space.wal.sync()?;
+        }
+        inner.batch_log.sync()
+    }
+}
+
+impl<'db> Keyspace<'db> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn id(&self) -> KeyspaceId {
+        self.id
+    }
+
+    pub(crate) fn db(&self) -> &'db Db {
+        self.db
+    }
+
+    /// The value stored under `key`, if any.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let inner = self.db.shared.inner.lock().unwrap();
+        inner.space(self.id)?.get_at(key, inner.seq)
+    }
+
+    /// Stores `value` under `key`, replacing any previous value.
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut inner = self.db.shared.inner.lock().unwrap();
+        inner.write(vec![(
+            self.id,
+            Op::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+        )])
+    }
+
+    /// Has every later commit that puts a value in this keyspace, whether
+    /// alone, in a batch or in a transaction, checked by `validator` first.
+    /// If it returns an error for any key, the whole commit is rejected with
+    /// [`Error::Rejected`]. Deletes are not checked, nor are commits a
+    /// replica receives from its primary. The validator runs while commits
+    /// are held up, so it should be quick. It replaces any set before, and
+    /// lasts until the database is closed or the keyspace dropped.
+    pub fn set_validator<F>(&self, validator: F) -> Result<()>
+    where
+        F: Fn(&[u8], &[u8]) -> std::result::Result<(), String> + Send + Sync + 'static,
+    {
+        let mut inner = self.db.shared.inner.lock().unwrap();
+        inner.space_mut(self.id)?.validator = Some(Box::new(validator));
+        Ok(())
+    }
+
+    /// Stops checking puts in this keyspace.
+    pub fn clear_validator(&self) -> Result<()> {
+        let mut inner = self.db.shared.inner.lock().unwrap();
+        inner.space_mut(self.id)?.validator = None;
+        Ok(())
+    }
+
+    /// Removes `key`, returning whether it was present.
+    pub fn delete(&self, key: &[u8]) -> Result<bool> {
+        let mut inner = self.db.shared.inner.lock().unwrap();
+        if !inner.space(self.id)?.contains(key)? {
+            return Ok(false);
+        }
+        inner.write(vec![(self.id, Op::Delete { key: key.to_vec() })])?;
+        Ok(true)
+    }
+
+    /// Iterates over the live key-value pairs whose keys fall in `range`, in
+    /// key order.
+    pub fn scan<K: AsRef<[u8]> + ?Sized, R: RangeBounds<K>>(&self, range: R) -> Iter<'db> {
+        let (start, end) = owned_bounds(&range);
+        Iter::new(self.db, self.id, self.db.snapshot(), true, start, end)
+    }
+
+    /// Iterates over the live key-value pairs whose keys start with `prefix`,
+    /// in key order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Iter<'db> {
+        Iter::new(
+            self.db,
+            self.id,
+            self.db.snapshot(),
+            true,
+            Bound::Included(prefix.to_vec()),
+            prefix_end(prefix),
+        )
+    }
+
+    /// Compacts this keyspace's log now, regardless of the configured
+    /// thresholds. Waits for a background compaction in progress, of any
+    /// keyspace, to finish first.
+    pub fn compact(&self) -> Result<CompactionStats> {
+        let stats = self.db.shared.compact(self.id, &AtomicBool::new(false))?;
+        Ok(stats.expect("compaction cancelled without a request"))
+    }
+
+    /// A summary of the keyspace as of now.
+    pub fn stats(&self) -> Result<Stats> {
+        let inner = self.db.shared.inner.lock().unwrap();
+        let space = inner.space(self.id)?;
+        Ok(Stats {
+            keys: space.live_keys,
+            last_seq: inner.seq,
+            log_bytes: space.size(),
+            segments: space.segments.len(),
+            garbage_bytes: space.garbage(),
+            open_snapshots: inner.snapshots.values().sum(),
+            block_cache: inner.cache.as_ref().map(|cache| cache.stats()).unwrap_or_default(),
+        })
+    }
+}
+
+impl Shared {
+    fn needs_compaction(&self, keyspace: KeyspaceId) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.spaces.get(&keyspace) {
+            Some(space) => inner.options.compaction.should_compact(space.reclaimable(), space.size()),
+            None => false,
+        }
+    }
+
+    /// Rewrites every sealed segment of `keyspace` into one compacted
+    /// segment, or into a table if the keyspace writes them. The index lock
+    /// is only held to seal the active segment and to swap in the new
+    /// locations; commits and reads proceed while values are copied. Returns
+    /// `None` if `cancel` was raised before the copy finished.
+    fn compact(&self, keyspace: KeyspaceId, cancel: &AtomicBool) -> Result<Option<CompactionStats>> {
+        let _compacting = self.compacting.lock().unwrap();
+
+        // After sealing, every version in the index lives below `output`.
+        let (span, output, dir, rate_limit, block_size, last_seq, horizon, inputs, old_table, live, mmap, cache) = {
+            let mut inner = self.inner.lock().unwrap();
+            let rate_limit = inner.options.compaction.rate_limit;
+            let block_size = inner.options.read.block_size;
+            // Every reader, now or later, sees at least the commits through
+            // `horizon`.
+            let horizon = inner.oldest().unwrap_or(inner.seq);
+            let space = inner.space_mut(keyspace)?;
+            let span = info_span!("compaction", keyspace = %space.name);
+            let sealed = space.active;
+            space.rotate(sealed + 2)?;
+            let inputs: Vec<(u64, PathBuf, u64)> = space
+                .segments
+                .range(..=sealed)
+                .map(|(&id, segment)| (id, segment.file.path().to_owned(), segment.size))
+                .collect();
+            let old_table = space
+                .table
+                .as_ref()
+                .map(|table| space.segments[&table.segment()].file.path().to_owned());
+            let live = if space.writes_tables() {
+                space.writing_table = true;
+                Live::Keys(space.index.iter().map(|(key, versions)| (key.clone(), versions.clone())).collect())
+            } else {
+                Live::Versions(
+                    space
+                        .index
+                        .iter()
+                        .flat_map(|(key, versions)| versions.iter().map(move |v| (v.seq, key.clone(), v.ptr)))
+                        .collect(),
+                )
+            };
+            let mmap = space.mmap;
+            let cache = space.cache.clone();
+            (span, sealed + 1, space.dir.clone(), rate_limit, block_size, space.seq, horizon, inputs, old_table, live, mmap, cache)
+        };
+        let _span = span.entered();
+
+        let path = segment_path(&dir, output, COMPACT_EXT);
+        let tmp = path.with_extension(format!("{}.tmp", COMPACT_EXT));
+        let written = SegmentWriter::create(&tmp, output, &inputs, mmap, rate_limit, cancel).and_then(|mut writer| {
+            let copied = match live {
+                Live::Keys(entries) => {
+                    debug!(segments = inputs.len(), keys = entries.len(), output, "writing a table");
+                    let old = old_table.as_deref().map(Entries::open).transpose()?;
+                    write_table(&mut writer, old, entries, horizon, block_size, cache)?
+                }
+                Live::Versions(mut versions) => {
+                    debug!(segments = inputs.len(), versions = versions.len(), output, "copying live versions");
+                    // Stable, so versions of one key within a commit keep their order.
+                    versions.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+                    writer.copy_versions(versions)?.map(|moved| (None, Vec::new(), moved))
+                }
+            };
+            match copied {
+                Some((table, absorbed, moved)) => Ok(Some((writer.finish(last_seq)?, table, absorbed, moved))),
+                None => Ok(None),
+            }
+        });
+        let (size, table, absorbed, moved) = match written {
+            Ok(Some(written)) => written,
+            result => {
+                let _ = fs::remove_file(&tmp);
+                if let Ok(space) = self.inner.lock().unwrap().space_mut(keyspace) {
+                    space.writing_table = false;
+                }
+                if result.is_ok() {
+                    info!("compaction cancelled");
+                }
+                return result.map(|_| None);
+            }
+        };
+        fs::rename(&tmp, &path)?;
+        sync_dir(&dir)?;
+        let file = DataFile::open(&path, mmap)?;
+
+        {
+            let mut inner = self.inner.lock().unwrap();
+            // Dropping a keyspace waits for compactions, so it is still here.
+            let space = inner.space_mut(keyspace)?;
+            // Versions dropped while copying are garbage in the new segment.
+            let mut garbage = 0;
+            for (key, seq, old, new) in moved {
+                let version = space
+                    .index
+                    .get_mut(&key)
+                    .and_then(|versions| versions.iter_mut().find(|v| v.seq == seq && v.ptr == Some(old)));
+                match version {
+                    Some(version) => version.ptr = Some(new),
+                    None => garbage += op_len(&key, new),
+                }
+            }
+            // The table now answers for these, in one step with the index
+            // giving them up.
+            for (key, version) in absorbed {
+                if let Some(versions) = space.index.get_mut(&key) {
+                    versions.retain(|v| *v != version);
+                    if versions.is_empty() {
+                        space.index.remove(&key);
+                        space.stale.remove(&key);
+                    }
+                }
+            }
+            if table.is_some() {
+                space.table = table;
+                space.writing_table = false;
+            }
+            for (id, _, _) in &inputs {
+                space.segments.remove(id);
+            }
+            space.segments.insert(output, Segment { file, size, garbage });
+        }
+
+        // Superseded by the compacted segment, so any left behind by a
+        // failure here are removed on the next open.
+        for (_, path, _) in &inputs {
+            let _ = fs::remove_file(path);
+        }
+        let stats = CompactionStats {
+            segments: inputs.len(),
+            bytes_before: inputs.iter().map(|(_, _, size)| size).sum(),
+            bytes_after: size,
+        };
+        info!(
+            segments = stats.segments,
+            bytes_before = stats.bytes_before,
+            bytes_after = stats.bytes_after,
+            "compaction finished"
+        );
+        Ok(Some(stats))
+    }
+}
+
+/// What compaction copies out of the index.
+enum Live {
+    /// Every version, as its commit, key and location.
+    Versions(Vec<(u64, Vec<u8>, Option<ValuePtr>)>),
+    /// Every key with its versions, to be merged into a table.
+    Keys(Vec<(Vec<u8>, Vec<Version>)>),
+}
+
+/// A value moved by compaction: its key, commit, and old and new locations.
+type Moved = (Vec<u8>, u64, ValuePtr, ValuePtr);
+
+/// What writing a table leaves to swap in: the table, the versions its
+/// blocks took over from the index, and where the newer values went.
+type Written = (Option<Table>, Vec<(Vec<u8>, Version)>, Vec<Moved>);
+
+/// Writes a compacted segment, reading values from the segments it
+/// replaces.
+struct SegmentWriter<'a> {
+    id: u64,
+    // Separate handles, so reads here do not move the cursors readers of the
+    // index seek with.
+    files: HashMap<u64, DataFile>,
+    out: Wal,
+    throttle: Throttle,
+    cancel: &'a AtomicBool,
+    /// Sequence number of the last commit written.
+    copied_seq: u64,
+}
+
+impl<'a> SegmentWriter<'a> {
+    /// Starts the segment numbered `id` at `path`, reading from `inputs`,
+    /// mapped into memory with `map`.
+    fn create(
+        path: &Path,
+        id: u64,
+        inputs: &[(u64, PathBuf, u64)],
+        map: bool,
+        rate_limit: u64,
+        cancel: &'a AtomicBool,
+    ) -> Result<SegmentWriter<'a>> {
+        let mut files = HashMap::new();
+        for (id, path, _) in inputs {
+            files.insert(*id, DataFile::open(path, map)?);
+        }
+        Ok(SegmentWriter {
+            id,
+            files,
+            out: Wal::open(path, 0, FsyncPolicy::Never)?,
+            throttle: Throttle::new(rate_limit),
+            cancel,
+            copied_seq: 0,
+        })
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Acquire)
+    }
+
+    fn read(&self, key: &[u8], ptr: ValuePtr) -> Result<Vec<u8>> {
+        read_value(&self.files[&ptr.segment], key, ptr)
+    }
+
+    /// Writes `versions`, sorted by commit, grouping the versions of each
+    /// commit into one record. Returns where each value went, or `None` if
+    /// cancelled.
+    fn copy_versions(&mut self, versions: Vec<(u64, Vec<u8>, Option<ValuePtr>)>) -> Result<Option<Vec<Moved>>> {
+        let mut moved = Vec::new();
+        let mut versions = versions.into_iter().peekable();
+        while let Some(&(seq, _, _)) = versions.peek() {
+            if self.cancelled() {
+                return Ok(None);
+            }
+            let mut ops = Vec::new();
+            let mut old = Vec::new();
+            while let Some((_, key, ptr)) = versions.next_if(|v| v.0 == seq) {
+                match ptr {
+                    Some(ptr) => {
+                        let value = self.read(&key, ptr)?;
+                        ops.push(Op::Put { key, value });
+                    }
+                    None => ops.push(Op::Delete { key }),
+                }
+                old.push(ptr);
+            }
+
+            let record = Record { seq, ops };
+            let before = self.out.size();
+            let offset = self.out.append(&record)?;
+            self.throttle.consume(self.out.size() - before);
+            let offsets = wal::value_offsets(offset, &record.ops);
+            for ((op, old), offset) in record.ops.into_iter().zip(old).zip(offsets) {
+                if let Some(old) = old {
+                    let new = ValuePtr {
+                        segment: self.id,
+                        offset,
+                        ..old
+                    };
+                    moved.push((op.key().to_vec(), seq, old, new));
+                }
+            }
+            self.copied_seq = seq;
+        }
+        Ok(Some(moved))
+    }
+
+    /// Ends the segment with an empty record numbered `last_seq` if no
+    /// commit written carries it, so that the keyspace still knows, when
+    /// replayed, which commits it has seen. Returns the segment's size.
+    fn finish(mut self, last_seq: u64) -> Result<u64> {
+        if self.copied_seq < last_seq {
+            self.out.append(&Record {
+                seq: last_seq,
+                ops: Vec::new(),
+            })?;
+        }
+        let size = self.out.size();
+        // Dropping the log syncs it.
+        drop(self.out);
+        Ok(size)
+    }
+}
+
+/// Writes the latest value of every key as of `horizon` into the blocks of
+/// a new table, merging `entries`, a copy of the index, into `old`, the
+/// entries of the table it replaces. The versions newer than `horizon`
+/// follow the blocks as commits. Returns `None` if cancelled.
+fn write_table(
+    writer: &mut SegmentWriter,
+    old: Option<Entries>,
+    entries: Vec<(Vec<u8>, Vec<Version>)>,
+    horizon: u64,
+    block_size: u64,
+    cache: Option<Arc<BlockCache>>,
+) -> Result<Option<Written>> {
+    let mut blocks = TableWriter::new(block_size);
+    let mut old = old.into_iter().flatten().peekable();
+    let mut entries = entries.into_iter().peekable();
+    let mut absorbed = Vec::new();
+    let mut newer = Vec::new();
+    loop {
+        if writer.cancelled() {
+            return Ok(None);
+        }
+        // Take whichever side has the smaller key; on a tie the index
+        // shadows the old table if it has a version as old as `horizon`.
+        let order = match (entries.peek(), old.peek()) {
+            (None, None) => break,
+            (_, Some(Err(_))) | (None, Some(_)) => cmp::Ordering::Greater,
+            (Some(_), None) => cmp::Ordering::Less,
+            (Some((key, _)), Some(Ok((old_key, _)))) => key.cmp(old_key),
+        };
+        let (key, value) = if order == cmp::Ordering::Greater {
+            old.next().unwrap()?
+        } else {
+            let (key, versions) = entries.next().unwrap();
+            let split = versions.iter().rposition(|v| v.seq <= horizon).map_or(0, |i| i + 1);
+            newer.extend(versions[split..].iter().map(|v| (v.seq, key.clone(), v.ptr)));
+            absorbed.extend(versions[..split].iter().map(|&v| (key.clone(), v)));
+            let base = match split.checked_sub(1) {
+                Some(i) => versions[i],
+                // Only written after `horizon`, so the old table's entry for
+                // the key, if any, comes next.
+                None => continue,
+            };
+            if order == cmp::Ordering::Equal {
+                old.next();
+            }
+            match base.ptr {
+                Some(ptr) => {
+                    let value = writer.read(&key, ptr)?;
+                    (key, value)
+                }
+                None => continue,
+            }
+        };
+        let written = blocks.add(&mut writer.out, key, value)?;
+        writer.throttle.consume(written);
+    }
+    let (table, written) = blocks.finish(&mut writer.out, writer.id, cache)?;
+    writer.throttle.consume(written);
+
+    // Stable, so versions of one key within a commit keep their order.
+    newer.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+    Ok(writer.copy_versions(newer)?.map(|moved| (Some(table), absorbed, moved)))
+}
+
+/// Writes the parts of a commit read from the batch log at `offset` that
+/// are missing from their keyspaces. Keyspaces dropped since are skipped.
+fn redo(spaces: &mut BTreeMap<KeyspaceId, Space>, offset: u64, record: Record) -> Result<()> {
+    for (id, ops) in split_batch(offset, record.ops)? {
+        if let Some(space) = spaces.get_mut(&id) {
+            // A keyspace holds its commits in order, so having seen this one
+            // or a later one means it is not missing this part.
+            if space.seq < record.seq {
+                info!(keyspace = %space.name, seq = record.seq, "restoring part of an interrupted commit from the batch log");
+                let part = Record { seq: record.seq, ops };
+                let (segment, offset) = space.append(&part)?;
+                space.apply(segment, offset, part, None);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Flattens the parts of a commit into the operations of one batch log
+/// record, prefixing each key with the id of its keyspace.
+fn join_batch(parts: &BTreeMap<KeyspaceId, Vec<Op>>) -> Vec<Op> {
+    let tag = |id: KeyspaceId, key: &[u8]| {
+        let mut tagged = id.to_be_bytes().to_vec();
+        tagged.extend_from_slice(key);
+        tagged
+    };
+    parts
+        .iter()
+        .flat_map(|(&id, ops)| {
+            ops.iter().map(move |op| match op {
+                Op::Put { key, value } => Op::Put {
+                    key: tag(id, key),
+                    value: value.clone(),
+                },
+                Op::Delete { key } => Op::Delete { key: tag(id, key) },
+            })
+        })
+        .collect()
+}
+
+/// Splits the operations of a batch log record at `offset` back into the
+/// parts of its commit.
+fn split_batch(offset: u64, ops: Vec<Op>) -> Result<BTreeMap<KeyspaceId, Vec<Op>>> {
+    let mut parts: BTreeMap<KeyspaceId, Vec<Op>> = BTreeMap::new();
+    for op in ops {
+        if op.key().len() < 4 {
+            return Err(Error::Corrupted {
+                offset,
+                reason: "batch log key without a keyspace",
+            });
+        }
+        let id = KeyspaceId::from_be_bytes(op.key()[..4].try_into().unwrap());
+        let op = match op {
+            Op::Put { key, value } => Op::Put {
+                key: key[4..].to_vec(),
+                value,
+            },
+            Op::Delete { key } => Op::Delete { key: key[4..].to_vec() },
+        };
+        parts.entry(id).or_default().push(op);
+    }
+    Ok(parts)
+}
+
+impl Inner {
+    fn space(&self, keyspace: KeyspaceId) -> Result<&Space> {
+        self.spaces.get(&keyspace).ok_or(Error::KeyspaceDropped)
+    }
+
+    fn space_mut(&mut self, keyspace: KeyspaceId) -> Result<&mut Space> {
+        self.spaces.get_mut(&keyspace).ok_or(Error::KeyspaceDropped)
+    }
+
+    fn oldest(&self) -> Option<u64> {
+        self.snapshots.keys().next().copied()
+    }
+
+    /// Checks `ops` against the validators of their keyspaces, then logs
+    /// them as a single commit and applies them.
+    fn write(&mut self, ops: Vec<(KeyspaceId, Op)>) -> Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        for (keyspace, op) in &ops {
+            let space = self.space(*keyspace)?;
+            if let (Some(validator), Op::Put { key, value }) = (&space.validator, op) {
+                validator(key, value).map_err(|reason| Error::Rejected {
+                    keyspace: space.name.clone(),
+                    key: key.clone(),
+                    reason,
+                })?;
+            }
+        }
+        let seq = self.seq + 1;
+        self.write_at(seq, ops)
+    }
+
+    /// Logs `ops` as the commit numbered `seq`, then applies them. Without
+    /// any operations, an empty record is logged to the default keyspace,
+    /// so that the number is still known after a restart.
+    fn write_at(&mut self, seq: u64, ops: Vec<(KeyspaceId, Op)>) -> Result<()> {
+        let mut parts: BTreeMap<KeyspaceId, Vec<Op>> = BTreeMap::new();
+        for (keyspace, op) in ops {
+            parts.entry(keyspace).or_default().push(op);
+        }
+        if parts.is_empty() {
+            parts.insert(DEFAULT_ID, Vec::new());
+        }
+        for &keyspace in parts.keys() {
+            self.space(keyspace)?;
+        }
+
+        let _span = debug_span!("commit", seq, keyspaces = parts.len()).entered();
+        let logged = if parts.len() > 1 {
+            Some(self.batch_log.append(&Record {
+                seq,
+                ops: join_batch(&parts),
+            })?)
+        } else {
+            None
+        };
+        let mut written = Vec::new();
+        for (keyspace, ops) in parts {
+            let record = Record { seq, ops };
+            match self.spaces.get_mut(&keyspace).unwrap().append(&record) {
+                Ok((segment, offset)) => written.push((keyspace, segment, offset, record)),
+                Err(e) => {
+                    // Take back the parts already written, so that nothing of
+                    // the commit is left to replay.
+                    for (keyspace, _, offset, _) in &written {
+                        let _ = self.spaces.get_mut(keyspace).unwrap().undo(*offset);
+                    }
+                    if let Some(offset) = logged {
+                        let _ = self.batch_log.truncate(offset);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let event = self.replication.enabled().then(|| Event::Commit {
+            seq,
+            parts: written
+                .iter()
+                .map(|(keyspace, _, _, record)| (self.spaces[keyspace].name.clone(), record.ops.clone()))
+                .collect(),
+        });
+        let oldest = self.oldest();
+        for (keyspace, segment, offset, record) in written {
+            self.spaces.get_mut(&keyspace).unwrap().apply(segment, offset, record, oldest);
+        }
+        self.seq = seq;
+        if let Some(event) = event {
+            self.replication.push(event);
+        }
+        if logged.is_some() && self.batch_log.size() >= self.options.segment_size {
+            // Every commit in the batch log is complete by now.
+            for space in self.spaces.values() {
+                space.wal.sync()?;
+            }
+            self.batch_log.truncate(0)?;
+        }
+        Ok(())
+    }
+}
+
+impl Space {
+    /// Opens the keyspace whose segments are in `dir`, creating the
+    /// directory if needed, and replays its log.
+    fn open(dir: &Path, name: &str, options: &Options, cache: Option<Arc<BlockCache>>) -> Result<Space> {
+        fs::create_dir_all(dir)?;
+
+        let mut listing = list_segments(dir)?;
+        let legacy = dir.join(LEGACY_LOG_FILE);
+        if listing.live.is_empty() && legacy.exists() {
+            let path = segment_path(dir, 1, LOG_EXT);
+            fs::rename(&legacy, &path)?;
+            listing.live.push((1, false, path));
+        }
+        for path in &listing.obsolete {
+            debug!(path = %path.display(), "removing superseded segment");
+            fs::remove_file(path)?;
+        }
+
+        // Only an uncompacted last segment is appended to; otherwise a new
+        // one is started after it.
+        let (active, len) = match listing.live.last() {
+            Some((id, false, path)) => {
+                let mut reader = wal::Reader::open(path)?;
+                for entry in &mut reader {
+                    entry?;
+                }
+                if reader.torn() {
+                    warn!(path = %path.display(), offset = reader.valid_len(), "discarding torn record at the end of the log");
+                }
+                (*id, reader.valid_len())
+            }
+            Some((id, true, _)) => (id + 1, 0),
+            None => (1, 0),
+        };
+        let active_path = segment_path(dir, active, LOG_EXT);
+        let mut space = Space {
+            name: name.to_owned(),
+            dir: dir.to_owned(),
+            fsync: options.fsync,
+            segment_size: options.segment_size,
+            mmap: options.read.mode == ReadMode::Mmap,
+            cache: cache.clone(),
+            wal: Wal::open(&active_path, len, options.fsync)?,
+            active,
+            segments: BTreeMap::new(),
+            seq: 0,
+            index: BTreeMap::new(),
+            live_keys: 0,
+            stale: HashSet::new(),
+            table: None,
+            writing_table: false,
+            validator: None,
+        };
+
+        for (id, compacted, path) in listing.live {
+            let mut reader = wal::Reader::open(&path)?;
+            let file = DataFile::open(&path, space.mmap && id != active)?;
+            space.segments.insert(id, Segment { file, size: 0, garbage: 0 });
+            while let Some(entry) = reader.next() {
+                let (offset, record) = entry?;
+                // Blocks come first, so the table is whole before any commit
+                // after them is applied.
+                if record.seq == BLOCK_SEQ {
+                    if !compacted {
+                        return Err(Error::Corrupted {
+                            offset,
+                            reason: "table block outside a compacted segment",
+                        });
+                    }
+                    let len = reader.valid_len() - offset;
+                    space.live_keys += record.ops.len();
+                    space.table.get_or_insert_with(|| Table::new(id, cache.clone())).add_block(offset, len, &record);
+                } else {
+                    space.apply(id, offset, record, None);
+                }
+            }
+            if reader.torn() && id != active {
+                return Err(Error::Corrupted {
+                    offset: reader.valid_len(),
+                    reason: "torn record in a sealed segment",
+                });
+            }
+            space.segments.get_mut(&id).unwrap().size = reader.valid_len();
+        }
+        if let Entry::Vacant(entry) = space.segments.entry(active) {
+            let file = DataFile::open(&active_path, false)?;
+            entry.insert(Segment { file, size: 0, garbage: 0 });
+        }
+        if let Some(table) = &space.table {
+            debug!(keyspace = name, segment = table.segment(), keys = table.keys(), "loaded table");
+        }
+        Ok(space)
+    }
+
+    fn size(&self) -> u64 {
+        self.segments.values().map(|s| s.size).sum()
+    }
+
+    fn garbage(&self) -> u64 {
+        self.segments.values().map(|s| s.garbage).sum()
+    }
+
+    /// Whether compaction writes a table rather than a compacted segment.
+    fn writes_tables(&self) -> bool {
+        self.mmap || self.table.is_some()
+    }
+
+    /// Bytes of the log compaction would take out: the garbage, and, when it
+    /// writes a table, everything outside the current one, since that is
+    /// what it moves into the table.
+    fn reclaimable(&self) -> u64 {
+        if !self.writes_tables() {
+            return self.garbage();
+        }
+        let table = self.table.as_ref().map(Table::segment);
+        self.segments
+            .iter()
+            .map(|(&id, segment)| if Some(id) == table { segment.garbage } else { segment.size })
+            .sum()
+    }
+
+    /// Appends `record` to the active segment, returning the segment and the
+    /// record's offset in it. The record is not applied to the index.
+    fn append(&mut self, record: &Record) -> Result<(u64, u64)> {
+        // Rotating first means a failure here leaves nothing half-done.
+        if self.wal.size() >= self.segment_size {
+            self.rotate(self.active + 1)?;
+        }
+        let offset = self.wal.append(record)?;
+        let active = self.active;
+        self.segments.get_mut(&active).unwrap().size = self.wal.size();
+        Ok((active, offset))
+    }
+
+    /// Takes back the record just appended at `offset`.
+    fn undo(&mut self, offset: u64) -> Result<()> {
+        self.wal.truncate(offset)?;
+        let active = self.active;
+        self.segments.get_mut(&active).unwrap().size = offset;
+        Ok(())
+    }
+
+    /// Seals the active segment and starts appending to a new one, `id`.
+    fn rotate(&mut self, id: u64) -> Result<()> {
+        let path = segment_path(&self.dir, id, LOG_EXT);
+        let wal = Wal::open(&path, 0, self.fsync)?;
+        let file = DataFile::open(&path, false)?;
+        sync_dir(&self.dir)?;
+        // Dropping the old log syncs it.
+        self.wal = wal;
+        let sealed = std::mem::replace(&mut self.active, id);
+        self.segments.insert(id, Segment { file, size: 0, garbage: 0 });
+        debug!(keyspace = %self.name, segment = id, "started a new segment");
+        if self.mmap {
+            // Unmapped, the segment is still read with ordinary reads.
+            if let Err(e) = self.segments.get_mut(&sealed).unwrap().file.map() {
+                warn!(keyspace = %self.name, segment = sealed, error = %e, "cannot map a sealed segment");
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a commit logged at `offset` in `segment` to the index, with
+    /// `oldest` the oldest open snapshot.
+    fn apply(&mut self, segment: u64, offset: u64, record: Record, oldest: Option<u64>) {
+        let offsets = wal::value_offsets(offset, &record.ops);
+        for (op, value_offset) in record.ops.into_iter().zip(offsets) {
+            let (key, ptr) = match op {
+                Op::Put { key, value } => {
+                    let ptr = ValuePtr {
+                        segment,
+                        offset: value_offset,
+                        len: value.len() as u32,
+                        crc: value_crc(&key, &value),
+                    };
+                    (key, Some(ptr))
+                }
+                Op::Delete { key } => (key, None),
+            };
+
+            let was_live = match self.latest(&key) {
+                Some(version) => version.ptr.is_some(),
+                None => self.in_table(&key),
+            };
+            self.index.entry(key.clone()).or_default().push(Version { seq: record.seq, ptr });
+            match (was_live, ptr.is_some()) {
+                (false, true) => self.live_keys += 1,
+                (true, false) => self.live_keys -= 1,
+                _ => {}
+            }
+            self.prune(key, oldest);
+        }
+        self.seq = self.seq.max(record.seq);
+    }
+
+    /// Drops the versions of `key` that no snapshot at or after `oldest` can
+    /// observe, and tracks whether it still has any worth revisiting.
+    fn prune(&mut self, key: Vec<u8>, oldest: Option<u64>) {
+        let versions = match self.index.get_mut(&key) {
+            Some(versions) => versions,
+            None => return,
+        };
+        // Everything before the newest version visible to the oldest reader
+        // is unreachable; without readers only the latest version is.
+        let keep_from = match oldest {
+            Some(oldest) => versions.iter().rposition(|v| v.seq <= oldest).unwrap_or(0),
+            None => versions.len() - 1,
+        };
+        for version in versions.drain(..keep_from) {
+            if let Some(ptr) = version.ptr {
+                if let Some(segment) = self.segments.get_mut(&ptr.segment) {
+                    segment.garbage += op_len(&key, ptr);
+                }
+            }
+        }
+        // A delete that every reader can see reads the same as no entry,
+        // unless it hides a key in the table. Newer ones are kept so commits
+        // racing with them still conflict.
+        let settled = versions[0].ptr.is_none() && oldest.is_none_or(|oldest| versions[0].seq <= oldest);
+        if settled && self.table.is_none() && !self.writing_table {
+            versions.remove(0);
+        }
+
+        // Deletes kept for a table still being written are revisited, in
+        // case it never arrives.
+        if versions.is_empty() {
+            self.index.remove(&key);
+            self.stale.remove(&key);
+        } else if versions.len() == 1 && (versions[0].ptr.is_some() || settled && self.table.is_some()) {
+            self.stale.remove(&key);
+        } else {
+            self.stale.insert(key);
+        }
+    }
+
+    fn collect_stale(&mut self, oldest: Option<u64>) {
+        let keys: Vec<_> = self.stale.iter().cloned().collect();
+        for key in keys {
+            self.prune(key, oldest);
+        }
+    }
+
+    fn latest(&self, key: &[u8]) -> Option<&Version> {
+        self.index.get(key).and_then(|versions| versions.last())
+    }
+
+    fn get_at(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key).and_then(|versions| visible(versions, seq)) {
+            Some(Version { ptr: Some(ptr), .. }) => self.read_value(key, ptr).map(Some),
+            Some(Version { ptr: None, .. }) => Ok(None),
+            None => self.table_get(key),
+        }
+    }
+
+    /// Whether `key` is present in the latest state of the keyspace.
+    fn contains(&self, key: &[u8]) -> Result<bool> {
+        match self.latest(key) {
+            Some(version) => Ok(version.ptr.is_some()),
+            None => Ok(self.table_get(key)?.is_some()),
+        }
+    }
+
+    /// The value of `key` in the table, if there is one.
+    fn table_get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match &self.table {
+            Some(table) => table.get(&self.segments[&table.segment()].file, key),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the table holds `key`. This only keeps count of the live
+    /// keys, so a block that cannot be read counts as not holding it; a read
+    /// of the key reports the damage.
+    fn in_table(&self, key: &[u8]) -> bool {
+        match self.table_get(key) {
+            Ok(value) => value.is_some(),
+            Err(e) => {
+                warn!(keyspace = %self.name, error = %e, "cannot read a table block");
+                false
+            }
+        }
+    }
+
+    fn read_value(&self, key: &[u8], ptr: ValuePtr) -> Result<Vec<u8>> {
+        read_value(&self.segments[&ptr.segment].file, key, ptr)
+    }
+}
+
+impl<'db> Iter<'db> {
+    fn new(
+        db: &'db Db,
+        keyspace: KeyspaceId,
+        snapshot: u64,
+        owns_snapshot: bool,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    ) -> Iter<'db> {
+        // BTreeMap::range panics on inverted ranges, so those end here.
+        let done = is_empty_range(&start, &end);
+        Iter {
+            db,
+            keyspace,
+            snapshot,
+            owns_snapshot,
+            start,
+            end,
+            buffer: VecDeque::new(),
+            done,
+        }
+    }
+
+    /// Reads the next batch of visible entries into the buffer, merging the
+    /// index with the table.
+    fn fill(&mut self) -> Result<()> {
+        let inner = self.db.shared.inner.lock().unwrap();
+        let space = inner.space(self.keyspace)?;
+        let range = (as_slice(&self.start), as_slice(&self.end));
+        let mut index = space.index.range::<[u8], _>(range).peekable();
+        let mut table = space
+            .table
+            .as_ref()
+            .map(|table| table.scan(&space.segments[&table.segment()].file, range.0, range.1).peekable());
+        loop {
+            let table_next = table.as_mut().and_then(|table| table.peek());
+            // Take whichever side has the smaller key; on a tie the index
+            // shadows the table if it has a version the snapshot sees.
+            let order = match (index.peek(), table_next) {
+                (None, None) => break,
+                (_, Some(Err(_))) | (None, Some(_)) => cmp::Ordering::Greater,
+                (Some(_), None) => cmp::Ordering::Less,
+                (Some((key, _)), Some(Ok((table_key, _)))) => key.cmp(&table_key),
+            };
+            let (key, value) = if order == cmp::Ordering::Greater {
+                table.as_mut().unwrap().next().unwrap()?
+            } else {
+                let (key, versions) = index.next().unwrap();
+                match visible(versions, self.snapshot) {
+                    Some(version) => {
+                        if order == cmp::Ordering::Equal {
+                            table.as_mut().unwrap().next();
+                        }
+                        match version.ptr {
+                            Some(ptr) => (key.clone(), space.read_value(key, ptr)?),
+                            None => continue,
+                        }
+                    }
+                    // Only written after the snapshot, so the table's entry
+                    // for the key, if any, comes next.
+                    None => continue,
+                }
+            };
+            self.buffer.push_back((key, value));
+            if self.buffer.len() == SCAN_BATCH {
+                self.start = Bound::Excluded(self.buffer.back().unwrap().0.clone());
+                return Ok(());
+            }
+        }
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        if self.buffer.is_empty() && !self.done {
+            if let Err(e) = self.fill() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+impl Drop for Iter<'_> {
+    fn drop(&mut self) {
+        if self.owns_snapshot {
+            self.db.release(self.snapshot);
+        }
+    }
+}
+
+/// Copies the bounds of `range` into owned keys.
+pub(crate) fn owned_bounds<K: AsRef<[u8]> + ?Sized, R: RangeBounds<K>>(range: &R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let own = |bound: Bound<&K>| bound.map(|key| key.as_ref().to_vec());
+    (own(range.start_bound()), own(range.end_bound()))
+}
+
+/// Whether no key can fall between `start` and `end`.
+pub(crate) fn is_empty_range(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => s >= e,
+        _ => false,
+    }
+}
+
+/// The exclusive upper bound of the keys starting with `prefix`: the
+/// prefix with its last byte below 0xff incremented and the rest dropped.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+fn as_slice(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    bound.as_ref().map(Vec::as_slice)
+}
+
+/// Lists the segments of every keyspace of the database in `dir`, each
+/// keyspace's in the order they are replayed, leaving out any superseded by
+/// a compacted segment.
+pub fn segment_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for id in Catalog::load(dir)?.ids() {
+        let space_dir = keyspace::keyspace_dir(dir, id);
+        if space_dir.exists() {
+            files.extend(list_segments(&space_dir)?.live.into_iter().map(|(_, _, path)| path));
+        }
+    }
+    Ok(files)
+}
+
+/// A log file of a database, as [`crate::verify`] checks it.
+pub(crate) struct LogFile {
+    /// Path within the database directory, with `/` between components.
+    pub name: String,
+    pub path: PathBuf,
+    /// Whether the file is still appended to, so that a torn record at its
+    /// end is an interrupted write rather than damage.
+    pub active: bool,
+}
+
+/// Every log file of the database in `dir`, whose keyspaces are in
+/// `catalog`: the live segments of each keyspace, in the order they are
+/// replayed, then the batch log.
+pub(crate) fn log_files(dir: &Path, catalog: &Catalog) -> Result<Vec<LogFile>> {
+    let mut files = Vec::new();
+    let mut add = |path: PathBuf, active: bool| {
+        files.push(LogFile {
+            name: relative_name(dir, &path),
+            path,
+            active,
+        })
+    };
+    for id in catalog.ids() {
+        let space_dir = keyspace::keyspace_dir(dir, id);
+        if !space_dir.exists() {
+            continue;
+        }
+        let live = list_segments(&space_dir)?.live;
+        let legacy = space_dir.join(LEGACY_LOG_FILE);
+        if live.is_empty() && legacy.exists() {
+            add(legacy, true);
+        }
+        let last = live.len().saturating_sub(1);
+        for (i, (_, compacted, path)) in live.into_iter().enumerate() {
+            add(path, i == last && !compacted);
+        }
+    }
+    let batch_path = dir.join(BATCH_LOG_FILE);
+    if batch_path.exists() {
+        add(batch_path, true);
+    }
+    Ok(files)
+}
+
+fn list_segments(dir: &Path) -> Result<Listing> {
+    let mut segments = BTreeMap::new();
+    let mut obsolete = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let (id, ext) = match path.file_name().and_then(|name| name.to_str()).and_then(|name| name.split_once('.')) {
+            Some((id, ext)) => match id.parse::<u64>() {
+                Ok(id) => (id, ext.to_owned()),
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        if ext == LOG_EXT || ext == COMPACT_EXT {
+            segments.insert(id, (ext == COMPACT_EXT, path));
+        } else if ext.ends_with(".tmp") {
+            obsolete.push(path);
+        }
+    }
+
+    if let Some(base) = segments.iter().rev().find(|(_, (compacted, _))| *compacted).map(|(&id, _)| id) {
+        let rest = segments.split_off(&base);
+        obsolete.extend(segments.into_values().map(|(_, path)| path));
+        segments = rest;
+    }
+    Ok(Listing {
+        live: segments.into_iter().map(|(id, (compacted, path))| (id, compacted, path)).collect(),
+        obsolete,
+    })
+}
+
+fn segment_path(dir: &Path, id: u64, ext: &str) -> PathBuf {
+    dir.join(format!("{:010}.{}", id, ext))
+}
+
+/// The path of `path` inside the database directory `dir`, with `/`
+/// between components, as backups name their files.
+fn relative_name(dir: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(dir).unwrap_or(path);
+    let parts: Vec<_> = relative
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    parts.join("/")
+}
+
+/// Bytes of blocks the block cache may hold: as many as configured, but no
+/// more than the memory limit leaves over after the replication backlog.
+fn cache_budget(options: &Options) -> u64 {
+    match options.max_memory {
+        0 => options.read.block_cache,
+        max => options.read.block_cache.min(max.saturating_sub(options.replication.backlog_size)),
+    }
+}
+
+/// Makes file creations, renames and deletions in `dir` durable.
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Reads the value of `key` at `ptr` from `file` and checks it against the
+/// checksum in `ptr`.
+fn read_value(file: &DataFile, key: &[u8], ptr: ValuePtr) -> Result<Vec<u8>> {
+    let value = file.read(ptr.offset, u64::from(ptr.len))?;
+    if value_crc(key, &value) != ptr.crc {
+        return Err(Error::CorruptedBlock {
+            path: file.path().to_owned(),
+            offset: ptr.offset,
+            len: u64::from(ptr.len),
+        });
+    }
+    Ok(value.into_owned())
+}
+
+fn value_crc(key: &[u8], value: &[u8]) -> u32 {
+    crc32c::crc32c_append(crc32c::crc32c(key), value)
+}
+
+/// Bytes taken up in the log by the put of `key` whose value is at `ptr`.
+fn op_len(key: &[u8], ptr: ValuePtr) -> u64 {
+    wal::OP_HEADER_LEN + key.len() as u64 + u64::from(ptr.len)
+}
+
+/// The version visible to a reader at `seq`, unless the index holds none
+/// as old. Its location is `None` if the key was deleted.
+fn visible(versions: &[Version], seq: u64) -> Option<Version> {
+    versions.iter().rev().find(|v| v.seq <= seq).copied()
+}
+
+/// Takes an exclusive lock on the database directory `dir`, held for as
+/// long as the returned file is open.
+fn lock_dir(dir: &Path) -> Result<File> {
+    let file = OpenOptions::new().write(true).create(true).truncate(false).open(dir.join(LOCK_FILE))?;
+    // SAFETY: `flock` only acts on the descriptor, which `file` keeps open.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == ErrorKind::WouldBlock {
+            let message = format!("{} is locked: the database is already open", dir.display());
+            return Err(io::Error::new(ErrorKind::WouldBlock, message).into());
+        }
+        return Err(e.into());
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Options for a database that only compacts when asked to.
+    pub(crate) fn options() -> Options {
+        Options {
+            fsync: FsyncPolicy::Never,
+            compaction: CompactionOptions {
+                interval: Duration::from_secs(0),
+                ..CompactionOptions::default()
+            },
+            ..Options::default()
+        }
+    }
+
+    /// Every key-value pair in `keyspace`.
+    pub(crate) fn contents(keyspace: &Keyspace) -> Vec<(Vec<u8>, Vec<u8>)> {
+        keyspace.scan::<[u8], _>(..).collect::<Result<_>>().unwrap()
+    }
+
+    pub(crate) fn pairs(pairs: &[(&str, &str)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        pairs.iter().map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec())).collect()
+    }
+
+    /// The segment a new database appends to.
+    pub(crate) fn first_segment(dir: &Path) -> PathBuf {
+        segment_path(dir, 1, LOG_EXT)
+    }
+
+    fn flip_byte(path: &Path, offset: u64) {
+        let mut bytes = fs::read(path).unwrap();
+        bytes[offset as usize] ^= 0xff;
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn reopen_replays_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Db::open(dir.path(), options()).unwrap();
+            let ks = db.default_keyspace();
+            ks.put(b"a", b"1").unwrap();
+            ks.put(b"b", b"2").unwrap();
+            ks.put(b"a", b"3").unwrap();
+            assert!(ks.delete(b"b").unwrap());
+            assert!(!ks.delete(b"b").unwrap());
+            ks.put(b"c", b"").unwrap();
+        }
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        assert_eq!(contents(&ks), pairs(&[("a", "3"), ("c", "")]));
+        assert_eq!(ks.get(b"b").unwrap(), None);
+        let stats = ks.stats().unwrap();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.last_seq, 5);
+    }
+
+    #[test]
+    fn reopen_replays_every_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            segment_size: 64,
+            ..options()
+        };
+        {
+            let db = Db::open(dir.path(), options.clone()).unwrap();
+            let ks = db.default_keyspace();
+            for i in 0..20 {
+                ks.put(format!("key{:02}", i).as_bytes(), b"value").unwrap();
+            }
+            assert!(ks.stats().unwrap().segments > 1);
+        }
+        let db = Db::open(dir.path(), options).unwrap();
+        let ks = db.default_keyspace();
+        assert_eq!(contents(&ks).len(), 20);
+        assert_eq!(ks.get(b"key19").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn legacy_log_is_adopted_as_the_first_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Db::open(dir.path(), options()).unwrap();
+            let ks = db.default_keyspace();
+            ks.put(b"a", b"1").unwrap();
+        }
+        fs::rename(segment_path(dir.path(), 1, LOG_EXT), dir.path().join(LEGACY_LOG_FILE)).unwrap();
+
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        assert_eq!(ks.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert!(!dir.path().join(LEGACY_LOG_FILE).exists());
+        assert!(segment_path(dir.path(), 1, LOG_EXT).exists());
+    }
+
+    #[test]
+    fn open_locks_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        match Db::open(dir.path(), options()) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+            other => panic!("expected the lock to be held, got {:?}", other.err()),
+        }
+        drop(db);
+        Db::open(dir.path(), options()).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_drops_the_last_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Db::open(dir.path(), options()).unwrap();
+            let ks = db.default_keyspace();
+            ks.put(b"a", b"1").unwrap();
+            ks.put(b"b", b"2").unwrap();
+        }
+        let log = segment_path(dir.path(), 1, LOG_EXT);
+        let len = fs::metadata(&log).unwrap().len();
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(len - 3).unwrap();
+
+        {
+            let db = Db::open(dir.path(), options()).unwrap();
+            let ks = db.default_keyspace();
+            assert_eq!(contents(&ks), pairs(&[("a", "1")]));
+            // The torn record is cut off, so new commits follow the last
+            // whole one.
+            ks.put(b"c", b"3").unwrap();
+        }
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        assert_eq!(contents(&ks), pairs(&[("a", "1"), ("c", "3")]));
+    }
+
+    #[test]
+    fn corrupted_record_fails_open() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Db::open(dir.path(), options()).unwrap();
+            let ks = db.default_keyspace();
+            ks.put(b"a", b"1").unwrap();
+            ks.put(b"b", b"2").unwrap();
+        }
+        // The value of the first record.
+        let log = segment_path(dir.path(), 1, LOG_EXT);
+        let offset = wal::value_offsets(0, &[Op::Put {
+            key: b"a".to_vec(),
+            value: b"1".to_vec(),
+        }])[0];
+        flip_byte(&log, offset);
+
+        match Db::open(dir.path(), options()) {
+            Err(Error::Corrupted { offset, reason }) => {
+                assert_eq!(offset, 0);
+                assert_eq!(reason, "checksum mismatch");
+            }
+            other => panic!("expected corruption, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn damaged_value_fails_its_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        ks.put(b"a", b"hello").unwrap();
+        ks.put(b"b", b"world").unwrap();
+
+        let log = segment_path(dir.path(), 1, LOG_EXT);
+        let offset = wal::value_offsets(0, &[Op::Put {
+            key: b"a".to_vec(),
+            value: b"hello".to_vec(),
+        }])[0];
+        flip_byte(&log, offset);
+
+        match ks.get(b"a") {
+            Err(Error::CorruptedBlock { path, offset: at, len }) => {
+                assert_eq!(path, log);
+                assert_eq!((at, len), (offset, 5));
+            }
+            other => panic!("expected a corrupted block, got {:?}", other),
+        }
+        assert_eq!(ks.get(b"b").unwrap(), Some(b"world".to_vec()));
+    }
+
+    #[test]
+    fn keyspaces_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Db::open(dir.path(), options()).unwrap();
+            db.create_keyspace("users").unwrap().put(b"alice", b"1").unwrap();
+            db.create_keyspace("sessions").unwrap().put(b"s1", b"2").unwrap();
+            db.create_keyspace("scratch").unwrap().put(b"tmp", b"3").unwrap();
+            assert!(matches!(db.create_keyspace("users"), Err(Error::KeyspaceExists(_))));
+            db.drop_keyspace("scratch").unwrap();
+            assert!(matches!(db.drop_keyspace(DEFAULT_KEYSPACE), Err(Error::DropDefaultKeyspace)));
+            assert!(matches!(db.keyspace("scratch"), Err(Error::NoSuchKeyspace(_))));
+        }
+        // The directory of a keyspace whose drop was interrupted.
+        let orphan = keyspace::keyspace_dir(dir.path(), 7);
+        fs::create_dir_all(&orphan).unwrap();
+
+        let db = Db::open(dir.path(), options()).unwrap();
+        assert!(!orphan.exists());
+        let names: Vec<_> = db.keyspaces().iter().map(|ks| ks.name().to_owned()).collect();
+        assert_eq!(names, vec![DEFAULT_KEYSPACE, "users", "sessions"]);
+        assert_eq!(contents(&db.keyspace("users").unwrap()), pairs(&[("alice", "1")]));
+        assert_eq!(contents(&db.keyspace("sessions").unwrap()), pairs(&[("s1", "2")]));
+
+        // A keyspace created under a dropped one's name starts out empty.
+        let scratch = db.create_keyspace("scratch").unwrap();
+        assert_eq!(contents(&scratch), pairs(&[]));
+        assert_eq!(scratch.id(), 4);
+    }
+
+    #[test]
+    fn validators_reject_whole_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        let counts = db.create_keyspace("counts").unwrap();
+        counts
+            .set_validator(|_, value| match std::str::from_utf8(value).ok().and_then(|v| v.parse::<u64>().ok()) {
+                Some(_) => Ok(()),
+                None => Err("not a count".to_owned()),
+            })
+            .unwrap();
+        counts.put(b"a", b"1").unwrap();
+        let rejected = counts.put(b"b", b"many");
+        assert!(matches!(&rejected, Err(Error::Rejected { keyspace, key, .. }) if keyspace == "counts" && key == b"b"));
+        assert_eq!(rejected.unwrap_err().to_string(), "keyspace 'counts' rejected \"b\": not a count");
+
+        // Nothing of a batch or transaction with one bad value is written.
+        let mut batch = WriteBatch::new();
+        batch.put(&ks, b"x", b"anything");
+        batch.put(&counts, b"c", b"2");
+        batch.put(&counts, b"d", b"");
+        assert!(matches!(db.write(batch), Err(Error::Rejected { .. })));
+        let mut txn = db.begin();
+        txn.put(&counts, b"e", b"three");
+        assert!(matches!(txn.commit(), Err(Error::Rejected { .. })));
+        assert_eq!(contents(&ks), pairs(&[]));
+        assert_eq!(contents(&counts), pairs(&[("a", "1")]));
+        assert_eq!(ks.stats().unwrap().last_seq, 1);
+
+        // Other keyspaces and deletes are not checked.
+        ks.put(b"x", b"anything").unwrap();
+        assert!(counts.delete(b"a").unwrap());
+        counts.clear_validator().unwrap();
+        counts.put(b"b", b"many").unwrap();
+        assert_eq!(contents(&counts), pairs(&[("b", "many")]));
+    }
+
+    #[test]
+    fn batch_parts_round_trip() {
+        let mut parts = BTreeMap::new();
+        parts.insert(DEFAULT_ID, vec![Op::Delete { key: b"a".to_vec() }]);
+        parts.insert(
+            0x0102_0304,
+            vec![
+                Op::Put {
+                    key: Vec::new(),
+                    value: b"1".to_vec(),
+                },
+                Op::Put {
+                    key: b"b".to_vec(),
+                    value: b"2".to_vec(),
+                },
+            ],
+        );
+        let joined = join_batch(&parts);
+        assert_eq!(joined[1].key(), &[1, 2, 3, 4]);
+        assert_eq!(split_batch(0, joined).unwrap(), parts);
+
+        match split_batch(9, vec![Op::Delete { key: vec![0, 0, 1] }]) {
+            Err(Error::Corrupted { offset, reason }) => {
+                assert_eq!(offset, 9);
+                assert_eq!(reason, "batch log key without a keyspace");
+            }
+            other => panic!("expected corruption, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn redo_completes_an_interrupted_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let db = Db::open(dir.path(), options()).unwrap();
+            db.default_keyspace().put(b"a", b"1").unwrap();
+            db.create_keyspace("other").unwrap().put(b"x", b"1").unwrap();
+        }
+        let other_log = segment_path(&keyspace::keyspace_dir(dir.path(), 1), 1, LOG_EXT);
+        let before = fs::read(&other_log).unwrap();
+        {
+            let db = Db::open(dir.path(), options()).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(&db.default_keyspace(), b"b", b"2");
+            batch.put(&db.keyspace("other").unwrap(), b"y", b"2");
+            batch.delete(&db.keyspace("other").unwrap(), b"x");
+            db.write(batch).unwrap();
+        }
+        // A crash after the default keyspace's part was written, but before
+        // the other's.
+        fs::write(&other_log, &before).unwrap();
+
+        {
+            let db = Db::open(dir.path(), options()).unwrap();
+            let default = db.default_keyspace();
+            let other = db.keyspace("other").unwrap();
+            assert_eq!(contents(&default), pairs(&[("a", "1"), ("b", "2")]));
+            assert_eq!(contents(&other), pairs(&[("y", "2")]));
+            assert_eq!(other.stats().unwrap().last_seq, 3);
+            // The part is in the keyspace's own log now, and the batch log
+            // has been emptied.
+            assert_eq!(fs::metadata(dir.path().join(BATCH_LOG_FILE)).unwrap().len(), 0);
+            assert!(fs::metadata(&other_log).unwrap().len() > before.len() as u64);
+            other.put(b"z", b"3").unwrap();
+        }
+        let db = Db::open(dir.path(), options()).unwrap();
+        assert_eq!(contents(&db.keyspace("other").unwrap()), pairs(&[("y", "2"), ("z", "3")]));
+        assert_eq!(db.default_keyspace().stats().unwrap().last_seq, 4);
+    }
+
+    /// Paths of every segment file in `dir`, compacted or not.
+    fn segment_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_stem().and_then(|stem| stem.to_str()).is_some_and(|stem| stem.parse::<u64>().is_ok()))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn compaction_drops_overwritten_and_deleted_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            segment_size: 256,
+            ..options()
+        };
+        let expected = {
+            let db = Db::open(dir.path(), options.clone()).unwrap();
+            let ks = db.default_keyspace();
+            for round in 0..5 {
+                for i in 0..20 {
+                    ks.put(format!("key{:02}", i).as_bytes(), format!("value{}", round).as_bytes()).unwrap();
+                }
+            }
+            for i in (0..20).step_by(2) {
+                ks.delete(format!("key{:02}", i).as_bytes()).unwrap();
+            }
+            let before = ks.stats().unwrap();
+            let old = segment_files(dir.path());
+            assert!(before.garbage_bytes > 0);
+
+            let stats = ks.compact().unwrap();
+            assert_eq!(stats.segments, old.len());
+            assert_eq!(stats.bytes_before, before.log_bytes);
+            assert!(stats.bytes_after < stats.bytes_before / 4, "{:?}", stats);
+            for path in &old {
+                assert!(!path.exists(), "{} was not removed", path.display());
+            }
+            // The compacted segment, and the active one after it.
+            assert_eq!(segment_files(dir.path()).len(), 2);
+            let after = ks.stats().unwrap();
+            assert_eq!(after.garbage_bytes, 0);
+            assert_eq!(after.keys, 10);
+            contents(&ks)
+        };
+        assert_eq!(expected.len(), 10);
+        assert!(expected.iter().all(|(_, value)| value == b"value4"));
+
+        let db = Db::open(dir.path(), options).unwrap();
+        let ks = db.default_keyspace();
+        assert_eq!(contents(&ks), expected);
+        assert_eq!(ks.stats().unwrap().last_seq, 110);
+        ks.put(b"key00", b"again").unwrap();
+        assert_eq!(ks.get(b"key00").unwrap(), Some(b"again".to_vec()));
+    }
+
+    #[test]
+    fn compactor_stops_when_the_db_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            compaction: CompactionOptions {
+                interval: Duration::from_secs(3600),
+                ..CompactionOptions::default()
+            },
+            ..options()
+        };
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+        let ks = db.default_keyspace();
+        ks.put(b"a", b"1").unwrap();
+
+        let start = std::time::Instant::now();
+        drop(db);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // The lock went with it.
+        Db::open(dir.path(), options).unwrap();
+    }
+
+    fn scan<'a>(keyspace: &Keyspace, range: (Bound<&'a [u8]>, Bound<&'a [u8]>)) -> Vec<(Vec<u8>, Vec<u8>)> {
+        keyspace.scan::<[u8], _>(range).collect::<Result<_>>().unwrap()
+    }
+
+    fn keys(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<Vec<u8>> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn scans_honour_their_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        for key in &["a", "b", "c", "d"] {
+            ks.put(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+
+        let b = &b"b"[..];
+        let d = &b"d"[..];
+        assert_eq!(scan(&ks, (Bound::Included(b), Bound::Excluded(d))), pairs(&[("b", "b"), ("c", "c")]));
+        assert_eq!(scan(&ks, (Bound::Excluded(b), Bound::Included(d))), pairs(&[("c", "c"), ("d", "d")]));
+        assert_eq!(scan(&ks, (Bound::Unbounded, Bound::Excluded(b))), pairs(&[("a", "a")]));
+        assert_eq!(contents(&ks).len(), 4);
+
+        // Empty and reversed ranges yield nothing rather than panicking.
+        for range in &[
+            (Bound::Included(b), Bound::Excluded(b)),
+            (Bound::Excluded(b), Bound::Excluded(b)),
+            (Bound::Excluded(b), Bound::Included(b)),
+            (Bound::Included(d), Bound::Included(b)),
+            (Bound::Excluded(d), Bound::Unbounded),
+        ] {
+            assert_eq!(scan(&ks, *range), pairs(&[]), "{:?}", range);
+        }
+        assert_eq!(scan(&ks, (Bound::Included(b), Bound::Included(b))), pairs(&[("b", "b")]));
+    }
+
+    #[test]
+    fn prefix_scans_handle_0xff() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        let all: &[&[u8]] = &[b"a", b"a\xff", b"a\xff\x00", b"a\xff\xff", b"b", b"\xff", b"\xff\xff", b"\xff\xff\x01"];
+        for key in all {
+            ks.put(key, b"").unwrap();
+        }
+        let prefix = |prefix: &[u8]| keys(ks.scan_prefix(prefix).collect::<Result<_>>().unwrap());
+
+        // Ending in 0xff, so the end bound carries into the byte before it.
+        assert_eq!(prefix(b"a\xff"), vec![b"a\xff".to_vec(), b"a\xff\x00".to_vec(), b"a\xff\xff".to_vec()]);
+        // All 0xff, so there is no end bound at all.
+        assert_eq!(prefix(b"\xff"), vec![b"\xff".to_vec(), b"\xff\xff".to_vec(), b"\xff\xff\x01".to_vec()]);
+        assert_eq!(prefix(b"\xff\xff"), vec![b"\xff\xff".to_vec(), b"\xff\xff\x01".to_vec()]);
+        assert_eq!(prefix(b"a").len(), 4);
+        assert_eq!(prefix(b"").len(), all.len());
+        assert!(prefix(b"c").is_empty());
+    }
+
+    #[test]
+    fn scans_stream_in_batches_from_a_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        let total = SCAN_BATCH * 2 + 10;
+        for i in 0..total {
+            ks.put(format!("key{:04}", i).as_bytes(), b"old").unwrap();
+        }
+        // Deleted on both sides of the first batch boundary.
+        for i in SCAN_BATCH - 2..SCAN_BATCH + 3 {
+            assert!(ks.delete(format!("key{:04}", i).as_bytes()).unwrap());
+        }
+
+        let mut iter = ks.scan::<[u8], _>(..);
+        let mut seen = Vec::new();
+        for _ in 0..SCAN_BATCH {
+            seen.push(iter.next().unwrap().unwrap());
+        }
+        // Between batches: none of this is visible to the scan already
+        // under way.
+        for i in 0..total {
+            let key = format!("key{:04}", i);
+            if i % 2 == 0 {
+                ks.put(key.as_bytes(), b"new").unwrap();
+            } else {
+                ks.delete(key.as_bytes()).unwrap();
+            }
+        }
+        ks.put(b"key9999", b"new").unwrap();
+        seen.extend(iter.map(Result::unwrap));
+
+        let expected: Vec<_> = (0..total)
+            .filter(|i| !(SCAN_BATCH - 2..SCAN_BATCH + 3).contains(i))
+            .map(|i| format!("key{:04}", i).into_bytes())
+            .collect();
+        assert!(seen.iter().all(|(_, value)| value == b"old"));
+        assert_eq!(keys(seen), expected);
+        // The snapshot went with the iterator.
+        assert_eq!(ks.stats().unwrap().open_snapshots, 0);
+        assert_eq!(contents(&ks).len(), total / 2 + 1);
+    }
+
+    #[test]
+    fn backup_runs_alongside_writes_and_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = tempfile::tempdir().unwrap();
+        let options = Options {
+            segment_size: 256,
+            ..options()
+        };
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+        let ks = db.default_keyspace();
+        // Write i is the commit numbered i + 1, overwriting one of 50 keys so
+        // compaction has garbage to drop.
+        let write = |i: u64| ks.put(format!("key{:02}", i % 50).as_bytes(), i.to_string().as_bytes()).unwrap();
+        for i in 0..500 {
+            write(i);
+        }
+
+        let done = AtomicBool::new(false);
+        let manifests = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut i = 500;
+                while !done.load(Ordering::Relaxed) {
+                    write(i);
+                    i += 1;
+                }
+            });
+            scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    ks.compact().unwrap();
+                }
+            });
+            let manifests: Result<Vec<_>> = (0..10).map(|n| db.backup(&backups.path().join(n.to_string()))).collect();
+            done.store(true, Ordering::Relaxed);
+            manifests
+        });
+        let manifests = manifests.unwrap();
+
+        for (n, manifest) in manifests.iter().enumerate() {
+            let restored = tempfile::tempdir().unwrap();
+            backup::restore(&backups.path().join(n.to_string()), restored.path()).unwrap();
+            let db = Db::open(restored.path(), options.clone()).unwrap();
+            let ks = db.default_keyspace();
+            assert_eq!(ks.stats().unwrap().last_seq, manifest.seq);
+            let expected: BTreeMap<Vec<u8>, Vec<u8>> = (0..manifest.seq)
+                .map(|i| (format!("key{:02}", i % 50).into_bytes(), i.to_string().into_bytes()))
+                .collect();
+            assert_eq!(contents(&ks), expected.into_iter().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn memory_limit_bounds_the_block_cache() {
+        let mut options = options();
+        options.read.block_cache = 8 << 20;
+        options.replication.backlog_size = 16 << 20;
+        assert_eq!(cache_budget(&options), 8 << 20);
+        options.max_memory = 64 << 20;
+        assert_eq!(cache_budget(&options), 8 << 20);
+        options.max_memory = 20 << 20;
+        assert_eq!(cache_budget(&options), 4 << 20);
+        options.max_memory = 16 << 20;
+        assert_eq!(cache_budget(&options), 0);
+        options.max_memory = 1;
+        assert_eq!(cache_budget(&options), 0);
+    }
+}
@@ -0,0 +1,815 @@
+//! Sorted tables and the memory-mapped read path (`read.mode = "mmap"`).
+//!
+//! By default the index of a keyspace holds every key, which stops scaling
+//! once there are more keys than memory. In mmap mode compaction writes a
+//! sorted table instead of a compacted segment, and the index only keeps
+//! the keys written since.
+//!
+//! A table is a segment file like any other, so it is replayed, verified,
+//! backed up and salvaged the same way. It starts with blocks: records
+//! numbered [`BLOCK_SEQ`], which no commit has, each holding the values of
+//! a run of keys in ascending order, about `read.block_size` bytes of them.
+//! Only the latest value of each key that every reader can see goes into a
+//! block; versions newer than that, kept for transactions still open,
+//! follow the blocks as ordinary commit records. A `Table` is the sparse
+//! index over the blocks: the first key and location of each, one entry per
+//! block. A lookup searches it for the one block that can hold the key,
+//! then decodes the block, checking its checksum, and searches that.
+//!
+//! Decoded blocks can be kept in a `BlockCache` shared by every keyspace,
+//! bounded by `read.block_cache` bytes, so that hot blocks are not decoded
+//! again on every read.
+//!
+//! In mmap mode every sealed segment, tables included, is also mapped into
+//! memory, so reading a value or a block copies it straight out of the page
+//! cache rather than going through a system call. The active segment is
+//! still growing and is read with ordinary reads.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Bound;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::vec;
+
+use crate::error::{Error, Result};
+use crate::wal::{self, Op, Reader, Record, Wal};
+
+/// Sequence number of table blocks. Commits are numbered from 1.
+pub const BLOCK_SEQ: u64 = 0;
+
+/// The `[read]` settings: how values are read, and what compaction writes
+/// for them.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadOptions {
+    pub mode: ReadMode,
+    /// Bytes of keys and values per table block.
+    pub block_size: u64,
+    /// Bytes of decoded blocks kept in memory; 0 disables the cache.
+    pub block_cache: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Every key in the index, and values read with ordinary reads.
+    Buffered,
+    /// Compaction writes sorted tables, and sealed segments are mapped into
+    /// memory.
+    Mmap,
+}
+
+/// A read-only mapping of a whole file.
+pub(crate) struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// SAFETY: the mapping is `PROT_READ` and only sealed segments, which are
+// never written again, are mapped, so no thread can see its bytes change.
+// The pointer is only read through `as_slice`, whose borrows are tied to
+// `self`, and it is unmapped only on drop, once none of them is left. That
+// makes moving or sharing it between threads as sound as for a `&[u8]`.
+unsafe impl Send for Mmap {}
+// SAFETY: as for `Send`; nothing in `Mmap` is mutated through `&self`.
+unsafe impl Sync for Mmap {}
+
+/// A segment file opened for reading, through a mapping if it has one.
+pub(crate) struct DataFile {
+    path: PathBuf,
+    file: File,
+    map: Option<Mmap>,
+}
+
+/// Where a block is, and the first key in it.
+struct BlockHandle {
+    first_key: Vec<u8>,
+    offset: u64,
+    len: u64,
+}
+
+/// The entries of a decoded block, in key order.
+type Block = Arc<Vec<(Vec<u8>, Vec<u8>)>>;
+
+/// The sparse index of a table.
+pub(crate) struct Table {
+    /// The segment the table is stored in.
+    segment: u64,
+    blocks: Vec<BlockHandle>,
+    keys: usize,
+    cache: Option<Arc<BlockCache>>,
+    /// Tells this table's blocks apart from others' in the cache.
+    cache_id: u64,
+}
+
+/// Lays out the blocks of a new table.
+pub(crate) struct TableWriter {
+    block_size: u64,
+    ops: Vec<Op>,
+    bytes: u64,
+    blocks: Vec<BlockHandle>,
+    keys: usize,
+}
+
+/// Iterator over the entries of a table in a key range.
+pub(crate) struct TableIter<'a> {
+    table: &'a Table,
+    file: &'a DataFile,
+    end: Bound<Vec<u8>>,
+    /// The block being read, the position in it, and the next block.
+    block: Option<Block>,
+    pos: usize,
+    next_block: usize,
+    done: bool,
+}
+
+/// Every entry of the table at `path`, read straight through, as
+/// compaction merges it into the next one.
+pub(crate) struct Entries {
+    reader: Reader<File>,
+    ops: vec::IntoIter<Op>,
+    done: bool,
+}
+
+/// Decoded blocks, least recently used first out.
+pub(crate) struct BlockCache {
+    budget: u64,
+    next_id: AtomicU64,
+    state: Mutex<CacheState>,
+}
+
+/// What the block cache has done since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups that found their block in the cache.
+    pub hits: u64,
+    /// Lookups that had to read and decode their block.
+    pub misses: u64,
+    /// Blocks dropped to make room for others.
+    pub evictions: u64,
+    /// Bytes of blocks held now.
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    used: u64,
+    tick: u64,
+    /// By table and offset: the block, its size, and when it was last used.
+    blocks: HashMap<(u64, u64), (Block, u64, u64)>,
+    by_use: BTreeMap<u64, (u64, u64)>,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions {
+            mode: ReadMode::Buffered,
+            block_size: 4 << 10,
+            block_cache: 0,
+        }
+    }
+}
+
+impl Mmap {
+    /// Maps the first `len` bytes of `file`, which must be more than zero.
+    fn map(file: &File, len: usize) -> io::Result<Mmap> {
+        // SAFETY: a fresh read-only shared mapping aliases nothing. Only
+        // sealed segments are mapped, and they are never written again.
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr, len })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: the mapping is `len` bytes long and lives as long as `self`.
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: the mapping came from `mmap` and no slice of it outlives
+        // `self`.
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+impl DataFile {
+    /// Opens the file at `path`, mapping it into memory as it is now with
+    /// `map`.
+    pub fn open(path: &Path, map: bool) -> Result<DataFile> {
+        let mut file = DataFile {
+            path: path.to_owned(),
+            file: File::open(path)?,
+            map: None,
+        };
+        if map {
+            file.map()?;
+        }
+        Ok(file)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Maps the file into memory, once it will not grow any more.
+    pub fn map(&mut self) -> Result<()> {
+        let len = self.file.metadata()?.len() as usize;
+        // An empty file cannot be mapped, and has nothing to read anyway.
+        self.map = if len > 0 { Some(Mmap::map(&self.file, len)?) } else { None };
+        Ok(())
+    }
+
+    /// The `len` bytes at `offset`.
+    pub fn read(&self, offset: u64, len: u64) -> Result<Cow<'_, [u8]>> {
+        let range = offset as usize..(offset + len) as usize;
+        if let Some(bytes) = self.map.as_ref().and_then(|map| map.as_slice().get(range)) {
+            return Ok(Cow::Borrowed(bytes));
+        }
+        let mut buf = vec![0; len as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        Ok(Cow::Owned(buf))
+    }
+}
+
+impl Table {
+    /// An empty table in `segment`, its blocks to be added as the segment
+    /// is replayed.
+    pub fn new(segment: u64, cache: Option<Arc<BlockCache>>) -> Table {
+        let cache_id = cache.as_ref().map_or(0, |cache| cache.next_id.fetch_add(1, Ordering::Relaxed));
+        Table {
+            segment,
+            blocks: Vec::new(),
+            keys: 0,
+            cache,
+            cache_id,
+        }
+    }
+
+    pub fn segment(&self) -> u64 {
+        self.segment
+    }
+
+    /// Number of keys in the table.
+    pub fn keys(&self) -> usize {
+        self.keys
+    }
+
+    /// Adds `block`, a record `len` bytes long at `offset` in the segment.
+    pub fn add_block(&mut self, offset: u64, len: u64, block: &Record) {
+        if let Some(first) = block.ops.first() {
+            self.blocks.push(BlockHandle {
+                first_key: first.key().to_vec(),
+                offset,
+                len,
+            });
+            self.keys += block.ops.len();
+        }
+    }
+
+    /// The value of `key`, read from `file`, the table's segment.
+    pub fn get(&self, file: &DataFile, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let i = self.blocks.partition_point(|block| block.first_key.as_slice() <= key);
+        if i == 0 {
+            return Ok(None);
+        }
+        let block = self.block(file, i - 1)?;
+        Ok(block
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+            .ok()
+            .map(|pos| block[pos].1.clone()))
+    }
+
+    /// Iterates over the entries from `start` to `end`, read from `file`,
+    /// the table's segment.
+    pub fn scan<'a>(&'a self, file: &'a DataFile, start: Bound<&[u8]>, end: Bound<&[u8]>) -> TableIter<'a> {
+        let first = match start {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                self.blocks.partition_point(|block| block.first_key.as_slice() <= key).saturating_sub(1)
+            }
+            Bound::Unbounded => 0,
+        };
+        let mut iter = TableIter {
+            table: self,
+            file,
+            end: end.map(|key| key.to_vec()),
+            block: None,
+            pos: 0,
+            next_block: first,
+            done: false,
+        };
+        // Skip the keys before `start` in the first block.
+        if let Bound::Included(key) | Bound::Excluded(key) = start {
+            match iter.load_next() {
+                Ok(true) => {
+                    let block = iter.block.as_ref().unwrap();
+                    iter.pos = match start {
+                        Bound::Included(_) => block.partition_point(|(k, _)| k.as_slice() < key),
+                        _ => block.partition_point(|(k, _)| k.as_slice() <= key),
+                    };
+                }
+                Ok(false) => iter.done = true,
+                // Reported by the first call to `next`.
+                Err(_) => iter.next_block = first,
+            }
+        }
+        iter
+    }
+
+    fn block(&self, file: &DataFile, i: usize) -> Result<Block> {
+        let handle = &self.blocks[i];
+        let key = (self.cache_id, handle.offset);
+        if let Some(block) = self.cache.as_ref().and_then(|cache| cache.get(key)) {
+            return Ok(block);
+        }
+        let block = Arc::new(read_block(file, handle)?);
+        if let Some(cache) = &self.cache {
+            cache.insert(key, block.clone(), handle.len);
+        }
+        Ok(block)
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        // A replaced table's blocks would otherwise sit in the cache until
+        // they aged out.
+        if let Some(cache) = &self.cache {
+            cache.forget(self.cache_id);
+        }
+    }
+}
+
+/// Decodes the block at `handle` in `file`, checking its checksum.
+fn read_block(file: &DataFile, handle: &BlockHandle) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let corrupted = || Error::CorruptedBlock {
+        path: file.path().to_owned(),
+        offset: handle.offset,
+        len: handle.len,
+    };
+    let data = file.read(handle.offset, handle.len)?;
+    let record = wal::decode_frame(&data)
+        .filter(|record| record.seq == BLOCK_SEQ)
+        .ok_or_else(corrupted)?;
+    record
+        .ops
+        .into_iter()
+        .map(|op| match op {
+            Op::Put { key, value } => Ok((key, value)),
+            Op::Delete { .. } => Err(corrupted()),
+        })
+        .collect()
+}
+
+impl TableWriter {
+    pub fn new(block_size: u64) -> TableWriter {
+        TableWriter {
+            block_size,
+            ops: Vec::new(),
+            bytes: 0,
+            blocks: Vec::new(),
+            keys: 0,
+        }
+    }
+
+    /// Adds `key`, which must come after every key added so far, appending a
+    /// block to `out` once there is a full one. Returns the bytes appended.
+    pub fn add(&mut self, out: &mut Wal, key: Vec<u8>, value: Vec<u8>) -> Result<u64> {
+        self.bytes += wal::OP_HEADER_LEN + (key.len() + value.len()) as u64;
+        self.ops.push(Op::Put { key, value });
+        if self.bytes >= self.block_size {
+            return self.flush(out);
+        }
+        Ok(0)
+    }
+
+    /// Appends the last block, and returns the table, stored in `segment`.
+    pub fn finish(mut self, out: &mut Wal, segment: u64, cache: Option<Arc<BlockCache>>) -> Result<(Table, u64)> {
+        let written = self.flush(out)?;
+        let mut table = Table::new(segment, cache);
+        table.blocks = self.blocks;
+        table.keys = self.keys;
+        Ok((table, written))
+    }
+
+    fn flush(&mut self, out: &mut Wal) -> Result<u64> {
+        if self.ops.is_empty() {
+            return Ok(0);
+        }
+        let first_key = self.ops[0].key().to_vec();
+        self.keys += self.ops.len();
+        let before = out.size();
+        let offset = out.append(&Record {
+            seq: BLOCK_SEQ,
+            ops: std::mem::take(&mut self.ops),
+        })?;
+        self.blocks.push(BlockHandle {
+            first_key,
+            offset,
+            len: out.size() - offset,
+        });
+        self.bytes = 0;
+        Ok(out.size() - before)
+    }
+}
+
+impl TableIter<'_> {
+    /// Moves on to the next block, returning `false` if there is none.
+    fn load_next(&mut self) -> Result<bool> {
+        if self.next_block == self.table.blocks.len() {
+            return Ok(false);
+        }
+        self.block = Some(self.table.block(self.file, self.next_block)?);
+        self.next_block += 1;
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl Iterator for TableIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        while !self.done {
+            if let Some(block) = &self.block {
+                if let Some((key, value)) = block.get(self.pos) {
+                    let past_end = match &self.end {
+                        Bound::Included(end) => key > end,
+                        Bound::Excluded(end) => key >= end,
+                        Bound::Unbounded => false,
+                    };
+                    if past_end {
+                        break;
+                    }
+                    self.pos += 1;
+                    return Some(Ok((key.clone(), value.clone())));
+                }
+            }
+            match self.load_next() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+impl Entries {
+    pub fn open(path: &Path) -> Result<Entries> {
+        Ok(Entries {
+            reader: Reader::open(path)?,
+            ops: Vec::new().into_iter(),
+            done: false,
+        })
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            match self.ops.next() {
+                Some(Op::Put { key, value }) => return Some(Ok((key, value))),
+                Some(Op::Delete { .. }) => {
+                    self.done = true;
+                    return Some(Err(Error::Corrupted {
+                        offset: self.reader.valid_len(),
+                        reason: "delete in a table block",
+                    }));
+                }
+                None => {}
+            }
+            if self.done {
+                return None;
+            }
+            // The blocks end at the first commit record.
+            match self.reader.next() {
+                Some(Ok((_, record))) if record.seq == BLOCK_SEQ => self.ops = record.ops.into_iter(),
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                _ => self.done = true,
+            }
+        }
+    }
+}
+
+impl BlockCache {
+    pub fn new(budget: u64) -> BlockCache {
+        BlockCache {
+            budget,
+            next_id: AtomicU64::new(0),
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    fn get(&self, key: (u64, u64)) -> Option<Block> {
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+        let (block, last) = match state.blocks.get_mut(&key) {
+            Some((block, _, used)) => (block.clone(), std::mem::replace(used, tick)),
+            None => {
+                state.misses += 1;
+                return None;
+            }
+        };
+        state.hits += 1;
+        state.by_use.remove(&last);
+        state.by_use.insert(tick, key);
+        Some(block)
+    }
+
+    fn insert(&self, key: (u64, u64), block: Block, size: u64) {
+        if size > self.budget {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        while state.used + size > self.budget {
+            let (_, oldest) = state.by_use.pop_first().unwrap();
+            let (_, evicted, _) = state.blocks.remove(&oldest).unwrap();
+            state.used -= evicted;
+            state.evictions += 1;
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some((_, replaced, used)) = state.blocks.insert(key, (block, size, tick)) {
+            state.used -= replaced;
+            state.by_use.remove(&used);
+        }
+        state.used += size;
+        state.by_use.insert(tick, key);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            hits: state.hits,
+            misses: state.misses,
+            evictions: state.evictions,
+            bytes: state.used,
+        }
+    }
+
+    /// Drops every block of the table numbered `table`.
+    fn forget(&self, table: u64) {
+        let mut state = self.state.lock().unwrap();
+        let mut freed = 0;
+        let mut ticks = Vec::new();
+        state.blocks.retain(|&(id, _), &mut (_, size, used)| {
+            if id == table {
+                freed += size;
+                ticks.push(used);
+            }
+            id != table
+        });
+        state.used -= freed;
+        for tick in ticks {
+            state.by_use.remove(&tick);
+        }
+    }
+}
+
+impl FromStr for ReadMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<ReadMode, ()> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "buffered" => Ok(ReadMode::Buffered),
+            "mmap" => Ok(ReadMode::Mmap),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for ReadMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ReadMode::Buffered => "buffered",
+            ReadMode::Mmap => "mmap",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal::FsyncPolicy;
+
+    /// Bytes of one entry: `key{:03}` and a four-byte value.
+    const ENTRY_LEN: u64 = wal::OP_HEADER_LEN + 6 + 4;
+    const KEYS: usize = 40;
+    const PER_BLOCK: usize = 4;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:03}", i).into_bytes()
+    }
+
+    fn value(i: usize) -> Vec<u8> {
+        format!("{:04}", i).into_bytes()
+    }
+
+    /// Writes keys `0..KEYS` into a table of `PER_BLOCK` keys per block, in
+    /// segment 1 in a temporary directory.
+    fn write_table(cache: Option<Arc<BlockCache>>) -> (tempfile::TempDir, PathBuf, Table) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0000000001.compact");
+        let mut out = Wal::open(&path, 0, FsyncPolicy::Never).unwrap();
+        let mut writer = TableWriter::new(ENTRY_LEN * PER_BLOCK as u64);
+        for i in 0..KEYS {
+            writer.add(&mut out, key(i), value(i)).unwrap();
+        }
+        let (table, _) = writer.finish(&mut out, 1, cache).unwrap();
+        assert_eq!(table.blocks.len(), KEYS / PER_BLOCK);
+        assert_eq!(table.keys(), KEYS);
+        (dir, path, table)
+    }
+
+    fn scan(table: &Table, file: &DataFile, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Vec<usize> {
+        table
+            .scan(file, start, end)
+            .map(|entry| {
+                let (k, v) = entry.unwrap();
+                let i = std::str::from_utf8(&k[3..]).unwrap().parse().unwrap();
+                assert_eq!(v, value(i));
+                i
+            })
+            .collect()
+    }
+
+    fn flip_byte(path: &Path, offset: u64) {
+        let mut bytes = std::fs::read(path).unwrap();
+        bytes[offset as usize] ^= 0xff;
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn point_lookups() {
+        let (_dir, path, table) = write_table(None);
+        for map in &[false, true] {
+            let file = DataFile::open(&path, *map).unwrap();
+            for i in 0..KEYS {
+                assert_eq!(table.get(&file, &key(i)).unwrap(), Some(value(i)));
+            }
+            // Before the first key, between keys, and past the last one.
+            for missing in &[&b"a"[..], b"key", b"key0015", b"key039x", b"z"] {
+                assert_eq!(table.get(&file, missing).unwrap(), None);
+            }
+        }
+    }
+
+    #[test]
+    fn range_scans() {
+        let (_dir, path, table) = write_table(None);
+        let file = DataFile::open(&path, true).unwrap();
+        let (k5, k6, k13, k16) = (key(5), key(6), key(13), key(16));
+
+        assert_eq!(scan(&table, &file, Bound::Unbounded, Bound::Unbounded), (0..KEYS).collect::<Vec<_>>());
+        // Starting mid-block, and at the first key of a block.
+        assert_eq!(scan(&table, &file, Bound::Included(&k5), Bound::Excluded(&k13)), (5..13).collect::<Vec<_>>());
+        assert_eq!(scan(&table, &file, Bound::Excluded(&k5), Bound::Included(&k13)), (6..14).collect::<Vec<_>>());
+        assert_eq!(scan(&table, &file, Bound::Excluded(&k16), Bound::Unbounded), (17..KEYS).collect::<Vec<_>>());
+        // Starting between two keys of a block, or before any key.
+        assert_eq!(scan(&table, &file, Bound::Included(b"key0055"), Bound::Included(&k6)), vec![6]);
+        assert_eq!(scan(&table, &file, Bound::Included(b"a"), Bound::Excluded(&k5)), (0..5).collect::<Vec<_>>());
+        // Ending right after a block, and empty ranges.
+        assert_eq!(scan(&table, &file, Bound::Included(&k13), Bound::Excluded(&k16)), vec![13, 14, 15]);
+        assert_eq!(scan(&table, &file, Bound::Included(&k6), Bound::Excluded(&k6)), Vec::<usize>::new());
+        assert_eq!(scan(&table, &file, Bound::Included(b"z"), Bound::Unbounded), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn replayed_table_matches_the_written_one() {
+        let (_dir, path, written) = write_table(None);
+        let mut replayed = Table::new(1, None);
+        let mut reader = Reader::open(&path).unwrap();
+        while let Some(entry) = reader.next() {
+            let (offset, record) = entry.unwrap();
+            replayed.add_block(offset, reader.valid_len() - offset, &record);
+        }
+        let file = DataFile::open(&path, false).unwrap();
+        assert_eq!(replayed.keys(), written.keys());
+        assert_eq!(scan(&replayed, &file, Bound::Unbounded, Bound::Unbounded), (0..KEYS).collect::<Vec<_>>());
+
+        let entries: Vec<_> = Entries::open(&path).unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries, (0..KEYS).map(|i| (key(i), value(i))).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cache_counts_hits_and_evicts_least_recently_used() {
+        // Room for two blocks, which are all the same size.
+        let (_dir, path, mut probe) = write_table(None);
+        let block_len = probe.blocks[0].len;
+        assert!(probe.blocks.iter().all(|block| block.len == block_len));
+        let cache = Arc::new(BlockCache::new(2 * block_len));
+        let mut table = Table::new(1, Some(cache.clone()));
+        table.blocks = std::mem::take(&mut probe.blocks);
+        let file = DataFile::open(&path, false).unwrap();
+        let in_block = |block: usize| key(block * PER_BLOCK + 1);
+        let stats = |hits, misses, evictions, blocks: u64| CacheStats {
+            hits,
+            misses,
+            evictions,
+            bytes: blocks * block_len,
+        };
+
+        table.get(&file, &in_block(0)).unwrap().unwrap();
+        assert_eq!(cache.stats(), stats(0, 1, 0, 1));
+        table.get(&file, &in_block(0)).unwrap().unwrap();
+        assert_eq!(cache.stats(), stats(1, 1, 0, 1));
+        table.get(&file, &in_block(1)).unwrap().unwrap();
+        assert_eq!(cache.stats(), stats(1, 2, 0, 2));
+        // Block 0 is used again, so block 1 goes first.
+        table.get(&file, &in_block(0)).unwrap().unwrap();
+        table.get(&file, &in_block(2)).unwrap().unwrap();
+        assert_eq!(cache.stats(), stats(2, 3, 1, 2));
+        table.get(&file, &in_block(0)).unwrap().unwrap();
+        table.get(&file, &in_block(2)).unwrap().unwrap();
+        assert_eq!(cache.stats(), stats(4, 3, 1, 2));
+        table.get(&file, &in_block(1)).unwrap().unwrap();
+        assert_eq!(cache.stats(), stats(4, 4, 2, 2));
+
+        // A scan goes through the cache too, a block at a time.
+        let all = scan(&table, &file, Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(all.len(), KEYS);
+        let blocks = (KEYS / PER_BLOCK) as u64;
+        assert_eq!(cache.stats().hits + cache.stats().misses, 4 + 4 + blocks);
+
+        // A dropped table's blocks leave the cache.
+        drop(table);
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    #[test]
+    fn cache_skips_blocks_over_its_budget() {
+        let (_dir, path, mut probe) = write_table(None);
+        let cache = Arc::new(BlockCache::new(probe.blocks[0].len - 1));
+        let mut table = Table::new(1, Some(cache.clone()));
+        table.blocks = std::mem::take(&mut probe.blocks);
+        let file = DataFile::open(&path, false).unwrap();
+        table.get(&file, &key(0)).unwrap().unwrap();
+        table.get(&file, &key(0)).unwrap().unwrap();
+        assert_eq!(cache.stats(), CacheStats {
+            hits: 0,
+            misses: 2,
+            evictions: 0,
+            bytes: 0,
+        });
+    }
+
+    #[test]
+    fn corrupted_block() {
+        let (_dir, path, table) = write_table(None);
+        let damaged = &table.blocks[2];
+        flip_byte(&path, damaged.offset + damaged.len - 1);
+
+        for map in &[false, true] {
+            let file = DataFile::open(&path, *map).unwrap();
+            match table.get(&file, &key(2 * PER_BLOCK)) {
+                Err(Error::CorruptedBlock { path: at, offset, len }) => {
+                    assert_eq!(at, path);
+                    assert_eq!((offset, len), (damaged.offset, damaged.len));
+                }
+                other => panic!("expected a corrupted block, got {:?}", other),
+            }
+            // The other blocks still read, and a scan stops at the damage.
+            assert_eq!(table.get(&file, &key(0)).unwrap(), Some(value(0)));
+            let mut iter = table.scan(&file, Bound::Unbounded, Bound::Unbounded);
+            for i in 0..2 * PER_BLOCK {
+                assert_eq!(iter.next().unwrap().unwrap(), (key(i), value(i)));
+            }
+            assert!(matches!(iter.next(), Some(Err(Error::CorruptedBlock { .. }))));
+            assert!(iter.next().is_none());
+            // So does one starting in the damaged block.
+            let mut iter = table.scan(&file, Bound::Included(&key(9)), Bound::Unbounded);
+            assert!(matches!(iter.next(), Some(Err(Error::CorruptedBlock { .. }))));
+            assert!(iter.next().is_none());
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Transactions with snapshot isolation.
+//!
+//! A [`Txn`] reads the database as of the moment it began, plus its own
+//! buffered writes, and applies all of its writes atomically on commit. One
+//! transaction can read and write any number of keyspaces. Commit fails with
+//! [`Conflict`](crate::error::Error::Conflict) if another transaction
+//! committed a write to any key this one wrote after it began (first
+//! committer wins); keys that were only read are not checked.
+
+use std::cmp::Ordering;
+use std::collections::btree_map;
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+
+use crate::batch::WriteBatch;
+use crate::error::Result;
+use crate::keyspace::KeyspaceId;
+use crate::storage::{self, Db, Keyspace};
+
+/// A transaction, started with [`Db::begin`]. Dropping it without
+/// committing rolls it back.
+pub struct Txn<'db> {
+    db: &'db Db,
+    snapshot: u64,
+    /// Buffered writes, by keyspace.
+    writes: BTreeMap<KeyspaceId, (Keyspace<'db>, Buffer)>,
+}
+
+/// The buffered writes to one keyspace; `None` marks a delete.
+type Buffer = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// Iterator over a key range as a transaction sees it: the snapshot it
+/// reads from, overlaid with its own buffered writes.
+pub struct Iter<'a> {
+    committed: Peekable<storage::Iter<'a>>,
+    /// `None` when the transaction has no writes in the range.
+    writes: Option<Writes<'a>>,
+}
+
+type Writes<'a> = Peekable<btree_map::Range<'a, Vec<u8>, Option<Vec<u8>>>>;
+
+impl<'db> Txn<'db> {
+    pub(crate) fn new(db: &'db Db, snapshot: u64) -> Txn<'db> {
+        Txn {
+            db,
+            snapshot,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// The value of `key` in `keyspace` as this transaction sees it.
+    pub fn get(&self, keyspace: &Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.writes.get(&keyspace.id()).and_then(|(_, writes)| writes.get(key)) {
+            Some(value) => Ok(value.clone()),
+            None => self.db.get_at(keyspace.id(), key, self.snapshot),
+        }
+    }
+
+    /// Buffers a write of `value` under `key`, applied on commit.
+    pub fn put(&mut self, keyspace: &Keyspace<'db>, key: &[u8], value: &[u8]) {
+        self.buffer(keyspace).insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    /// Removes `key`, returning whether it was present as seen by this
+    /// transaction.
+    pub fn delete(&mut self, keyspace: &Keyspace<'db>, key: &[u8]) -> Result<bool> {
+        let existed = self.get(keyspace, key)?.is_some();
+        self.buffer(keyspace).insert(key.to_vec(), None);
+        Ok(existed)
+    }
+
+    /// Iterates over the key-value pairs of `keyspace` visible to this
+    /// transaction whose keys fall in `range`, in key order.
+    pub fn scan<K: AsRef<[u8]> + ?Sized, R: RangeBounds<K>>(&self, keyspace: &Keyspace, range: R) -> Iter<'_> {
+        let (start, end) = storage::owned_bounds(&range);
+        self.scan_bounds(keyspace, start, end)
+    }
+
+    /// Iterates over the key-value pairs of `keyspace` visible to this
+    /// transaction whose keys start with `prefix`, in key order.
+    pub fn scan_prefix(&self, keyspace: &Keyspace, prefix: &[u8]) -> Iter<'_> {
+        self.scan_bounds(keyspace, Bound::Included(prefix.to_vec()), storage::prefix_end(prefix))
+    }
+
+    fn scan_bounds(&self, keyspace: &Keyspace, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Iter<'_> {
+        let writes = match self.writes.get(&keyspace.id()) {
+            Some((_, writes)) if !storage::is_empty_range(&start, &end) => {
+                let range = (start.as_ref().map(Vec::as_slice), end.as_ref().map(Vec::as_slice));
+                Some(writes.range::<[u8], _>(range).peekable())
+            }
+            _ => None,
+        };
+        Iter {
+            committed: self.db.scan_at(keyspace.id(), self.snapshot, start, end).peekable(),
+            writes,
+        }
+    }
+
+    fn buffer(&mut self, keyspace: &Keyspace<'db>) -> &mut Buffer {
+        &mut self
+            .writes
+            .entry(keyspace.id())
+            .or_insert_with(|| (keyspace.clone(), BTreeMap::new()))
+            .1
+    }
+
+    /// Applies every buffered write atomically, across all the keyspaces
+    /// written.
+    pub fn commit(mut self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (keyspace, writes) in std::mem::take(&mut self.writes).into_values() {
+            for (key, value) in writes {
+                match value {
+                    Some(value) => batch.put(&keyspace, &key, &value),
+                    None => batch.delete(&keyspace, &key),
+                }
+            }
+        }
+        self.db.commit(self.snapshot, batch)
+    }
+
+    /// Discards every buffered write. Dropping the transaction does the same.
+    pub fn rollback(self) {}
+}
+
+impl Drop for Txn<'_> {
+    fn drop(&mut self) {
+        self.db.release(self.snapshot);
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let writes = match self.writes.as_mut() {
+            Some(writes) => writes,
+            None => return self.committed.next(),
+        };
+        loop {
+            // Take whichever side has the smaller key; on a tie the buffered
+            // write shadows the committed value.
+            let order = match (self.committed.peek(), writes.peek()) {
+                (None, None) => return None,
+                (Some(Err(_)), _) | (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(Ok((key, _))), Some((write_key, _))) => key.cmp(*write_key),
+            };
+            match order {
+                Ordering::Less => return self.committed.next(),
+                Ordering::Equal => {
+                    self.committed.next();
+                }
+                Ordering::Greater => {}
+            }
+            let (key, value) = writes.next().unwrap();
+            // A buffered delete hides the key.
+            if let Some(value) = value {
+                return Some(Ok((key.clone(), value.clone())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::storage::tests::{contents, options, pairs};
+    use crate::storage::Options;
+    use crate::table::{ReadMode, ReadOptions};
+
+    fn scan<'a>(txn: &Txn, keyspace: &Keyspace, range: (Bound<&'a [u8]>, Bound<&'a [u8]>)) -> Vec<(Vec<u8>, Vec<u8>)> {
+        txn.scan::<[u8], _>(keyspace, range).collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn reads_its_own_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        ks.put(b"a", b"1").unwrap();
+        ks.put(b"b", b"2").unwrap();
+
+        let mut txn = db.begin();
+        txn.put(&ks, b"a", b"10");
+        assert!(txn.delete(&ks, b"b").unwrap());
+        assert!(!txn.delete(&ks, b"missing").unwrap());
+        txn.put(&ks, b"c", b"30");
+        assert_eq!(txn.get(&ks, b"a").unwrap(), Some(b"10".to_vec()));
+        assert_eq!(txn.get(&ks, b"b").unwrap(), None);
+        assert_eq!(txn.get(&ks, b"c").unwrap(), Some(b"30".to_vec()));
+        // Nothing is visible outside before the commit.
+        assert_eq!(contents(&ks), pairs(&[("a", "1"), ("b", "2")]));
+
+        txn.commit().unwrap();
+        assert_eq!(contents(&ks), pairs(&[("a", "10"), ("c", "30")]));
+    }
+
+    #[test]
+    fn reads_from_its_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        ks.put(b"a", b"1").unwrap();
+
+        let txn = db.begin();
+        assert_eq!(ks.stats().unwrap().open_snapshots, 1);
+        ks.put(b"a", b"2").unwrap();
+        ks.put(b"b", b"3").unwrap();
+        assert!(ks.delete(b"a").unwrap());
+        assert_eq!(txn.get(&ks, b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(txn.get(&ks, b"b").unwrap(), None);
+        assert_eq!(scan(&txn, &ks, (Bound::Unbounded, Bound::Unbounded)), pairs(&[("a", "1")]));
+
+        txn.rollback();
+        assert_eq!(ks.stats().unwrap().open_snapshots, 0);
+        assert_eq!(contents(&ks), pairs(&[("b", "3")]));
+    }
+
+    #[test]
+    fn write_write_race_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        ks.put(b"k", b"0").unwrap();
+
+        let mut first = db.begin();
+        let mut second = db.begin();
+        first.put(&ks, b"k", b"1");
+        second.put(&ks, b"k", b"2");
+        second.put(&ks, b"other", b"2");
+        first.commit().unwrap();
+        match second.commit() {
+            Err(Error::Conflict(key)) => assert_eq!(key, b"k"),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        // The loser wrote nothing, not even its other keys.
+        assert_eq!(contents(&ks), pairs(&[("k", "1")]));
+
+        // A plain write counts as much as a transaction's, and so does a
+        // delete.
+        let mut txn = db.begin();
+        txn.put(&ks, b"k", b"3");
+        assert!(ks.delete(b"k").unwrap());
+        assert!(matches!(txn.commit(), Err(Error::Conflict(_))));
+
+        // Keys that were only read are not checked.
+        let mut txn = db.begin();
+        assert_eq!(txn.get(&ks, b"k").unwrap(), None);
+        txn.put(&ks, b"elsewhere", b"4");
+        ks.put(b"k", b"5").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(contents(&ks), pairs(&[("elsewhere", "4"), ("k", "5")]));
+    }
+
+    #[test]
+    fn scan_overlays_buffered_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), options()).unwrap();
+        let ks = db.default_keyspace();
+        for key in &["b", "c", "d", "f"] {
+            ks.put(key.as_bytes(), b"old").unwrap();
+        }
+
+        let mut txn = db.begin();
+        txn.put(&ks, b"a", b"new");
+        txn.put(&ks, b"c", b"new");
+        txn.delete(&ks, b"d").unwrap();
+        txn.put(&ks, b"e", b"new");
+        txn.delete(&ks, b"e").unwrap();
+        txn.put(&ks, b"g", b"new");
+
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(
+            scan(&txn, &ks, all),
+            pairs(&[("a", "new"), ("b", "old"), ("c", "new"), ("f", "old"), ("g", "new")])
+        );
+        let middle = (Bound::Included(&b"c"[..]), Bound::Excluded(&b"g"[..]));
+        assert_eq!(scan(&txn, &ks, middle), pairs(&[("c", "new"), ("f", "old")]));
+        let after = (Bound::Excluded(&b"c"[..]), Bound::Unbounded);
+        assert_eq!(scan(&txn, &ks, after), pairs(&[("f", "old"), ("g", "new")]));
+        let empty = (Bound::Included(&b"e"[..]), Bound::Excluded(&b"b"[..]));
+        assert_eq!(scan(&txn, &ks, empty), pairs(&[]));
+        let prefixed: Vec<_> = txn.scan_prefix(&ks, b"c").collect::<Result<_>>().unwrap();
+        assert_eq!(prefixed, pairs(&[("c", "new")]));
+
+        // Writes to another keyspace stay out of this one's scans.
+        let other = db.create_keyspace("other").unwrap();
+        let mut txn = db.begin();
+        txn.put(&other, b"a", b"elsewhere");
+        assert_eq!(scan(&txn, &ks, all).len(), 4);
+        assert_eq!(scan(&txn, &other, all), pairs(&[("a", "elsewhere")]));
+    }
+
+    #[test]
+    fn survives_a_compaction() {
+        // In mmap mode, compaction writes a sorted table instead.
+        for &mode in &[ReadMode::Buffered, ReadMode::Mmap] {
+            let dir = tempfile::tempdir().unwrap();
+            let options = Options {
+                segment_size: 256,
+                read: ReadOptions {
+                    mode,
+                    ..ReadOptions::default()
+                },
+                ..options()
+            };
+            let db = Db::open(dir.path(), options).unwrap();
+            let ks = db.default_keyspace();
+            for i in 0..50 {
+                ks.put(format!("key{:02}", i).as_bytes(), b"old").unwrap();
+            }
+
+            let mut txn = db.begin();
+            txn.put(&ks, b"key00", b"mine");
+            for i in 0..50 {
+                let key = format!("key{:02}", i);
+                if i % 2 == 0 {
+                    ks.put(key.as_bytes(), b"new").unwrap();
+                } else {
+                    ks.delete(key.as_bytes()).unwrap();
+                }
+            }
+            ks.compact().unwrap();
+
+            assert_eq!(txn.get(&ks, b"key00").unwrap(), Some(b"mine".to_vec()));
+            assert_eq!(txn.get(&ks, b"key01").unwrap(), Some(b"old".to_vec()));
+            assert_eq!(txn.get(&ks, b"key02").unwrap(), Some(b"old".to_vec()));
+            let seen = scan(&txn, &ks, (Bound::Unbounded, Bound::Unbounded));
+            assert_eq!(seen.len(), 50);
+            assert!(seen[1..].iter().all(|(_, value)| value == b"old"));
+            // key00 was rewritten since the transaction began.
+            assert!(matches!(txn.commit(), Err(Error::Conflict(_))));
+
+            let mut txn = db.begin();
+            txn.put(&ks, b"key01", b"mine");
+            txn.commit().unwrap();
+            let expected: Vec<_> = (0..50)
+                .filter_map(|i| match i {
+                    1 => Some((b"key01".to_vec(), b"mine".to_vec())),
+                    i if i % 2 == 0 => Some((format!("key{:02}", i).into_bytes(), b"new".to_vec())),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(contents(&ks), expected);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Offline integrity checks (`rmdb verify`).
+//!
+//! Verification reads the keyspace catalog and every log file of the
+//! database straight from disk, without opening it, so it also works on a
+//! database too damaged to open. Each record is checked against its
+//! checksum (see [`crate::wal`]). Past a damaged record the check carries on
+//! from the next offset at which an intact record starts, so one bad block
+//! does not hide everything after it, and each damaged region is reported
+//! with its extent.
+//!
+//! Salvaging writes every intact record to a new database directory, under
+//! the same file names, along with the catalog. Opening the copy rebuilds it
+//! from what survived: the commits in damaged regions are lost, but the
+//! rest, sequence numbers included, is kept as it was.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::keyspace::Catalog;
+use crate::storage::{self, LogFile};
+use crate::wal::{self, FsyncPolicy, Reader, Wal};
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub keyspaces: usize,
+    pub files: Vec<FileReport>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    /// Path within the database directory.
+    pub name: String,
+    /// Intact records found.
+    pub records: u64,
+    /// Bytes taken up by the intact records.
+    pub bytes: u64,
+    pub damaged: Vec<Damage>,
+    /// Offset of a torn record at the end of a file still appended to: an
+    /// interrupted write, which the next open discards.
+    pub torn: Option<u64>,
+}
+
+/// A region of a file holding no intact record.
+#[derive(Debug, Clone, Copy)]
+pub struct Damage {
+    pub offset: u64,
+    pub len: u64,
+    pub reason: &'static str,
+}
+
+/// Where reading a run of records stopped.
+enum Stop {
+    End,
+    /// At a record cut short by the end of the file.
+    Torn(u64),
+    Damaged(u64, &'static str),
+}
+
+impl Report {
+    pub fn records(&self) -> u64 {
+        self.files.iter().map(|file| file.records).sum()
+    }
+
+    pub fn damaged_regions(&self) -> usize {
+        self.files.iter().map(|file| file.damaged.len()).sum()
+    }
+
+    pub fn damaged_bytes(&self) -> u64 {
+        self.files.iter().flat_map(|file| &file.damaged).map(|damage| damage.len).sum()
+    }
+}
+
+/// Checks every log file of the database in `dir`. With `salvage`, also
+/// writes the intact records to a new database there, which must not exist
+/// yet or be empty.
+pub fn verify(dir: &Path, salvage: Option<&Path>) -> Result<Report> {
+    let catalog = Catalog::load(dir)?;
+    if let Some(dest) = salvage {
+        fs::create_dir_all(dest)?;
+        if fs::read_dir(dest)?.next().is_some() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not empty", dest.display())).into());
+        }
+        catalog.store(dest)?;
+    }
+
+    let mut files = Vec::new();
+    for file in storage::log_files(dir, &catalog)? {
+        let mut out = match salvage {
+            Some(dest) => {
+                let target = dest.join(&file.name);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                Some(Wal::open(&target, 0, FsyncPolicy::Never)?)
+            }
+            None => None,
+        };
+        files.push(check_file(&file, out.as_mut())?);
+    }
+    Ok(Report {
+        keyspaces: catalog.ids().len(),
+        files,
+    })
+}
+
+/// Checks one log file, appending its intact records to `out`.
+fn check_file(file: &LogFile, mut out: Option<&mut Wal>) -> Result<FileReport> {
+    let mut report = FileReport {
+        name: file.name.clone(),
+        records: 0,
+        bytes: 0,
+        damaged: Vec::new(),
+        torn: None,
+    };
+    let len = fs::metadata(&file.path)?.len();
+    let mut stop = read_run(&mut Reader::new(fs::File::open(&file.path)?, len), 0, &mut report, &mut out)?;
+
+    // Only a damaged file is read whole, to search it for intact records.
+    let mut data = Vec::new();
+    loop {
+        let (offset, reason) = match stop {
+            Stop::End => break,
+            Stop::Torn(offset) => (offset, "record runs past the end of the file"),
+            Stop::Damaged(offset, reason) => (offset, reason),
+        };
+        if data.is_empty() {
+            data = fs::read(&file.path)?;
+            data.truncate(len as usize);
+        }
+        let next = wal::find_record(&data, offset as usize + 1);
+        if let (Stop::Torn(_), None) = (&stop, next) {
+            // Nothing intact follows, so it is an interrupted append unless
+            // the file was no longer appended to.
+            if file.active {
+                report.torn = Some(offset);
+            } else {
+                report.damaged.push(Damage {
+                    offset,
+                    len: len - offset,
+                    reason: "torn record in a sealed segment",
+                });
+            }
+            break;
+        }
+
+        let end = next.map_or(len, |next| next as u64);
+        report.damaged.push(Damage {
+            offset,
+            len: end - offset,
+            reason,
+        });
+        stop = match next {
+            Some(start) => {
+                let rest = &data[start..];
+                read_run(&mut Reader::new(rest, rest.len() as u64), start as u64, &mut report, &mut out)?
+            }
+            None => Stop::End,
+        };
+    }
+    Ok(report)
+}
+
+/// Reads records from `reader`, which starts at `base` in the file, until
+/// the end of the file or the first bad record.
+fn read_run<R: Read>(reader: &mut Reader<R>, base: u64, report: &mut FileReport, out: &mut Option<&mut Wal>) -> Result<Stop> {
+    for entry in &mut *reader {
+        match entry {
+            Ok((_, record)) => {
+                report.records += 1;
+                if let Some(out) = out {
+                    out.append(&record)?;
+                }
+            }
+            Err(Error::Corrupted { offset, reason }) => {
+                report.bytes += offset;
+                return Ok(Stop::Damaged(base + offset, reason));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    report.bytes += reader.valid_len();
+    if reader.torn() {
+        Ok(Stop::Torn(base + reader.valid_len()))
+    } else {
+        Ok(Stop::End)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::storage::tests::{contents, options, pairs};
+    use crate::storage::Db;
+
+    /// A database whose default keyspace holds `key0`..`key9`, one commit
+    /// each, so every record of its segment has the same length, and whose
+    /// keyspace `other` holds `x`. Returns the path of the default segment
+    /// and the last sequence number.
+    fn populate(dir: &Path) -> (PathBuf, u64) {
+        let db = Db::open(dir, options()).unwrap();
+        let ks = db.default_keyspace();
+        for i in 0..10 {
+            ks.put(format!("key{}", i).as_bytes(), b"value").unwrap();
+        }
+        db.create_keyspace("other").unwrap().put(b"x", b"1").unwrap();
+        let catalog = Catalog::load(dir).unwrap();
+        (storage::log_files(dir, &catalog).unwrap().remove(0).path, ks.stats().unwrap().last_seq)
+    }
+
+    fn report<'a>(report: &'a Report, path: &Path) -> &'a FileReport {
+        report.files.iter().find(|file| path.ends_with(&file.name)).unwrap()
+    }
+
+    #[test]
+    fn damaged_record_is_reported_and_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let (segment, last_seq) = populate(dir.path());
+        let len = fs::metadata(&segment).unwrap().len();
+        let record = len / 10;
+
+        let clean = verify(dir.path(), None).unwrap();
+        assert_eq!(clean.keyspaces, 2);
+        assert_eq!(clean.damaged_regions(), 0);
+        assert_eq!(report(&clean, &segment).records, 10);
+
+        // Damage the value of key4.
+        let mut bytes = fs::read(&segment).unwrap();
+        bytes[(5 * record - 2) as usize] ^= 0xff;
+        fs::write(&segment, bytes).unwrap();
+
+        let salvaged = tempfile::tempdir().unwrap();
+        let damaged = verify(dir.path(), Some(salvaged.path())).unwrap();
+        let file = report(&damaged, &segment);
+        assert_eq!(file.records, 9);
+        assert_eq!(file.bytes, 9 * record);
+        assert_eq!(file.torn, None);
+        assert_eq!(file.damaged.len(), 1);
+        assert_eq!((file.damaged[0].offset, file.damaged[0].len), (4 * record, record));
+        assert_eq!(file.damaged[0].reason, "checksum mismatch");
+        assert_eq!((damaged.damaged_regions(), damaged.damaged_bytes()), (1, record));
+        assert_eq!(damaged.records(), clean.records() - 1);
+
+        // Only the damaged commit is lost from the salvaged copy.
+        let db = Db::open(salvaged.path(), options()).unwrap();
+        let keys: Vec<_> = contents(&db.default_keyspace()).into_iter().map(|(key, _)| key).collect();
+        let expected: Vec<_> = (0..10).filter(|&i| i != 4).map(|i| format!("key{}", i).into_bytes()).collect();
+        assert_eq!(keys, expected);
+        assert_eq!(contents(&db.keyspace("other").unwrap()), pairs(&[("x", "1")]));
+        assert_eq!(db.default_keyspace().stats().unwrap().last_seq, last_seq);
+    }
+
+    #[test]
+    fn torn_tail_is_not_damage() {
+        let dir = tempfile::tempdir().unwrap();
+        let (segment, _) = populate(dir.path());
+        let len = fs::metadata(&segment).unwrap().len();
+        let record = len / 10;
+        File::options().write(true).open(&segment).unwrap().set_len(len - 1).unwrap();
+
+        let salvaged = tempfile::tempdir().unwrap();
+        let torn = verify(dir.path(), Some(salvaged.path())).unwrap();
+        let file = report(&torn, &segment);
+        assert_eq!(file.records, 9);
+        assert_eq!(file.torn, Some(9 * record));
+        assert!(file.damaged.is_empty());
+        assert_eq!(torn.damaged_regions(), 0);
+
+        let db = Db::open(salvaged.path(), options()).unwrap();
+        assert_eq!(contents(&db.default_keyspace()).len(), 9);
+        assert_eq!(db.default_keyspace().get(b"key9").unwrap(), None);
+    }
+}
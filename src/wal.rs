@@ -0,0 +1,571 @@
+//! Write-ahead log.
+//!
+//! The log is the database's primary storage: every mutation is appended to
+//! it before it becomes visible, and the storage engine's index points into
+//! it. Each record is framed as
+//!
+//! ```text
+//! crc: u32 | len: u32 | payload
+//! ```
+//!
+//! where `crc` is the CRC32C of `len` and `payload`. A record holds every
+//! operation of one commit, so it is applied entirely or not at all:
+//!
+//! ```text
+//! payload = seq: u64 | count: u32 | op * count
+//! op      = kind: u8 | key_len: u32 | value_len: u32 | key | value
+//! ```
+//!
+//! All integers are little-endian. A record that is cut short, or that fails
+//! its checksum and is the last thing in the file, is a torn write from a
+//! crash and marks the end of the log. A bad record followed by more data is
+//! reported as corruption instead, since truncating there would silently
+//! drop the records after it.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use tracing::{error, trace, trace_span, warn};
+
+use crate::error::{Error, Result};
+
+pub(crate) const FRAME_LEN: u64 = 8;
+const RECORD_HEADER_LEN: u64 = 12;
+pub(crate) const OP_HEADER_LEN: u64 = 9;
+
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 2;
+
+/// When appended records are forced to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every record.
+    Always,
+    /// From a background thread, at most this long after a write.
+    Interval(Duration),
+    /// Never explicitly; left to the operating system.
+    Never,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub seq: u64,
+    pub ops: Vec<Op>,
+}
+
+pub(crate) struct Wal {
+    file: File,
+    len: u64,
+    policy: FsyncPolicy,
+    flusher: Option<Flusher>,
+}
+
+/// Background thread implementing [`FsyncPolicy::Interval`].
+struct Flusher {
+    dirty: Arc<AtomicBool>,
+    stop: mpsc::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+/// Sequential reader over the records of a log file.
+pub struct Reader<R> {
+    reader: BufReader<R>,
+    offset: u64,
+    file_len: u64,
+    torn: bool,
+}
+
+impl Wal {
+    /// Opens the log at `path` for appending. `len` is the length of its
+    /// valid prefix, as found by a [`Reader`]; anything after it is cut off.
+    pub fn open(path: &Path, len: u64, policy: FsyncPolicy) -> Result<Wal> {
+        let file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        if len < file.metadata()?.len() {
+            file.set_len(len)?;
+            file.sync_data()?;
+        }
+
+        let flusher = match policy {
+            FsyncPolicy::Interval(interval) => Some(Flusher::spawn(file.try_clone()?, interval)),
+            _ => None,
+        };
+        Ok(Wal { file, len, policy, flusher })
+    }
+
+    /// Appends `record`, returning its offset in the file. If the write
+    /// fails, whatever part of the record reached the file is cut off again,
+    /// so a later append cannot land behind a partial record.
+    pub fn append(&mut self, record: &Record) -> Result<u64> {
+        let _span = trace_span!("wal_append", seq = record.seq, ops = record.ops.len()).entered();
+        let frame = encode(record)?;
+        if let Err(e) = self.file.write_all(&frame) {
+            warn!(error = %e, len = self.len, "write failed, truncating the log");
+            let _ = self.file.set_len(self.len);
+            return Err(e.into());
+        }
+        match self.policy {
+            FsyncPolicy::Always => {
+                if let Err(e) = self.file.sync_data() {
+                    warn!(error = %e, len = self.len, "fsync failed, truncating the log");
+                    let _ = self.file.set_len(self.len);
+                    return Err(e.into());
+                }
+            }
+            FsyncPolicy::Interval(_) => {
+                if let Some(flusher) = &self.flusher {
+                    flusher.dirty.store(true, Ordering::Release);
+                }
+            }
+            FsyncPolicy::Never => {}
+        }
+
+        let offset = self.len;
+        self.len += frame.len() as u64;
+        trace!(offset, bytes = frame.len(), "appended");
+        Ok(offset)
+    }
+
+    /// Cuts the log back to its first `len` bytes, undoing the appends
+    /// after them.
+    pub fn truncate(&mut self, len: u64) -> Result<()> {
+        self.file.set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+
+    /// Forces every appended record to stable storage, whatever the policy.
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Length of the log in bytes.
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        if let Some(flusher) = self.flusher.take() {
+            drop(flusher.stop);
+            let _ = flusher.handle.join();
+        }
+        let _ = self.file.sync_data();
+    }
+}
+
+impl Flusher {
+    fn spawn(file: File, interval: Duration) -> Flusher {
+        let dirty = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let flag = dirty.clone();
+        // The loop ends once the sender is dropped.
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if flag.swap(false, Ordering::AcqRel) {
+                    if let Err(e) = file.sync_data() {
+                        error!(error = %e, "wal fsync failed");
+                    }
+                }
+            }
+        });
+
+        Flusher { dirty, stop, handle }
+    }
+}
+
+impl Reader<File> {
+    pub fn open(path: &Path) -> Result<Reader<File>> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        Ok(Reader::new(file, file_len))
+    }
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R, file_len: u64) -> Reader<R> {
+        Reader {
+            reader: BufReader::new(inner),
+            offset: 0,
+            file_len,
+            torn: false,
+        }
+    }
+
+    /// Length of the valid prefix read so far. Once iteration has finished
+    /// this is where the next record should be appended.
+    pub fn valid_len(&self) -> u64 {
+        self.offset
+    }
+
+    /// Whether iteration stopped at a torn record rather than a clean end.
+    pub fn torn(&self) -> bool {
+        self.torn
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>> {
+        let remaining = self.file_len - self.offset;
+        if remaining == 0 {
+            return Ok(None);
+        }
+
+        let mut frame = [0; FRAME_LEN as usize];
+        if remaining < FRAME_LEN || !read_full(&mut self.reader, &mut frame)? {
+            self.torn = true;
+            return Ok(None);
+        }
+        let crc = u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        let len = u64::from(u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]));
+        let end = self.offset + FRAME_LEN + len;
+        if end > self.file_len {
+            self.torn = true;
+            return Ok(None);
+        }
+
+        let mut payload = vec![0; len as usize];
+        if !read_full(&mut self.reader, &mut payload)? {
+            self.torn = true;
+            return Ok(None);
+        }
+        if crc32c::crc32c_append(crc32c::crc32c(&frame[4..]), &payload) != crc {
+            if end == self.file_len {
+                self.torn = true;
+                return Ok(None);
+            }
+            return Err(self.corrupted("checksum mismatch"));
+        }
+
+        let record = decode(&payload).ok_or_else(|| self.corrupted("malformed payload"))?;
+        self.offset = end;
+        Ok(Some(record))
+    }
+
+    fn corrupted(&self, reason: &'static str) -> Error {
+        Error::Corrupted {
+            offset: self.offset,
+            reason,
+        }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    /// A record and its offset in the file.
+    type Item = Result<(u64, Record)>;
+
+    fn next(&mut self) -> Option<Result<(u64, Record)>> {
+        let offset = self.offset;
+        self.read_record().transpose().map(|r| r.map(|record| (offset, record)))
+    }
+}
+
+impl Op {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Op::Put { key, .. } | Op::Delete { key } => key,
+        }
+    }
+}
+
+/// Offsets of the values of `ops` within a record at `offset`, in order.
+/// Deletes get the offset their (empty) value would have.
+pub(crate) fn value_offsets(offset: u64, ops: &[Op]) -> Vec<u64> {
+    let mut pos = offset + FRAME_LEN + RECORD_HEADER_LEN;
+    ops.iter()
+        .map(|op| {
+            let (key, value) = op_parts(op);
+            let value_offset = pos + OP_HEADER_LEN + key.len() as u64;
+            pos = value_offset + value.len() as u64;
+            value_offset
+        })
+        .collect()
+}
+
+/// Offset of the first whole and intact record in `buf` at or after `from`,
+/// for picking a damaged log up again past the damage.
+pub(crate) fn find_record(buf: &[u8], from: usize) -> Option<usize> {
+    (from..buf.len()).find(|&pos| decode_frame(&buf[pos..]).is_some())
+}
+
+/// Decodes the record at the start of `buf`, if it is whole and passes its
+/// checksum.
+pub(crate) fn decode_frame(buf: &[u8]) -> Option<Record> {
+    let frame = buf.get(..FRAME_LEN as usize)?;
+    let crc = u32::from_le_bytes(frame[..4].try_into().ok()?);
+    let len = u32::from_le_bytes(frame[4..].try_into().ok()?) as usize;
+    let payload = buf.get(FRAME_LEN as usize..FRAME_LEN as usize + len)?;
+    if crc32c::crc32c_append(crc32c::crc32c(&frame[4..]), payload) != crc {
+        return None;
+    }
+    decode(payload)
+}
+
+fn op_parts(op: &Op) -> (&[u8], &[u8]) {
+    match op {
+        Op::Put { key, value } => (key, value),
+        Op::Delete { key } => (key, &[]),
+    }
+}
+
+/// Frames `record` as it is appended to a log.
+pub(crate) fn encode(record: &Record) -> Result<Vec<u8>> {
+    let mut frame = vec![0; FRAME_LEN as usize];
+    frame.extend_from_slice(&record.seq.to_le_bytes());
+    frame.extend_from_slice(&(record.ops.len() as u32).to_le_bytes());
+    for op in &record.ops {
+        let (key, value) = op_parts(op);
+        if key.len() > u32::MAX as usize || value.len() > u32::MAX as usize {
+            return Err(Error::TooLarge(key.len().max(value.len())));
+        }
+        frame.push(match op {
+            Op::Put { .. } => KIND_PUT,
+            Op::Delete { .. } => KIND_DELETE,
+        });
+        frame.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        frame.extend_from_slice(key);
+        frame.extend_from_slice(value);
+    }
+
+    let payload_len = frame.len() - FRAME_LEN as usize;
+    if payload_len > u32::MAX as usize {
+        return Err(Error::TooLarge(payload_len));
+    }
+    frame[4..8].copy_from_slice(&(payload_len as u32).to_le_bytes());
+    let crc = crc32c::crc32c(&frame[4..]);
+    frame[..4].copy_from_slice(&crc.to_le_bytes());
+    Ok(frame)
+}
+
+fn decode(payload: &[u8]) -> Option<Record> {
+    let mut cursor = payload;
+    let seq = u64::from_le_bytes(take(&mut cursor, 8)?.try_into().ok()?);
+    let count = u32::from_le_bytes(take(&mut cursor, 4)?.try_into().ok()?);
+
+    let mut ops = Vec::new();
+    for _ in 0..count {
+        let kind = take(&mut cursor, 1)?[0];
+        let key_len = u32::from_le_bytes(take(&mut cursor, 4)?.try_into().ok()?) as usize;
+        let value_len = u32::from_le_bytes(take(&mut cursor, 4)?.try_into().ok()?) as usize;
+        let key = take(&mut cursor, key_len)?.to_vec();
+        let value = take(&mut cursor, value_len)?;
+        ops.push(match kind {
+            KIND_PUT => Op::Put {
+                key,
+                value: value.to_vec(),
+            },
+            KIND_DELETE if value.is_empty() => Op::Delete { key },
+            _ => return None,
+        });
+    }
+    if !cursor.is_empty() {
+        return None;
+    }
+    Some(Record { seq, ops })
+}
+
+/// Splits `n` bytes off the front of `cursor`.
+fn take<'a>(cursor: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if cursor.len() < n {
+        return None;
+    }
+    let (head, rest) = cursor.split_at(n);
+    *cursor = rest;
+    Some(head)
+}
+
+/// Fills `buf`, returning `false` if the reader ends first.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = ();
+
+    /// Parses `always`, `never`, or an interval in milliseconds such as
+    /// `100ms`.
+    fn from_str(s: &str) -> std::result::Result<FsyncPolicy, ()> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            s => match s.strip_suffix("ms").map(|ms| ms.trim().parse::<u64>()) {
+                Some(Ok(ms)) if ms > 0 => Ok(FsyncPolicy::Interval(Duration::from_millis(ms))),
+                _ => Err(()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsyncPolicy::Always => f.write_str("always"),
+            FsyncPolicy::Interval(interval) => write!(f, "{}ms", interval.as_millis()),
+            FsyncPolicy::Never => f.write_str("never"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: u64, key: &str, value: Option<&str>) -> Record {
+        let key = key.as_bytes().to_vec();
+        let op = match value {
+            Some(value) => Op::Put {
+                key,
+                value: value.as_bytes().to_vec(),
+            },
+            None => Op::Delete { key },
+        };
+        Record { seq, ops: vec![op] }
+    }
+
+    /// Appends `records` to a fresh log in a temporary directory, returning
+    /// the directory, the log's path and the offset of every record.
+    fn write_log(records: &[Record]) -> (tempfile::TempDir, std::path::PathBuf, Vec<u64>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.log");
+        let mut wal = Wal::open(&path, 0, FsyncPolicy::Never).unwrap();
+        let offsets = records.iter().map(|record| wal.append(record).unwrap()).collect();
+        (dir, path, offsets)
+    }
+
+    fn read_log(path: &Path) -> (Vec<(u64, Record)>, u64, bool) {
+        let mut reader = Reader::open(path).unwrap();
+        let mut records = Vec::new();
+        for entry in &mut reader {
+            records.push(entry.unwrap());
+        }
+        (records, reader.valid_len(), reader.torn())
+    }
+
+    fn flip_byte(path: &Path, offset: u64) {
+        let mut bytes = std::fs::read(path).unwrap();
+        bytes[offset as usize] ^= 0xff;
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn encode_decode() {
+        let record = Record {
+            seq: 7,
+            ops: vec![
+                Op::Put {
+                    key: b"a".to_vec(),
+                    value: b"1".to_vec(),
+                },
+                Op::Delete { key: b"b".to_vec() },
+                Op::Put {
+                    key: b"c".to_vec(),
+                    value: Vec::new(),
+                },
+            ],
+        };
+        let frame = encode(&record).unwrap();
+        let payload = &frame[FRAME_LEN as usize..];
+        assert_eq!(decode(payload), Some(record.clone()));
+        assert_eq!(decode(&payload[..payload.len() - 1]), None);
+        for (op, offset) in record.ops.iter().zip(value_offsets(0, &record.ops)) {
+            let (_, value) = op_parts(op);
+            assert_eq!(&frame[offset as usize..][..value.len()], value);
+        }
+    }
+
+    #[test]
+    fn reopen_reads_every_record() {
+        let records = vec![record(1, "a", Some("1")), record(2, "b", None), record(3, "a", Some("3"))];
+        let (_dir, path, offsets) = write_log(&records);
+
+        let (read, valid_len, torn) = read_log(&path);
+        assert_eq!(read, offsets.into_iter().zip(records).collect::<Vec<_>>());
+        assert_eq!(valid_len, std::fs::metadata(&path).unwrap().len());
+        assert!(!torn);
+    }
+
+    #[test]
+    fn torn_tail_ends_the_log() {
+        let records = vec![record(1, "a", Some("1")), record(2, "b", Some("2"))];
+        let (_dir, path, offsets) = write_log(&records);
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        // Cut off part of the last record, then only part of its frame.
+        for cut in &[len - 1, offsets[1] + 4] {
+            let file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.set_len(*cut).unwrap();
+            let (read, valid_len, torn) = read_log(&path);
+            assert_eq!(read, vec![(0, records[0].clone())]);
+            assert_eq!(valid_len, offsets[1]);
+            assert!(torn);
+        }
+
+        // Reopening for appends cuts the torn record off.
+        let mut wal = Wal::open(&path, offsets[1], FsyncPolicy::Never).unwrap();
+        let third = record(3, "c", Some("3"));
+        assert_eq!(wal.append(&third).unwrap(), offsets[1]);
+        drop(wal);
+        let (read, _, torn) = read_log(&path);
+        assert_eq!(read, vec![(0, records[0].clone()), (offsets[1], third)]);
+        assert!(!torn);
+    }
+
+    #[test]
+    fn bad_checksum_at_the_end_is_torn() {
+        let records = vec![record(1, "a", Some("1")), record(2, "b", Some("2"))];
+        let (_dir, path, offsets) = write_log(&records);
+        let len = std::fs::metadata(&path).unwrap().len();
+        flip_byte(&path, len - 1);
+
+        let (read, valid_len, torn) = read_log(&path);
+        assert_eq!(read, vec![(0, records[0].clone())]);
+        assert_eq!(valid_len, offsets[1]);
+        assert!(torn);
+    }
+
+    #[test]
+    fn bad_record_before_more_data_is_corruption() {
+        let records = vec![record(1, "a", Some("1")), record(2, "b", Some("2")), record(3, "c", Some("3"))];
+        let (_dir, path, offsets) = write_log(&records);
+        flip_byte(&path, offsets[2] - 1);
+
+        let mut reader = Reader::open(&path).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), (0, records[0].clone()));
+        match reader.next() {
+            Some(Err(Error::Corrupted { offset, reason })) => {
+                assert_eq!(offset, offsets[1]);
+                assert_eq!(reason, "checksum mismatch");
+            }
+            other => panic!("expected corruption, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fsync_policy_round_trips() {
+        for policy in &["always", "never", "250ms"] {
+            assert_eq!(policy.parse::<FsyncPolicy>().unwrap().to_string(), *policy);
+        }
+        assert!("0ms".parse::<FsyncPolicy>().is_err());
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+}